@@ -0,0 +1,68 @@
+//! Redirect lookups with and without the in-process cache.
+//! Needs the Redis of the configuration, like the repository tests:
+//!
+//!     $ cargo bench --bench redirect
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+use url_shortener::settings::Settings;
+use url_shortener::urls::redis_url_repo::{self, RedisUrlRepoImpl};
+use url_shortener::urls::types::{NewUrl, UrlRepo, UrlService};
+use url_shortener::urls::url_cache::UrlCache;
+use url_shortener::urls::url_service::UrlServiceImpl;
+
+fn service(
+    url_repo: RedisUrlRepoImpl,
+    cache: Option<Arc<UrlCache>>,
+) -> UrlServiceImpl<RedisUrlRepoImpl> {
+    UrlServiceImpl {
+        url_repo,
+        metadata_fetcher: None,
+        link_checker: None,
+        events: vec![],
+        // only the lookup is measured
+        count_clicks: false,
+        cache,
+    }
+}
+
+fn redirect(c: &mut Criterion) {
+    let settings = Settings::load().expect("Benchmarks need a valid configuration");
+    let mut system = actix_rt::System::new("redirect");
+    let configured = settings.clone();
+    let url_repo = system.block_on(async move { redis_url_repo::configure(&configured).await });
+    let new_url = NewUrl {
+        url: "https://example.com".to_string(),
+        domain: settings.domain.clone(),
+        ..Default::default()
+    };
+    let repo = url_repo.clone();
+    let url = system
+        .block_on(async move { repo.generate(&new_url).await })
+        .expect("Benchmarks need a running Redis");
+    let cache = UrlCache::new(
+        NonZeroUsize::new(1000).unwrap(),
+        Duration::from_secs(60),
+        Duration::from_secs(5),
+    );
+    let services = [
+        ("uncached", Arc::new(service(url_repo.clone(), None))),
+        ("cached", Arc::new(service(url_repo, Some(Arc::new(cache))))),
+    ];
+
+    let mut group = c.benchmark_group("redirect");
+    for (name, service) in services.iter() {
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let (service, url) = (service.clone(), url.clone());
+                system.block_on(async move { service.get(&url.domain, &url.id, None).await })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, redirect);
+criterion_main!(benches);
@@ -0,0 +1,171 @@
+use crate::hashids;
+use crate::redis::{self, Namespace};
+use crate::settings::Settings;
+use crate::urls::{migrations, redis_url_repo};
+
+const USAGE: &str = "Usage: url_shortener [command]
+
+Starts the server when no command is given.
+
+Commands:
+    decode-id <id>...    Show the counter and hashid version behind ids
+    schedule-checks      Queue link checks of links created before checking was enabled
+    migrate [--check]    Upgrade stored links to the current schema, --check only
+                         reports whether migrations are pending
+    copy-namespace <from> <to>
+                         Copy all keys of a namespace into an empty one
+    move-namespace <from> <to>
+                         Copy a namespace like copy-namespace, then delete the originals
+
+Stop the servers and workers of both namespaces while copying or moving, writes
+made in between may be lost.";
+
+/// Maintenance commands, returns the process exit code.
+pub async fn run(settings: &Settings, args: &[String]) -> i32 {
+    match args.split_first() {
+        Some((command, ids)) if command == "decode-id" && !ids.is_empty() => {
+            decode_ids(settings, ids).await
+        }
+        Some((command, rest)) if command == "schedule-checks" && rest.is_empty() => {
+            schedule_checks(settings).await
+        }
+        Some((command, rest)) if command == "migrate" && rest.is_empty() => {
+            migrate(settings, false).await
+        }
+        Some((command, rest)) if command == "migrate" && rest == ["--check"] => {
+            migrate(settings, true).await
+        }
+        Some((command, namespaces))
+            if (command == "copy-namespace" || command == "move-namespace")
+                && namespaces.len() == 2 =>
+        {
+            copy_namespace(
+                settings,
+                &namespaces[0],
+                &namespaces[1],
+                command == "move-namespace",
+            )
+            .await
+        }
+        Some((command, _)) if command == "help" || command == "--help" => {
+            println!("{}", USAGE);
+            0
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    }
+}
+
+async fn decode_ids(settings: &Settings, ids: &[String]) -> i32 {
+    let hashids = hashids::configure(settings).await;
+    let mut status = 0;
+    for id in ids {
+        let decoded = hashids.decode(id);
+        if decoded.is_empty() {
+            println!("{}: not a hashid of any configured version", id);
+            status = 1;
+        }
+        for decoded in decoded {
+            let current = if decoded.version == hashids.version() {
+                " (current)"
+            } else {
+                ""
+            };
+            println!(
+                "{}: counter {}, hashid version {}{}",
+                id, decoded.counter, decoded.version, current
+            );
+        }
+    }
+    status
+}
+
+async fn schedule_checks(settings: &Settings) -> i32 {
+    let url_repo = redis_url_repo::configure(settings).await;
+    match url_repo.schedule_all_checks().await {
+        Ok(count) => {
+            println!("{} links scheduled", count);
+            0
+        }
+        Err(e) => {
+            eprintln!("Unable to schedule checks: {}", e);
+            1
+        }
+    }
+}
+
+async fn migrate(settings: &Settings, check: bool) -> i32 {
+    let url_repo = redis_url_repo::configure(settings).await;
+    let version = match url_repo.schema_version().await {
+        Ok(version) => version,
+        Err(e) => {
+            eprintln!("Unable to read the schema version: {}", e);
+            return 1;
+        }
+    };
+    println!(
+        "schema version {}, current {}",
+        version,
+        migrations::SCHEMA_VERSION
+    );
+    if version > migrations::SCHEMA_VERSION {
+        eprintln!("Links were written by a newer version");
+        return 1;
+    }
+    for migration in migrations::pending(version) {
+        if check {
+            println!("pending {}: {}", migration.version, migration.description);
+            continue;
+        }
+        match url_repo.migrate(migration).await {
+            Ok(count) => println!(
+                "migrated to {}: {} ({} links upgraded)",
+                migration.version, migration.description, count
+            ),
+            Err(e) => {
+                eprintln!(
+                    "Migration {} failed, run it again: {}",
+                    migration.version, e
+                );
+                return 1;
+            }
+        }
+    }
+    if check && version < migrations::SCHEMA_VERSION {
+        1
+    } else {
+        0
+    }
+}
+
+async fn copy_namespace(settings: &Settings, from: &str, to: &str, remove: bool) -> i32 {
+    let (from, to) = match (Namespace::parse(from), Namespace::parse(to)) {
+        (Some(from), Some(to)) if from != to => (from, to),
+        _ => {
+            eprintln!(
+                "Namespaces must differ and be non-empty without ':', spaces or glob characters"
+            );
+            return 2;
+        }
+    };
+    let redis_client = redis::configure(settings).await;
+    match redis_client.copy_namespace(&from, &to, remove).await {
+        Ok(count) => {
+            let action = if remove { "moved" } else { "copied" };
+            println!(
+                "{} keys {} from {} to {}",
+                count,
+                action,
+                from.as_str(),
+                to.as_str()
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Unable to copy the namespace: {}", e);
+            1
+        }
+    }
+}
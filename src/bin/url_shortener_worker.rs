@@ -0,0 +1,15 @@
+use url_shortener::settings::Settings;
+use url_shortener::{telemetry, worker};
+
+/// Aggregates the event stream published by the server.
+#[actix_web::main]
+async fn main() {
+    let settings = Settings::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
+    let telemetry = telemetry::configure(&settings);
+    let status = worker::run(&settings).await;
+    telemetry.shutdown();
+    std::process::exit(status);
+}
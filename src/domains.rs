@@ -0,0 +1,100 @@
+use crate::settings::Settings;
+
+/// Domains served by this instance. Every link is bound to one of them,
+/// links created before domains were introduced belong to the default one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Domains {
+    default: String,
+    extra: Vec<String>,
+}
+
+impl Domains {
+    pub fn new(default: &str, extra: &[&str]) -> Domains {
+        Domains {
+            default: default.to_string(),
+            extra: extra
+                .iter()
+                .map(|domain| domain.to_string())
+                .filter(|domain| domain != default)
+                .collect(),
+        }
+    }
+
+    pub fn default_domain(&self) -> &str {
+        &self.default
+    }
+
+    /// All domains, the default one first.
+    pub fn all(&self) -> Vec<&str> {
+        std::iter::once(&*self.default)
+            .chain(self.extra.iter().map(|domain| &**domain))
+            .collect()
+    }
+
+    pub fn is_default(&self, domain: &str) -> bool {
+        domain.is_empty() || domain == self.default
+    }
+
+    pub fn is_known(&self, domain: &str) -> bool {
+        self.is_default(domain) || self.extra.iter().any(|extra| extra == domain)
+    }
+
+    /// Maps a request `Host` to the domain its links are scoped by,
+    /// unknown hosts fall back to the default domain. Domains may be configured
+    /// with or without the port the host carries.
+    pub fn resolve(&self, host: &str) -> &str {
+        let hostname = strip_port(host);
+        self.extra
+            .iter()
+            .find(|domain| domain.eq_ignore_ascii_case(host))
+            .or_else(|| {
+                self.extra
+                    .iter()
+                    .find(|domain| domain.eq_ignore_ascii_case(hostname))
+            })
+            .unwrap_or(&self.default)
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((hostname, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            hostname
+        }
+        _ => host,
+    }
+}
+
+pub async fn configure(settings: &Settings) -> Domains {
+    let extra: Vec<&str> = settings.domains.iter().map(|domain| &**domain).collect();
+    Domains::new(&settings.domain, &extra)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let domains = Domains::new("urls.lol", &["go.example.com", "urls.lol"]);
+        assert_eq!(domains.all(), vec!["urls.lol", "go.example.com"]);
+        assert_eq!(domains.resolve("GO.example.com"), "go.example.com");
+        assert_eq!(domains.resolve("urls.lol"), "urls.lol");
+        assert_eq!(domains.resolve("unknown.com"), "urls.lol");
+        assert!(domains.is_known("go.example.com"));
+        assert!(!domains.is_known("unknown.com"));
+        assert!(domains.is_default(""));
+    }
+
+    #[test]
+    fn test_resolve_with_port() {
+        let domains = Domains::new("localhost:8080", &["go.example.com", "short.test:8081"]);
+        assert_eq!(domains.resolve("go.example.com:8080"), "go.example.com");
+        assert_eq!(domains.resolve("go.example.com:443"), "go.example.com");
+        assert_eq!(domains.resolve("short.test:8081"), "short.test:8081");
+        assert_eq!(domains.resolve("short.test:9000"), "localhost:8080");
+        assert_eq!(domains.resolve("[::1]:8080"), "localhost:8080");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+}
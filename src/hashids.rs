@@ -1,14 +1,116 @@
+use crate::settings::{HashidConfig, Settings};
 use harsh::Harsh;
 
-pub async fn configure() -> Harsh {
-    let salt: String = std::env::var("HASHID_SALT").expect("HASHID_SALT env var needs to be set");
-    let min_length: usize = std::env::var("HASHID_MIN_LENGTH")
-        .expect("HASHID_MIN_LENGTH env var needs to be set")
-        .parse()
-        .unwrap();
-    Harsh::builder()
-        .salt(salt)
-        .length(min_length)
-        .build()
-        .expect("Error during Harsh configure")
+/// Counter value an id was generated from and the hashid version that encoded it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedId {
+    pub version: u32,
+    pub counter: u64,
+}
+
+/// Hashid encoders of every configured version, the current one first.
+#[derive(Debug, Clone)]
+pub struct Hashids {
+    versions: Vec<(u32, Harsh)>,
+}
+
+impl Hashids {
+    pub fn new(configs: &[HashidConfig]) -> Result<Hashids, String> {
+        let versions = configs
+            .iter()
+            .map(|config| {
+                let mut builder = Harsh::builder()
+                    .salt(config.salt.as_str())
+                    .length(config.min_length);
+                if let Some(alphabet) = &config.alphabet {
+                    builder = builder.alphabet(alphabet.as_str());
+                }
+                builder
+                    .build()
+                    .map(|harsh| (config.version, harsh))
+                    .map_err(|e| format!("hashid version {}: {}", config.version, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if versions.is_empty() {
+            return Err("at least one hashid config is required".to_string());
+        }
+        Ok(Hashids { versions })
+    }
+
+    pub fn version(&self) -> u32 {
+        self.versions[0].0
+    }
+
+    /// Encodes with the current version.
+    pub fn encode(&self, counter: u64) -> String {
+        self.versions[0].1.encode(&[counter])
+    }
+
+    /// Every version the id is a valid encoding for, usually at most one.
+    pub fn decode(&self, id: &str) -> Vec<DecodedId> {
+        self.versions
+            .iter()
+            .filter_map(|(version, harsh)| match harsh.decode(id).ok()?.as_slice() {
+                [counter] => Some(DecodedId {
+                    version: *version,
+                    counter: *counter,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+pub async fn configure(settings: &Settings) -> Hashids {
+    Hashids::new(&settings.hashid_configs()).expect("Error during Harsh configure")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(version: u32, salt: &str, min_length: usize) -> HashidConfig {
+        HashidConfig {
+            version,
+            salt: salt.to_string(),
+            min_length,
+            alphabet: None,
+        }
+    }
+
+    #[test]
+    fn test_versions() {
+        let old = Hashids::new(&[config(1, "salt", 6)]).unwrap();
+        let old_id = old.encode(42);
+
+        let sut = Hashids::new(&[config(2, "new salt", 8), config(1, "salt", 6)]).unwrap();
+        let new_id = sut.encode(42);
+        assert_eq!(sut.version(), 2);
+        assert_ne!(old_id, new_id);
+        assert_eq!(
+            sut.decode(&old_id),
+            vec![DecodedId {
+                version: 1,
+                counter: 42
+            }]
+        );
+        assert_eq!(
+            sut.decode(&new_id),
+            vec![DecodedId {
+                version: 2,
+                counter: 42
+            }]
+        );
+        assert!(sut.decode("not-a-hashid").is_empty());
+    }
+
+    #[test]
+    fn test_custom_alphabet() {
+        let mut custom = config(1, "salt", 6);
+        custom.alphabet = Some("abcdefghijklmnopqrstuvwxyz".to_string());
+        let sut = Hashids::new(&[custom]).unwrap();
+        let id = sut.encode(7);
+        assert!(id.chars().all(|c| c.is_ascii_lowercase()));
+        assert_eq!(sut.decode(&id)[0].counter, 7);
+    }
 }
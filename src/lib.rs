@@ -0,0 +1,11 @@
+pub mod admin;
+pub mod domains;
+pub mod hashids;
+pub mod metrics;
+pub mod redis;
+pub mod security_headers;
+pub mod session;
+pub mod settings;
+pub mod telemetry;
+pub mod urls;
+pub mod worker;
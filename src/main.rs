@@ -1,58 +1,132 @@
 use std::sync::Arc;
 
-use actix_files;
-use actix_identity::{CookieIdentityPolicy, IdentityService};
+use actix_rt::signal;
+use actix_web::dev::{Server, Service};
 use actix_web::{web, App, HttpServer};
-use harsh::Harsh;
+use futures::future::{self, FutureExt};
+use std::time::{Duration, Instant};
 use tera::Tera;
 
-mod hashids;
-mod redis;
-mod urls;
-
-fn configure(redis_client: Arc<redis::Client>, hashids: Harsh, cfg: &mut web::ServiceConfig) {
-    use crate::urls::api;
-    use crate::urls::redis_url_repo::RedisUrlRepoImpl;
-    use crate::urls::url_service::UrlServiceImpl;
-    let url_repo = RedisUrlRepoImpl {
-        redis_client,
-        hashids,
-    };
-    let url_service = UrlServiceImpl { url_repo };
-    api::configure(web::Data::new(url_service), cfg);
+use url_shortener::settings::{Bind, Settings};
+use url_shortener::urls::url_service::UrlServiceImpl;
+use url_shortener::urls::{
+    self, event_stream, expiry, link_checker, metadata, migrations, redis_url_repo, url_cache,
+    webhooks,
+};
+use url_shortener::{admin, metrics, security_headers, session, telemetry};
+
+/// Stops accepting connections on SIGTERM or SIGINT and gives in-flight requests,
+/// including their click writes, `shutdown_timeout` seconds to finish.
+async fn shutdown_on_signal(server: Server) {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+        .expect("Unable to listen for SIGTERM");
+    let interrupt = signal::ctrl_c().map(|_| ());
+    future::select(Box::pin(terminate.recv().map(|_| ())), Box::pin(interrupt)).await;
+    tracing::info!("shutting down");
+    server.stop(true).await;
 }
 
 #[actix_web::main]
 async fn main() {
-    env_logger::init();
-    dotenv::dotenv().ok();
+    let settings = Settings::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(admin::run(&settings, &args).await);
+    }
+    let telemetry = telemetry::configure(&settings);
 
-    let redis_client = Arc::new(redis::configure().await);
-    let hashids = hashids::configure().await;
+    let url_repo = redis_url_repo::configure(&settings).await;
+    if let Ok(version) = url_repo.schema_version().await {
+        if version < migrations::SCHEMA_VERSION {
+            tracing::warn!(
+                version,
+                "links use an older schema, run `url_shortener migrate`"
+            );
+        }
+    }
+    let mut events = vec![webhooks::start(&settings, Arc::new(url_repo.clone()))];
+    events.extend(event_stream::start(
+        &settings,
+        url_repo.redis_client.clone(),
+    ));
+    let cache = url_cache::start(&settings, url_repo.redis_client.clone());
+    let domains = web::Data::new(url_repo.domains.clone());
+    // one service shared by all workers and the background jobs
+    let url_service = web::Data::new(UrlServiceImpl {
+        url_repo,
+        metadata_fetcher: metadata::configure(&settings).await,
+        link_checker: link_checker::configure(&settings).await,
+        events: events.clone(),
+        count_clicks: !settings.count_clicks_in_worker,
+        cache,
+    });
+    link_checker::start(&settings, url_service.clone());
+    expiry::start(url_service.clone());
     let template = Tera::new("templates/**/*").unwrap();
 
-    let port = std::env::var("PORT").expect("PORT env var must be set");
-    let bind = format!("0.0.0.0:{}", port);
-
-    let secret_key = std::env::var("SECRET").expect("SECRET env var must be set");
+    let max_payload = settings.max_payload;
+    let settings = web::Data::new(settings);
+    let server_settings = settings.clone();
 
     let app = move || {
         App::new()
             .data(template.clone())
-            .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(&secret_key.as_bytes())
-                    .name("auth")
-                    .secure(false)
-                    .max_age(315576000), // 10 years
-            ))
+            .app_data(settings.clone())
+            .app_data(web::JsonConfig::default().limit(max_payload))
+            .app_data(web::PayloadConfig::new(max_payload))
+            .wrap_fn(|req, srv| {
+                let started = Instant::now();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    metrics::observe_request(
+                        response.request(),
+                        response.status(),
+                        started.elapsed(),
+                    );
+                    Ok(response)
+                }
+            })
+            .wrap(telemetry::RequestId)
+            .wrap(session::Csrf::new(&settings))
+            .wrap(session::configure(&settings))
+            .wrap(security_headers::SecurityHeaders)
             .service(actix_files::Files::new("/static/", "static/").use_last_modified(true))
-            .configure(|cfg| configure(redis_client.clone(), hashids.clone(), cfg))
+            .route("/metrics", web::get().to(metrics::metrics))
+            .configure(|cfg| urls::api::configure(url_service.clone(), domains.clone(), cfg))
     };
 
-    HttpServer::new(app)
-        .bind(bind)
-        .expect("Unable to bind server")
-        .run()
-        .await
-        .expect("Failed to start web server")
+    let mut server = HttpServer::new(app)
+        .keep_alive(server_settings.keep_alive)
+        .backlog(server_settings.backlog as i32)
+        .shutdown_timeout(server_settings.shutdown_timeout)
+        .disable_signals();
+    if let Some(workers) = server_settings.workers {
+        server = server.workers(workers);
+    }
+    let server = match &server_settings.bind {
+        Bind::Tcp(address) => server.bind(address),
+        Bind::Unix(path) => server.bind_uds(path),
+    }
+    .expect("Unable to bind server")
+    .run();
+
+    tracing::info!(bind = %server_settings.bind, "starting server");
+    actix_rt::spawn(shutdown_on_signal(server.clone()));
+    server.await.expect("Failed to start web server");
+
+    let pending = metrics::CLICK_QUEUE_DEPTH.get();
+    if pending > 0 {
+        tracing::warn!(pending, "shutdown timed out before all clicks were written");
+    }
+    // events of the last requests are still queued in the stream and webhook actors
+    let flushed = future::join_all(events.iter().map(|events| events.flush()));
+    let timeout = Duration::from_secs(server_settings.shutdown_timeout);
+    if actix_rt::time::timeout(timeout, flushed).await.is_err() {
+        tracing::warn!("shutdown timed out before all events were delivered");
+    }
+    telemetry.shutdown();
 }
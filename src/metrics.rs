@@ -0,0 +1,169 @@
+use actix_web::http::{Method, StatusCode};
+use actix_web::{HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "url_shortener_http_requests_total",
+        "HTTP requests by route, method and status",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "url_shortener_http_request_duration_seconds",
+        "HTTP request latency by route",
+        &["route"]
+    )
+    .unwrap();
+    pub static ref REDIRECTS: IntCounterVec = register_int_counter_vec!(
+        "url_shortener_redirects_total",
+        "Redirect lookups by result",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref REDIS_COMMAND_DURATION: HistogramVec = register_histogram_vec!(
+        "url_shortener_redis_command_duration_seconds",
+        "Redis command latency by command",
+        &["command"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+    )
+    .unwrap();
+    pub static ref REDIS_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "url_shortener_redis_errors_total",
+        "Failed Redis commands by command",
+        &["command"]
+    )
+    .unwrap();
+    pub static ref LINKS_CREATED: IntCounter =
+        register_int_counter!("url_shortener_links_created_total", "Short links created").unwrap();
+    pub static ref USERS_CREATED: IntCounter = register_int_counter!(
+        "url_shortener_users_created_total",
+        "Anonymous users created"
+    )
+    .unwrap();
+    pub static ref METADATA_FETCHES: IntCounterVec = register_int_counter_vec!(
+        "url_shortener_metadata_fetches_total",
+        "Destination metadata fetches by result",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref LINK_CHECKS: IntCounterVec = register_int_counter_vec!(
+        "url_shortener_link_checks_total",
+        "Destination checks by result",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref WEBHOOK_DELIVERIES: IntCounterVec = register_int_counter_vec!(
+        "url_shortener_webhook_deliveries_total",
+        "Webhook delivery attempts by result",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref STREAM_EVENTS: IntCounterVec = register_int_counter_vec!(
+        "url_shortener_stream_events_total",
+        "Events appended to the event stream by result",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref URL_CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "url_shortener_url_cache_lookups_total",
+        "Redirect cache lookups by result",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref CLICK_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "url_shortener_click_queue_depth",
+        "Clicks waiting to be written to Redis"
+    )
+    .unwrap();
+}
+
+/// Route label for a matched resource, keeps the label set bounded.
+fn route_name(method: &Method, pattern: Option<&str>) -> String {
+    match (method, pattern) {
+        (&Method::GET, Some("/")) => "index".to_string(),
+        (&Method::POST, Some("/")) => "shorten".to_string(),
+        (&Method::PATCH, Some("/{id}")) => "update".to_string(),
+        (_, Some("/{id}")) => "redirect".to_string(),
+        (_, Some(pattern)) => pattern.to_string(),
+        (_, None) => "unmatched".to_string(),
+    }
+}
+
+pub fn observe_request(request: &HttpRequest, status: StatusCode, elapsed: Duration) {
+    let route = route_name(request.method(), request.match_pattern().as_deref());
+    HTTP_REQUESTS
+        .with_label_values(&[&route, request.method().as_str(), status.as_str()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[&route])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Measures a single Redis round trip, failures are counted and logged.
+pub async fn observe_redis<T, E, F>(command: &str, future: F) -> Result<T, E>
+where
+    E: fmt::Display,
+    F: Future<Output = Result<T, E>>,
+{
+    let timer = REDIS_COMMAND_DURATION
+        .with_label_values(&[command])
+        .start_timer();
+    let result = future.await;
+    timer.observe_duration();
+    if let Err(e) = &result {
+        REDIS_ERRORS.with_label_values(&[command]).inc();
+        tracing::warn!(command, error = %e, "redis command failed");
+    }
+    result
+}
+
+pub async fn metrics() -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(buffer),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_name() {
+        assert_eq!(route_name(&Method::GET, Some("/")), "index");
+        assert_eq!(route_name(&Method::POST, Some("/")), "shorten");
+        assert_eq!(route_name(&Method::GET, Some("/{id}")), "redirect");
+        assert_eq!(route_name(&Method::PATCH, Some("/{id}")), "update");
+        assert_eq!(route_name(&Method::GET, Some("/{id}/stats")), "/{id}/stats");
+        assert_eq!(route_name(&Method::GET, None), "unmatched");
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_observe_redis_errors() {
+        let before = REDIS_ERRORS.with_label_values(&["test"]).get();
+        let _ = observe_redis("test", async { Err::<(), &str>("failed") }).await;
+        let _ = observe_redis("test", async { Ok::<(), &str>(()) }).await;
+        assert_eq!(REDIS_ERRORS.with_label_values(&["test"]).get(), before + 1);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_metrics_endpoint() {
+        LINKS_CREATED.inc_by(0);
+        let response = metrics().await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -1,6 +1,364 @@
-pub use redis::Client;
+use crate::settings::Settings;
+use redis::aio;
+use redis::{
+    ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo, RedisError, RedisResult,
+};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-pub async fn configure() -> redis::Client {
-    let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL env var needs to be set");
-    redis::Client::open(redis_url).expect("Unable to connect to Redis")
+const SENTINEL_REFRESH: Duration = Duration::from_secs(1);
+
+fn error(kind: ErrorKind, description: &'static str, detail: String) -> RedisError {
+    RedisError::from((kind, description, detail))
+}
+
+/// Credentials and database come from `template` unless `node` is a full url.
+fn node_info(node: &str, template: &ConnectionInfo) -> RedisResult<ConnectionInfo> {
+    if node.contains("://") {
+        return node.into_connection_info();
+    }
+    let (host, port) = node
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+        .ok_or_else(|| {
+            error(
+                ErrorKind::InvalidClientConfig,
+                "node must be host:port",
+                node.to_string(),
+            )
+        })?;
+    Ok(ConnectionInfo {
+        addr: Box::new(ConnectionAddr::Tcp(host.to_string(), port)),
+        ..template.clone()
+    })
+}
+
+/// The master is asked for again after `SENTINEL_REFRESH` so connections follow failovers.
+struct Sentinel {
+    sentinels: Vec<ConnectionInfo>,
+    master_name: String,
+    template: ConnectionInfo,
+    master: Mutex<Option<(redis::Client, Instant)>>,
+}
+
+impl Sentinel {
+    async fn master(&self) -> RedisResult<redis::Client> {
+        if let Some((client, resolved_at)) = &*self.master.lock().unwrap_or_else(|e| e.into_inner())
+        {
+            if resolved_at.elapsed() < SENTINEL_REFRESH {
+                return Ok(client.clone());
+            }
+        }
+        let mut last_error = error(
+            ErrorKind::InvalidClientConfig,
+            "no sentinel configured",
+            String::new(),
+        );
+        for sentinel in self.sentinels.iter() {
+            let result = async {
+                let mut conn = redis::Client::open(sentinel.clone())?
+                    .get_async_connection()
+                    .await?;
+                let master: Option<(String, u16)> = redis::cmd("SENTINEL")
+                    .arg("get-master-addr-by-name")
+                    .arg(&self.master_name)
+                    .query_async(&mut conn)
+                    .await?;
+                master.ok_or_else(|| {
+                    error(
+                        ErrorKind::ResponseError,
+                        "sentinel doesn't know the master",
+                        self.master_name.clone(),
+                    )
+                })
+            };
+            match result.await {
+                Ok((host, port)) => {
+                    let client = redis::Client::open(ConnectionInfo {
+                        addr: Box::new(ConnectionAddr::Tcp(host, port)),
+                        ..self.template.clone()
+                    })?;
+                    *self.master.lock().unwrap_or_else(|e| e.into_inner()) =
+                        Some((client.clone(), Instant::now()));
+                    return Ok(client);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn connect(&self) -> RedisResult<aio::Connection> {
+        let result = self.master().await?.get_async_connection().await;
+        if result.is_err() {
+            // the master may have moved
+            *self.master.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+        result
+    }
+}
+enum Topology {
+    Single(redis::Client),
+    Sentinel(Sentinel),
+}
+
+/// Prefix of every key and channel, so several tenants can share one Redis.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Namespace(String);
+
+impl Namespace {
+    /// Separators and glob characters are refused so no namespace matches the
+    /// keys of another one.
+    pub fn parse(name: &str) -> Option<Namespace> {
+        let valid = !name.is_empty()
+            && !name
+                .chars()
+                .any(|c| c == ':' || c.is_whitespace() || "*?[]\\".contains(c));
+        if valid {
+            Some(Namespace(name.to_string()))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// `namespace:name`
+    pub fn key(&self, name: &str) -> String {
+        format!("{}:{}", self.0, name)
+    }
+
+    pub fn pattern(&self) -> String {
+        self.key("*")
+    }
+
+    fn rename(&self, key: &str, to: &Namespace) -> Option<String> {
+        key.strip_prefix(&self.key("")).map(|name| to.key(name))
+    }
+}
+
+pub fn namespace(settings: &Settings) -> Namespace {
+    Namespace::parse(&settings.redis_namespace).expect("Invalid Redis namespace")
+}
+
+/// A single server or a master resolved through Sentinel.
+pub struct RedisClient {
+    topology: Topology,
+}
+
+pub type Connection = aio::Connection;
+
+impl RedisClient {
+    pub fn open(url: &str) -> RedisResult<RedisClient> {
+        Ok(RedisClient {
+            topology: Topology::Single(redis::Client::open(url)?),
+        })
+    }
+
+    pub async fn get_async_connection(&self) -> RedisResult<Connection> {
+        match &self.topology {
+            Topology::Single(client) => client.get_async_connection().await,
+            Topology::Sentinel(sentinel) => sentinel.connect().await,
+        }
+    }
+
+    pub async fn get_pubsub(&self) -> RedisResult<aio::PubSub> {
+        Ok(self.get_async_connection().await?.into_pubsub())
+    }
+
+    pub async fn scan_match(&self, pattern: &str) -> RedisResult<Vec<String>> {
+        let mut conn = self.get_async_connection().await?;
+        let mut iter: redis::AsyncIter<String> =
+            redis::AsyncCommands::scan_match(&mut conn, pattern).await?;
+        let mut keys = vec![];
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    /// One `SCAN` step from `cursor`, `0` to start. The next cursor is `None`
+    /// once the scan is complete.
+    pub async fn scan_step(
+        &self,
+        cursor: u64,
+        pattern: &str,
+        count: usize,
+    ) -> RedisResult<(Option<u64>, Vec<String>)> {
+        let mut conn = self.get_async_connection().await?;
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(count)
+            .query_async(&mut conn)
+            .await?;
+        Ok((Some(next).filter(|&next| next != 0), keys))
+    }
+
+    /// `to` must be empty. Writes made to `from` meanwhile may be missed.
+    pub async fn copy_namespace(
+        &self,
+        from: &Namespace,
+        to: &Namespace,
+        remove: bool,
+    ) -> RedisResult<usize> {
+        if !self.scan_match(&to.pattern()).await?.is_empty() {
+            return Err(error(
+                ErrorKind::ClientError,
+                "target namespace is not empty",
+                to.as_str().to_string(),
+            ));
+        }
+        let mut conn = self.get_async_connection().await?;
+        let mut copied = 0;
+        for key in self.scan_match(&from.pattern()).await? {
+            let target = match from.rename(&key, to) {
+                Some(target) => target,
+                None => continue,
+            };
+            let dump: Option<Vec<u8>> = redis::cmd("DUMP").arg(&key).query_async(&mut conn).await?;
+            let ttl: i64 = redis::cmd("PTTL").arg(&key).query_async(&mut conn).await?;
+            let dump = match dump {
+                // expired since the scan
+                Some(dump) if ttl != -2 => dump,
+                _ => continue,
+            };
+            let _: () = redis::cmd("RESTORE")
+                .arg(&target)
+                .arg(ttl.max(0))
+                .arg(dump)
+                .query_async(&mut conn)
+                .await?;
+            copied += 1;
+            if remove {
+                let _: usize = redis::cmd("DEL").arg(&key).query_async(&mut conn).await?;
+            }
+        }
+        Ok(copied)
+    }
+}
+
+pub async fn configure(settings: &Settings) -> RedisClient {
+    let template = settings
+        .redis_url
+        .as_str()
+        .into_connection_info()
+        .expect("Unable to connect to Redis");
+    let nodes = |nodes: &[String]| -> Vec<ConnectionInfo> {
+        nodes
+            .iter()
+            .map(|node| node_info(node, &template).expect("Unable to connect to Redis"))
+            .collect()
+    };
+    let topology = if !settings.redis_sentinels.is_empty() {
+        Topology::Sentinel(Sentinel {
+            sentinels: nodes(&settings.redis_sentinels),
+            master_name: settings.redis_sentinel_master.clone(),
+            template: template.clone(),
+            master: Mutex::new(None),
+        })
+    } else {
+        Topology::Single(redis::Client::open(template).expect("Unable to connect to Redis"))
+    };
+    RedisClient { topology }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::AsyncCommands;
+
+    #[test]
+    fn test_node_info() {
+        let template = "redis://:secret@redis/2".into_connection_info().unwrap();
+        let info = node_info("10.0.0.1:7000", &template).unwrap();
+        assert_eq!(info.addr.to_string(), "10.0.0.1:7000");
+        assert_eq!(info.passwd, Some("secret".to_string()));
+        assert_eq!(info.db, 2);
+        assert!(node_info("10.0.0.1", &template).is_err());
+    }
+
+    #[test]
+    fn test_namespace() {
+        let namespace = Namespace::parse("staging").unwrap();
+        assert_eq!(namespace.key("urls:abc"), "staging:urls:abc");
+        assert_eq!(namespace.pattern(), "staging:*");
+        let to = Namespace::parse("prod").unwrap();
+        assert_eq!(
+            namespace.rename("staging:urls:abc", &to),
+            Some("prod:urls:abc".to_string())
+        );
+        assert_eq!(namespace.rename("staging_old:urls:abc", &to), None);
+        for invalid in ["", "a:b", "a b", "a*", "a?", "[a]", "a\\b"] {
+            assert_eq!(Namespace::parse(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_copy_namespace() {
+        let settings = Settings::load().expect("Tests need a valid configuration");
+        let client = configure(&settings).await;
+        let mut conn = client.get_async_connection().await.unwrap();
+        let from = Namespace::parse("copy_test_from").unwrap();
+        let to = Namespace::parse("copy_test_to").unwrap();
+        let moved = Namespace::parse("copy_test_moved").unwrap();
+        for namespace in [&from, &to, &moved] {
+            for key in client.scan_match(&namespace.pattern()).await.unwrap() {
+                let _: () = conn.del(key).await.unwrap();
+            }
+        }
+        let _: () = conn.set(from.key("a"), "1").await.unwrap();
+        let _: () = conn.set_ex(from.key("b"), "2", 60).await.unwrap();
+        let _: () = conn.sadd(from.key("c"), "x").await.unwrap();
+
+        assert_eq!(client.copy_namespace(&from, &to, false).await.unwrap(), 3);
+        let value: String = conn.get(to.key("a")).await.unwrap();
+        assert_eq!(value, "1");
+        let ttl: i64 = conn.ttl(to.key("b")).await.unwrap();
+        assert!(ttl > 0 && ttl <= 60);
+        let members: Vec<String> = conn.smembers(to.key("c")).await.unwrap();
+        assert_eq!(members, vec!["x"]);
+        // the target must be empty
+        assert!(client.copy_namespace(&from, &to, false).await.is_err());
+
+        assert_eq!(client.copy_namespace(&to, &moved, true).await.unwrap(), 3);
+        assert!(client.scan_match(&to.pattern()).await.unwrap().is_empty());
+        for namespace in [&from, &moved] {
+            for key in client.scan_match(&namespace.pattern()).await.unwrap() {
+                let _: () = conn.del(key).await.unwrap();
+            }
+        }
+    }
+
+    #[actix_web::main]
+    #[test]
+    #[ignore = "needs REDIS_SENTINELS, see docker-compose.sentinel.yml"]
+    async fn test_sentinel() {
+        let settings = Settings::load().expect("Tests need a valid configuration");
+        assert!(
+            !settings.redis_sentinels.is_empty(),
+            "REDIS_SENTINELS is not set"
+        );
+        let client = configure(&settings).await;
+        let mut conn = client.get_async_connection().await.unwrap();
+        let role: Vec<redis::Value> = redis::cmd("ROLE").query_async(&mut conn).await.unwrap();
+        assert_eq!(role.first(), Some(&redis::Value::Data(b"master".to_vec())));
+        let mut transaction = redis::pipe();
+        transaction
+            .atomic()
+            .set("sentinel_test:a", 1)
+            .ignore()
+            .incr("sentinel_test:b", 1)
+            .ignore();
+        let _: () = transaction.query_async(&mut conn).await.unwrap();
+        assert_eq!(client.scan_match("sentinel_test:*").await.unwrap().len(), 2);
+        for key in ["sentinel_test:a", "sentinel_test:b"] {
+            let _: () = conn.del(key).await.unwrap();
+        }
+    }
 }
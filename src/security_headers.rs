@@ -0,0 +1,165 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage, HttpRequest};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::task::{Context, Poll};
+
+/// Nonce of the inline scripts of the current response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CspNonce(pub String);
+
+/// Nonce for `<script nonce>` tags of rendered pages, empty when the
+/// `SecurityHeaders` middleware is not installed.
+pub fn csp_nonce(req: &HttpRequest) -> String {
+    req.extensions()
+        .get::<CspNonce>()
+        .map(|nonce| nonce.0.clone())
+        .unwrap_or_default()
+}
+
+/// Only scripts carrying the nonce of the response run. Images may come from
+/// anywhere over HTTPS as favicons of shortened sites are shown in the history.
+fn content_security_policy(nonce: &str) -> String {
+    format!(
+        "default-src 'self'; script-src 'nonce-{}'; style-src 'self'; \
+         img-src 'self' https: data:; object-src 'none'; base-uri 'none'; \
+         form-action 'self'; frame-ancestors 'none'",
+        nonce
+    )
+}
+
+/// Adds a nonce based Content-Security-Policy and related headers to every
+/// response, unless the handler set them itself.
+pub struct SecurityHeaders;
+
+impl<S, B> Transform<S> for SecurityHeaders
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SecurityHeadersMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SecurityHeadersMiddleware { service })
+    }
+}
+
+pub struct SecurityHeadersMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for SecurityHeadersMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let nonce = format!("{:032x}", rand::random::<u128>());
+        req.extensions_mut().insert(CspNonce(nonce.clone()));
+        let response = self.service.call(req);
+
+        Box::pin(async move {
+            let mut response = response.await?;
+            let headers = response.headers_mut();
+            let defaults = [
+                (
+                    header::CONTENT_SECURITY_POLICY,
+                    content_security_policy(&nonce),
+                ),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+                (
+                    header::REFERRER_POLICY,
+                    "strict-origin-when-cross-origin".to_string(),
+                ),
+                (header::X_FRAME_OPTIONS, "DENY".to_string()),
+            ];
+            for (name, value) in defaults.iter() {
+                if headers.contains_key(name) {
+                    continue;
+                }
+                if let Ok(value) = HeaderValue::from_str(value) {
+                    headers.insert(HeaderName::clone(name), value);
+                }
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    async fn page(req: HttpRequest) -> HttpResponse {
+        HttpResponse::Ok().body(csp_nonce(&req))
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_security_headers() {
+        let mut sut = test::init_service(
+            App::new()
+                .wrap(SecurityHeaders)
+                .route("/", web::get().to(page))
+                .route(
+                    "/framed",
+                    web::get().to(|| {
+                        HttpResponse::Ok()
+                            .header(header::X_FRAME_OPTIONS, "SAMEORIGIN")
+                            .finish()
+                    }),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        let csp = resp
+            .headers()
+            .get(header::CONTENT_SECURITY_POLICY)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            resp.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+            "nosniff"
+        );
+        assert_eq!(
+            resp.headers().get(header::REFERRER_POLICY).unwrap(),
+            "strict-origin-when-cross-origin"
+        );
+        let nonce = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert_eq!(nonce.len(), 32);
+        assert!(csp.contains(&format!("script-src 'nonce-{}'", nonce)));
+
+        // every response has its own nonce
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_ne!(test::read_body(resp).await, nonce.as_str());
+
+        let req = test::TestRequest::get().uri("/framed").to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(
+            resp.headers().get(header::X_FRAME_OPTIONS).unwrap(),
+            "SAMEORIGIN"
+        );
+    }
+}
@@ -0,0 +1,467 @@
+use crate::settings::{CookieSameSite, Settings};
+use actix_identity::{CookieIdentityPolicy, IdentityPolicy, IdentityService};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{AUTHORIZATION, COOKIE};
+use actix_web::http::{HeaderValue, Method};
+use actix_web::{error, Error, HttpMessage, HttpRequest};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// Cookie holding the encrypted user id.
+pub const SESSION_COOKIE: &str = "auth";
+/// Cookie holding the CSRF token, browser scripts send it back in `X-CSRF-Token`.
+pub const CSRF_COOKIE: &str = "csrf";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+fn same_site(settings: &Settings) -> SameSite {
+    match settings.cookie_same_site {
+        CookieSameSite::Strict => SameSite::Strict,
+        CookieSameSite::Lax => SameSite::Lax,
+        CookieSameSite::None => SameSite::None,
+    }
+}
+
+/// Value of an `Authorization: Bearer` header.
+fn bearer_token(req: &ServiceRequest) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Marks sessions read with a previous secret, they are written again with the current one.
+struct Reissue;
+
+/// Session cookie encrypted with `SECRET`. Cookies of `PREVIOUS_SECRETS` are still
+/// read, so rotating the secret does not log anybody out.
+///
+/// API clients may send the value of the session cookie as a bearer token instead.
+pub struct SessionPolicy {
+    current: CookieIdentityPolicy,
+    previous: Vec<CookieIdentityPolicy>,
+}
+
+impl SessionPolicy {
+    pub fn new(settings: &Settings) -> SessionPolicy {
+        let policy = |secret: &String| {
+            let policy = CookieIdentityPolicy::new(secret.as_bytes())
+                .name(SESSION_COOKIE)
+                .path("/")
+                .secure(settings.cookie_secure)
+                .same_site(same_site(settings))
+                .http_only(true)
+                .max_age(settings.cookie_max_age as i64);
+            match &settings.cookie_domain {
+                Some(domain) => policy.domain(domain.as_str()),
+                None => policy,
+            }
+        };
+        SessionPolicy {
+            current: policy(&settings.secret),
+            previous: settings.previous_secrets.iter().map(policy).collect(),
+        }
+    }
+}
+
+impl IdentityPolicy for SessionPolicy {
+    type Future = Ready<Result<Option<String>, Error>>;
+    type ResponseFuture = Ready<Result<(), Error>>;
+
+    fn from_request(&self, req: &mut ServiceRequest) -> Self::Future {
+        // the token replaces all cookies, so a bearer request is never authenticated
+        // by cookies the browser attached on its own, which is what exempts it from CSRF
+        if let Some(token) = bearer_token(req) {
+            let cookie = Cookie::new(SESSION_COOKIE, token.to_string());
+            match HeaderValue::from_str(&cookie.to_string()) {
+                Ok(value) => req.headers_mut().insert(COOKIE, value),
+                Err(_) => req.headers_mut().remove(COOKIE),
+            }
+        }
+
+        if let Ok(Some(user)) = self.current.from_request(req).into_inner() {
+            return ok(Some(user));
+        }
+        for policy in &self.previous {
+            if let Ok(Some(user)) = policy.from_request(req).into_inner() {
+                req.extensions_mut().insert(Reissue);
+                return ok(Some(user));
+            }
+        }
+        ok(None)
+    }
+
+    fn to_response<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        res: &mut ServiceResponse<B>,
+    ) -> Self::ResponseFuture {
+        let reissue = identity.is_some() && res.request().extensions().contains::<Reissue>();
+        self.current.to_response(identity, changed || reissue, res)
+    }
+}
+
+pub fn configure(settings: &Settings) -> IdentityService<SessionPolicy> {
+    IdentityService::new(SessionPolicy::new(settings))
+}
+
+/// CSRF token of the current request, for pages submitting forms with scripts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsrfToken(pub String);
+
+/// Token of the request, empty when the `Csrf` middleware is not installed.
+pub fn csrf_token(req: &HttpRequest) -> String {
+    req.extensions()
+        .get::<CsrfToken>()
+        .map(|token| token.0.clone())
+        .unwrap_or_default()
+}
+
+fn signature(secret: &str, nonce: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC takes any key");
+    mac.update(nonce.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Random nonce and its signature, so tokens planted by a sibling subdomain are refused.
+fn new_token(secret: &str) -> String {
+    let nonce = format!("{:032x}", rand::random::<u128>());
+    let signature = signature(secret, &nonce);
+    format!("{}.{}", nonce, signature)
+}
+
+fn is_valid_token(secrets: &[String], token: &str) -> bool {
+    match token.split_once('.') {
+        Some((nonce, token_signature)) => secrets.iter().any(|secret| {
+            constant_time_eq(
+                signature(secret, nonce).as_bytes(),
+                token_signature.as_bytes(),
+            )
+        }),
+        None => false,
+    }
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// Double submit protection for cookie authenticated requests: unsafe methods must
+/// send the token of the `csrf` cookie in the `X-CSRF-Token` header, which other
+/// sites can neither read nor set. Bearer authenticated requests are exempt.
+#[derive(Clone)]
+pub struct Csrf {
+    inner: Rc<CsrfInner>,
+}
+
+struct CsrfInner {
+    /// Current secret first, tokens are signed with it.
+    secrets: Vec<String>,
+    secure: bool,
+    domain: Option<String>,
+    max_age: i64,
+}
+
+impl Csrf {
+    pub fn new(settings: &Settings) -> Csrf {
+        let mut secrets = vec![settings.secret.clone()];
+        secrets.extend(settings.previous_secrets.iter().cloned());
+        Csrf {
+            inner: Rc::new(CsrfInner {
+                secrets,
+                secure: settings.cookie_secure,
+                domain: settings.cookie_domain.clone(),
+                max_age: settings.cookie_max_age as i64,
+            }),
+        }
+    }
+}
+
+impl CsrfInner {
+    fn cookie(&self, token: String) -> Cookie<'static> {
+        let mut cookie = Cookie::build(CSRF_COOKIE, token)
+            .path("/")
+            .secure(self.secure)
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .max_age(time::Duration::seconds(self.max_age))
+            .finish();
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+}
+
+impl<S, B> Transform<S> for Csrf
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfMiddleware {
+            service,
+            inner: self.inner.clone(),
+        })
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: S,
+    inner: Rc<CsrfInner>,
+}
+
+impl<S, B> Service for CsrfMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let inner = self.inner.clone();
+        let cookie_token = req
+            .cookie(CSRF_COOKIE)
+            .map(|cookie| cookie.value().to_string())
+            .filter(|token| is_valid_token(&inner.secrets, token));
+
+        if !is_safe(req.method()) && bearer_token(&req).is_none() {
+            let header_token = req
+                .headers()
+                .get(CSRF_HEADER)
+                .and_then(|value| value.to_str().ok());
+            let valid = match (&cookie_token, header_token) {
+                (Some(cookie), Some(header)) => {
+                    constant_time_eq(cookie.as_bytes(), header.as_bytes())
+                }
+                _ => false,
+            };
+            if !valid {
+                tracing::warn!(method = %req.method(), path = %req.path(), "CSRF token missing or invalid");
+                let response = req.error_response(error::ErrorForbidden("Invalid CSRF token"));
+                return Box::pin(async move { Ok(response) });
+            }
+        }
+
+        let new_token = match cookie_token {
+            Some(token) => {
+                req.extensions_mut().insert(CsrfToken(token));
+                None
+            }
+            None => {
+                let token = new_token(&inner.secrets[0]);
+                req.extensions_mut().insert(CsrfToken(token.clone()));
+                Some(token)
+            }
+        };
+
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            if let Some(token) = new_token {
+                response.response_mut().add_cookie(&inner.cookie(token))?;
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_identity::Identity;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+
+    const OLD_SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn settings() -> Settings {
+        let mut settings = Settings::for_tests();
+        settings.previous_secrets = vec![OLD_SECRET.to_string()];
+        settings
+    }
+
+    async fn whoami(identity: Identity) -> HttpResponse {
+        HttpResponse::Ok().body(identity.identity().unwrap_or_default())
+    }
+
+    async fn login(identity: Identity) -> HttpResponse {
+        identity.remember("user".to_string());
+        HttpResponse::Ok().finish()
+    }
+
+    fn set_cookie<B>(resp: &ServiceResponse<B>, name: &str) -> Option<Cookie<'static>> {
+        resp.response()
+            .cookies()
+            .find(|cookie| cookie.name() == name)
+            .map(|cookie| cookie.into_owned())
+    }
+
+    #[test]
+    fn test_tokens() {
+        let secrets = vec!["current".to_string(), "previous".to_string()];
+        assert!(is_valid_token(&secrets, &new_token("current")));
+        assert!(is_valid_token(&secrets, &new_token("previous")));
+        assert!(!is_valid_token(&secrets, &new_token("retired")));
+        assert!(!is_valid_token(&secrets, "nonce.signature"));
+        assert!(!is_valid_token(&secrets, ""));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_csrf() {
+        let settings = settings();
+        let mut sut = test::init_service(
+            App::new()
+                .wrap(Csrf::new(&settings))
+                .wrap(configure(&settings))
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        let cookie = set_cookie(&resp, CSRF_COOKIE).unwrap();
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+        let token = cookie.value().to_string();
+
+        // a known token is kept
+        let req = test::TestRequest::get()
+            .uri("/")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert!(set_cookie(&resp, CSRF_COOKIE).is_none());
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .cookie(cookie.clone())
+            .header(CSRF_HEADER, token.as_str())
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // unsigned tokens are refused even when cookie and header agree
+        let forged = "nonce.signature";
+        let req = test::TestRequest::post()
+            .uri("/")
+            .cookie(Cookie::new(CSRF_COOKIE, forged))
+            .header(CSRF_HEADER, forged)
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .header(AUTHORIZATION, "Bearer token")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_session() {
+        let settings = settings();
+        let mut old_settings = settings.clone();
+        old_settings.secret = OLD_SECRET.to_string();
+        old_settings.previous_secrets = vec![];
+
+        let mut old = test::init_service(
+            App::new()
+                .wrap(configure(&old_settings))
+                .route("/login", web::post().to(login)),
+        )
+        .await;
+        let req = test::TestRequest::post().uri("/login").to_request();
+        let resp = test::call_service(&mut old, req).await;
+        let old_cookie = set_cookie(&resp, SESSION_COOKIE).unwrap();
+
+        let mut sut = test::init_service(
+            App::new()
+                .wrap(configure(&settings))
+                .route("/", web::get().to(whoami)),
+        )
+        .await;
+
+        // sessions of the previous secret are accepted and written with the current one
+        let req = test::TestRequest::get()
+            .uri("/")
+            .cookie(old_cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        let cookie = set_cookie(&resp, SESSION_COOKIE).unwrap();
+        assert_ne!(cookie.value(), old_cookie.value());
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(test::read_body(resp).await, "user");
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert!(set_cookie(&resp, SESSION_COOKIE).is_none());
+        assert_eq!(test::read_body(resp).await, "user");
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(AUTHORIZATION, format!("Bearer {}", cookie.value()))
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(test::read_body(resp).await, "user");
+
+        // a bearer token takes precedence over cookies
+        let req = test::TestRequest::get()
+            .uri("/")
+            .cookie(cookie)
+            .header(AUTHORIZATION, "Bearer invalid")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(test::read_body(resp).await, "");
+    }
+}
@@ -0,0 +1,1161 @@
+use crate::redis::Namespace;
+use harsh::Harsh;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::{error, fmt, fs};
+
+/// Used when `CONFIG_FILE` is not set, missing file is not an error.
+const DEFAULT_CONFIG_FILE: &str = "config/settings.toml";
+/// `CookieIdentityPolicy` refuses shorter keys.
+const MIN_SECRET_LENGTH: usize = 32;
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+const DEFAULT_KEEP_ALIVE: usize = 5;
+const DEFAULT_BACKLOG: u32 = 2048;
+const DEFAULT_MAX_PAYLOAD: usize = 32 * 1024;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+const DEFAULT_ID_LENGTH: usize = 8;
+/// Same bounds as custom aliases, so every generated id is a valid alias.
+const MIN_ID_LENGTH: usize = 4;
+const MAX_ID_LENGTH: usize = 64;
+/// Snowflake ids reserve 10 bits for the node.
+const MAX_NODE_ID: u16 = 1023;
+const DEFAULT_METADATA_TIMEOUT: u64 = 5;
+const DEFAULT_METADATA_MAX_BYTES: usize = 512 * 1024;
+/// A day between checks of the same link.
+const DEFAULT_LINK_CHECK_INTERVAL: u64 = 24 * 60 * 60;
+const DEFAULT_LINK_CHECK_CONCURRENCY: usize = 8;
+const DEFAULT_WEBHOOK_TIMEOUT: u64 = 10;
+/// Approximate, Redis trims whole nodes of the stream.
+const DEFAULT_EVENT_STREAM_MAX_LENGTH: usize = 100_000;
+const DEFAULT_WORKER_NAME: &str = "worker";
+const DEFAULT_REDIS_SENTINEL_MASTER: &str = "mymaster";
+/// Prefix of the keys written before namespaces were configurable.
+const DEFAULT_REDIS_NAMESPACE: &str = "url_shortener";
+const DEFAULT_URL_CACHE_CAPACITY: usize = 10_000;
+const DEFAULT_URL_CACHE_TTL: u64 = 60;
+/// Short, so a link created on another instance is found soon even if the invalidation is lost.
+const DEFAULT_URL_CACHE_NEGATIVE_TTL: u64 = 5;
+/// A year, renewed on every visit to the index page.
+const DEFAULT_COOKIE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CookieSameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// One generation of hashid parameters. New ids use the current version,
+/// previous versions are kept so their ids can still be decoded.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HashidConfig {
+    pub version: u32,
+    pub salt: String,
+    pub min_length: usize,
+    /// Harsh default alphabet when unset.
+    #[serde(default)]
+    pub alphabet: Option<String>,
+}
+
+/// How ids of new links are generated, see `urls::id_generator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdStrategy {
+    Hashids,
+    Random,
+    Snowflake,
+    Words,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bind {
+    Tcp(String),
+    Unix(String),
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bind::Tcp(address) => write!(f, "{}", address),
+            Bind::Unix(path) => write!(f, "{}{}", UNIX_SOCKET_PREFIX, path),
+        }
+    }
+}
+
+/// Application configuration, validated once at startup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub domain: String,
+    pub domains: Vec<String>,
+    /// Added to the built-in lists.
+    pub deny_list: Vec<String>,
+    pub port: u16,
+    /// `0.0.0.0:{port}` unless `BIND` is set.
+    pub bind: Bind,
+    /// Worker threads, one per logical CPU when unset.
+    pub workers: Option<usize>,
+    /// Seconds, 0 disables keep-alive.
+    pub keep_alive: usize,
+    pub backlog: u32,
+    pub max_payload: usize,
+    /// Seconds.
+    pub shutdown_timeout: u64,
+    /// Also the credentials and database of the master found through Sentinel.
+    pub redis_url: String,
+    /// Sentinel is used when not empty.
+    pub redis_sentinels: Vec<String>,
+    pub redis_sentinel_master: String,
+    pub redis_namespace: String,
+    pub hashid_salt: String,
+    pub hashid_min_length: usize,
+    pub hashid_alphabet: Option<String>,
+    /// Version of the current salt, length and alphabet.
+    pub hashid_version: u32,
+    /// Only set in the config file.
+    pub previous_hashids: Vec<HashidConfig>,
+    pub id_strategy: IdStrategy,
+    /// Length of random ids.
+    pub id_length: usize,
+    /// Unique per instance of a deployment.
+    pub node_id: u16,
+    pub secret: String,
+    /// Still accepted, sessions are written again with `secret`.
+    pub previous_secrets: Vec<String>,
+    /// Defaults to true unless the domain is localhost.
+    pub cookie_secure: bool,
+    pub cookie_same_site: CookieSameSite,
+    /// Host only when unset.
+    pub cookie_domain: Option<String>,
+    /// Seconds.
+    pub cookie_max_age: u64,
+    pub log_format: LogFormat,
+    /// Spans are not exported when unset.
+    pub otlp_endpoint: Option<String>,
+    pub fetch_metadata: bool,
+    /// Seconds, redirects included.
+    pub metadata_timeout: u64,
+    pub metadata_max_bytes: usize,
+    /// Seconds, 0 disables link checking.
+    pub link_check_interval: u64,
+    pub link_check_concurrency: usize,
+    /// Operator hook, owners are notified through their webhooks.
+    pub link_check_webhook: Option<String>,
+    /// Seconds per delivery attempt.
+    pub webhook_timeout: u64,
+    /// Whether webhooks may point to loopback and private addresses.
+    pub webhook_allow_private: bool,
+    /// 0 stops publishing events to the stream.
+    pub event_stream_max_length: usize,
+    pub count_clicks_in_worker: bool,
+    pub worker_name: String,
+    /// 0 disables the cache.
+    pub url_cache_capacity: usize,
+    /// Seconds.
+    pub url_cache_ttl: u64,
+    /// Seconds an unknown id is remembered.
+    pub url_cache_negative_ttl: u64,
+}
+
+/// Optional values as they come from the TOML file or the environment.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSettings {
+    domain: Option<String>,
+    domains: Option<Vec<String>>,
+    deny_list: Option<Vec<String>>,
+    port: Option<u16>,
+    bind: Option<String>,
+    workers: Option<usize>,
+    keep_alive: Option<usize>,
+    backlog: Option<u32>,
+    max_payload: Option<usize>,
+    shutdown_timeout: Option<u64>,
+    redis_url: Option<String>,
+    redis_sentinels: Option<Vec<String>>,
+    redis_sentinel_master: Option<String>,
+    redis_namespace: Option<String>,
+    hashid_salt: Option<String>,
+    hashid_min_length: Option<usize>,
+    hashid_alphabet: Option<String>,
+    hashid_version: Option<u32>,
+    previous_hashids: Option<Vec<HashidConfig>>,
+    id_strategy: Option<String>,
+    id_length: Option<usize>,
+    node_id: Option<u16>,
+    secret: Option<String>,
+    previous_secrets: Option<Vec<String>>,
+    cookie_secure: Option<bool>,
+    cookie_same_site: Option<String>,
+    cookie_domain: Option<String>,
+    cookie_max_age: Option<u64>,
+    log_format: Option<String>,
+    otlp_endpoint: Option<String>,
+    fetch_metadata: Option<bool>,
+    metadata_timeout: Option<u64>,
+    metadata_max_bytes: Option<usize>,
+    link_check_interval: Option<u64>,
+    link_check_concurrency: Option<usize>,
+    link_check_webhook: Option<String>,
+    webhook_timeout: Option<u64>,
+    webhook_allow_private: Option<bool>,
+    event_stream_max_length: Option<usize>,
+    count_clicks_in_worker: Option<bool>,
+    worker_name: Option<String>,
+    url_cache_capacity: Option<usize>,
+    url_cache_ttl: Option<u64>,
+    url_cache_negative_ttl: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SettingsError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for problem in self.problems.iter() {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for SettingsError {}
+
+impl SettingsError {
+    fn single(problem: String) -> SettingsError {
+        SettingsError {
+            problems: vec![problem],
+        }
+    }
+}
+
+impl Settings {
+    /// Current hashid config first, followed by the previous ones.
+    pub fn hashid_configs(&self) -> Vec<HashidConfig> {
+        let current = HashidConfig {
+            version: self.hashid_version,
+            salt: self.hashid_salt.clone(),
+            min_length: self.hashid_min_length,
+            alphabet: self.hashid_alphabet.clone(),
+        };
+        std::iter::once(current)
+            .chain(self.previous_hashids.iter().cloned())
+            .collect()
+    }
+
+    /// Loads `.env`, the optional TOML file from `CONFIG_FILE` and the environment,
+    /// environment variables take precedence over the file.
+    pub fn load() -> Result<Settings, SettingsError> {
+        dotenv::dotenv().ok();
+        let file = match std::env::var("CONFIG_FILE") {
+            Ok(path) => Some(read_file(&path)?),
+            Err(_) if std::path::Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Some(read_file(DEFAULT_CONFIG_FILE)?)
+            }
+            Err(_) => None,
+        };
+        Settings::from_sources(file.as_deref(), |name| std::env::var(name).ok())
+    }
+
+    /// Only the required values, the same for every run whatever the environment.
+    #[cfg(test)]
+    pub fn for_tests() -> Settings {
+        Settings::from_sources(None, |name| {
+            let value = match name {
+                "DOMAIN" => "localhost:8080",
+                "PORT" => "8080",
+                "REDIS_URL" => "redis://127.0.0.1",
+                "HASHID_SALT" => "salt",
+                "HASHID_MIN_LENGTH" => "6",
+                "SECRET" => "397b6ad60d93275af050599ea9a6ec8a4e4eed0ebf0a19938c65d515dfe2",
+                _ => return None,
+            };
+            Some(value.to_string())
+        })
+        .expect("Test settings are valid")
+    }
+
+    fn from_sources<F>(file: Option<&str>, env: F) -> Result<Settings, SettingsError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut raw: RawSettings = match file {
+            Some(content) => toml::from_str(content)
+                .map_err(|e| SettingsError::single(format!("config file: {}", e)))?,
+            None => RawSettings::default(),
+        };
+
+        let mut problems = vec![];
+        let overrides = [
+            ("DOMAIN", &mut raw.domain),
+            ("BIND", &mut raw.bind),
+            ("REDIS_URL", &mut raw.redis_url),
+            ("REDIS_SENTINEL_MASTER", &mut raw.redis_sentinel_master),
+            ("REDIS_NAMESPACE", &mut raw.redis_namespace),
+            ("HASHID_SALT", &mut raw.hashid_salt),
+            ("HASHID_ALPHABET", &mut raw.hashid_alphabet),
+            ("ID_STRATEGY", &mut raw.id_strategy),
+            ("SECRET", &mut raw.secret),
+            ("COOKIE_SAME_SITE", &mut raw.cookie_same_site),
+            ("COOKIE_DOMAIN", &mut raw.cookie_domain),
+            ("LOG_FORMAT", &mut raw.log_format),
+            ("OTLP_ENDPOINT", &mut raw.otlp_endpoint),
+            ("LINK_CHECK_WEBHOOK", &mut raw.link_check_webhook),
+            ("WORKER_NAME", &mut raw.worker_name),
+        ];
+        for (name, value) in overrides {
+            if let Some(env_value) = env(name) {
+                *value = Some(env_value);
+            }
+        }
+        parse_env(&env, "PORT", "a port number", &mut raw.port, &mut problems);
+        parse_env(
+            &env,
+            "HASHID_MIN_LENGTH",
+            "a non-negative number",
+            &mut raw.hashid_min_length,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "HASHID_VERSION",
+            "a number",
+            &mut raw.hashid_version,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "ID_LENGTH",
+            "a number",
+            &mut raw.id_length,
+            &mut problems,
+        );
+        parse_env(&env, "NODE_ID", "a number", &mut raw.node_id, &mut problems);
+        parse_env(&env, "WORKERS", "a number", &mut raw.workers, &mut problems);
+        parse_env(
+            &env,
+            "KEEP_ALIVE",
+            "a number of seconds",
+            &mut raw.keep_alive,
+            &mut problems,
+        );
+        parse_env(&env, "BACKLOG", "a number", &mut raw.backlog, &mut problems);
+        parse_env(
+            &env,
+            "MAX_PAYLOAD",
+            "a number of bytes",
+            &mut raw.max_payload,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "SHUTDOWN_TIMEOUT",
+            "a number of seconds",
+            &mut raw.shutdown_timeout,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "FETCH_METADATA",
+            "true or false",
+            &mut raw.fetch_metadata,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "METADATA_TIMEOUT",
+            "a number of seconds",
+            &mut raw.metadata_timeout,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "METADATA_MAX_BYTES",
+            "a number of bytes",
+            &mut raw.metadata_max_bytes,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "LINK_CHECK_INTERVAL",
+            "a number of seconds",
+            &mut raw.link_check_interval,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "LINK_CHECK_CONCURRENCY",
+            "a number",
+            &mut raw.link_check_concurrency,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "WEBHOOK_TIMEOUT",
+            "a number of seconds",
+            &mut raw.webhook_timeout,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "WEBHOOK_ALLOW_PRIVATE",
+            "true or false",
+            &mut raw.webhook_allow_private,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "EVENT_STREAM_MAX_LENGTH",
+            "a number",
+            &mut raw.event_stream_max_length,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "COUNT_CLICKS_IN_WORKER",
+            "true or false",
+            &mut raw.count_clicks_in_worker,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "COOKIE_SECURE",
+            "true or false",
+            &mut raw.cookie_secure,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "COOKIE_MAX_AGE",
+            "a number of seconds",
+            &mut raw.cookie_max_age,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "URL_CACHE_CAPACITY",
+            "a number",
+            &mut raw.url_cache_capacity,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "URL_CACHE_TTL",
+            "a number of seconds",
+            &mut raw.url_cache_ttl,
+            &mut problems,
+        );
+        parse_env(
+            &env,
+            "URL_CACHE_NEGATIVE_TTL",
+            "a number of seconds",
+            &mut raw.url_cache_negative_ttl,
+            &mut problems,
+        );
+        if let Some(domains) = env("DOMAINS") {
+            raw.domains = Some(split_list(&domains));
+        }
+        if let Some(words) = env("DENY_LIST") {
+            raw.deny_list = Some(split_list(&words));
+        }
+        if let Some(secrets) = env("PREVIOUS_SECRETS") {
+            raw.previous_secrets = Some(split_list(&secrets));
+        }
+        if let Some(sentinels) = env("REDIS_SENTINELS") {
+            raw.redis_sentinels = Some(split_list(&sentinels));
+        }
+
+        raw.validate(problems)
+    }
+}
+
+/// Comma separated list, blank entries are skipped.
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Overrides `value` with a parsed environment variable, malformed values are reported.
+fn parse_env<F, T>(
+    env: &F,
+    name: &str,
+    expected: &str,
+    value: &mut Option<T>,
+    problems: &mut Vec<String>,
+) where
+    F: Fn(&str) -> Option<String>,
+    T: FromStr,
+{
+    if let Some(raw) = env(name) {
+        match raw.parse() {
+            Ok(parsed) => *value = Some(parsed),
+            Err(_) => problems.push(format!("{} must be {}, got {:?}", name, expected, raw)),
+        }
+    }
+}
+
+fn read_file(path: &str) -> Result<String, SettingsError> {
+    fs::read_to_string(path)
+        .map_err(|e| SettingsError::single(format!("unable to read {}: {}", path, e)))
+}
+
+fn validate_domain(name: &str, domain: &str, problems: &mut Vec<String>) {
+    if domain.is_empty() || domain.contains('/') || domain.contains(char::is_whitespace) {
+        problems.push(format!(
+            "{} must be a host name without scheme or path, got {:?}",
+            name, domain
+        ));
+    }
+}
+
+fn is_host_port(address: &str) -> bool {
+    match address.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+fn validate_hashids(configs: &[HashidConfig], problems: &mut Vec<String>) {
+    for (index, config) in configs.iter().enumerate() {
+        if configs[..index]
+            .iter()
+            .any(|other| other.version == config.version)
+        {
+            problems.push(format!(
+                "hashid version {} is configured more than once",
+                config.version
+            ));
+        }
+        let mut builder = Harsh::builder().salt(config.salt.as_str());
+        if let Some(alphabet) = &config.alphabet {
+            if !alphabet
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                problems.push(format!(
+                    "hashid alphabet of version {} may only contain letters, digits, - and _",
+                    config.version
+                ));
+            }
+            builder = builder.alphabet(alphabet.as_str());
+        }
+        if let Err(e) = builder.build() {
+            problems.push(format!("hashid version {}: {}", config.version, e));
+        }
+    }
+}
+
+/// Reports a missing value unless the variable was already reported as malformed.
+fn report_missing(name: &str, problems: &mut Vec<String>) {
+    let prefix = format!("{} ", name);
+    if !problems.iter().any(|problem| problem.starts_with(&prefix)) {
+        problems.push(format!("{} must be set", name));
+    }
+}
+
+impl RawSettings {
+    fn validate(self, mut problems: Vec<String>) -> Result<Settings, SettingsError> {
+        let mut required = |name: &str, value: Option<String>| match value {
+            Some(value) if !value.is_empty() => value,
+            _ => {
+                report_missing(name, &mut problems);
+                String::new()
+            }
+        };
+
+        let domain = required("DOMAIN", self.domain);
+        let redis_url = required("REDIS_URL", self.redis_url);
+        let hashid_salt = required("HASHID_SALT", self.hashid_salt);
+        let secret = required("SECRET", self.secret);
+        let domains = self.domains.unwrap_or_default();
+        let deny_list = self.deny_list.unwrap_or_default();
+
+        if !domain.is_empty() {
+            validate_domain("DOMAIN", &domain, &mut problems);
+        }
+        for extra in domains.iter() {
+            validate_domain("DOMAINS", extra, &mut problems);
+        }
+
+        let port = self.port.unwrap_or_else(|| {
+            report_missing("PORT", &mut problems);
+            0
+        });
+        let hashid_min_length = self.hashid_min_length.unwrap_or_else(|| {
+            report_missing("HASHID_MIN_LENGTH", &mut problems);
+            0
+        });
+
+        let bind = match self.bind.filter(|bind| !bind.is_empty()) {
+            Some(bind) => match bind.strip_prefix(UNIX_SOCKET_PREFIX) {
+                Some("") => {
+                    problems.push("BIND must contain a socket path after unix:".to_string());
+                    Bind::Unix(String::new())
+                }
+                Some(path) => Bind::Unix(path.to_string()),
+                None if !is_host_port(&bind) => {
+                    problems.push(format!(
+                        "BIND must be host:port or unix:/path/to/socket, got {:?}",
+                        bind
+                    ));
+                    Bind::Tcp(bind)
+                }
+                None => Bind::Tcp(bind),
+            },
+            None => Bind::Tcp(format!("0.0.0.0:{}", port)),
+        };
+        if self.workers == Some(0) {
+            problems.push("WORKERS must be greater than 0".to_string());
+        }
+        let backlog = self.backlog.unwrap_or(DEFAULT_BACKLOG);
+        if backlog == 0 || backlog > i32::MAX as u32 {
+            problems.push(format!("BACKLOG must be between 1 and {}", i32::MAX));
+        }
+        let max_payload = self.max_payload.unwrap_or(DEFAULT_MAX_PAYLOAD);
+        if max_payload == 0 {
+            problems.push("MAX_PAYLOAD must be greater than 0".to_string());
+        }
+
+        let redis_schemes = ["redis://", "rediss://", "unix://", "redis+unix://"];
+        if !redis_url.is_empty()
+            && !redis_schemes
+                .iter()
+                .any(|scheme| redis_url.starts_with(scheme))
+        {
+            problems.push(format!(
+                "REDIS_URL must start with one of {}",
+                redis_schemes.join(", ")
+            ));
+        }
+
+        let redis_namespace = self
+            .redis_namespace
+            .unwrap_or_else(|| DEFAULT_REDIS_NAMESPACE.to_string());
+        if Namespace::parse(&redis_namespace).is_none() {
+            problems.push(format!(
+                "REDIS_NAMESPACE must be non-empty without ':', spaces or glob characters, got {:?}",
+                redis_namespace
+            ));
+        }
+
+        let redis_sentinels = self.redis_sentinels.unwrap_or_default();
+        for node in redis_sentinels.iter() {
+            let valid = node
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+            if !valid {
+                problems.push(format!(
+                    "REDIS_SENTINELS entries must be host:port, got {:?}",
+                    node
+                ));
+            }
+        }
+
+        if !secret.is_empty() && secret.len() < MIN_SECRET_LENGTH {
+            problems.push(format!(
+                "SECRET must be at least {} bytes long",
+                MIN_SECRET_LENGTH
+            ));
+        }
+        let previous_secrets = self.previous_secrets.unwrap_or_default();
+        if previous_secrets
+            .iter()
+            .any(|previous| previous.len() < MIN_SECRET_LENGTH)
+        {
+            problems.push(format!(
+                "PREVIOUS_SECRETS must each be at least {} bytes long",
+                MIN_SECRET_LENGTH
+            ));
+        }
+
+        // plain http is only expected in development
+        let cookie_secure = self
+            .cookie_secure
+            .unwrap_or_else(|| !domain.starts_with("localhost"));
+        let cookie_same_site = match self.cookie_same_site.as_deref() {
+            None | Some("") | Some("lax") => CookieSameSite::Lax,
+            Some("strict") => CookieSameSite::Strict,
+            Some("none") => CookieSameSite::None,
+            Some(other) => {
+                problems.push(format!(
+                    "COOKIE_SAME_SITE must be one of strict, lax, none, got {:?}",
+                    other
+                ));
+                CookieSameSite::Lax
+            }
+        };
+        if cookie_same_site == CookieSameSite::None && !cookie_secure {
+            problems.push("COOKIE_SAME_SITE none needs COOKIE_SECURE".to_string());
+        }
+        let cookie_domain = self.cookie_domain.filter(|domain| !domain.is_empty());
+        if let Some(cookie_domain) = &cookie_domain {
+            validate_domain("COOKIE_DOMAIN", cookie_domain, &mut problems);
+        }
+        let cookie_max_age = self.cookie_max_age.unwrap_or(DEFAULT_COOKIE_MAX_AGE);
+        if cookie_max_age == 0 || cookie_max_age > i64::MAX as u64 {
+            problems.push("COOKIE_MAX_AGE must be greater than 0".to_string());
+        }
+
+        let hashid_version = self.hashid_version.unwrap_or(1);
+        let hashid_alphabet = self.hashid_alphabet.filter(|alphabet| !alphabet.is_empty());
+        let previous_hashids = self.previous_hashids.unwrap_or_default();
+        if !hashid_salt.is_empty() {
+            let current = HashidConfig {
+                version: hashid_version,
+                salt: hashid_salt.clone(),
+                min_length: hashid_min_length,
+                alphabet: hashid_alphabet.clone(),
+            };
+            let configs: Vec<HashidConfig> = std::iter::once(current)
+                .chain(previous_hashids.iter().cloned())
+                .collect();
+            validate_hashids(&configs, &mut problems);
+        }
+
+        let id_strategy = match self.id_strategy.as_deref() {
+            None | Some("") | Some("hashids") => IdStrategy::Hashids,
+            Some("random") => IdStrategy::Random,
+            Some("snowflake") => IdStrategy::Snowflake,
+            Some("words") => IdStrategy::Words,
+            Some(other) => {
+                problems.push(format!(
+                    "ID_STRATEGY must be one of hashids, random, snowflake, words, got {:?}",
+                    other
+                ));
+                IdStrategy::Hashids
+            }
+        };
+        let id_length = self.id_length.unwrap_or(DEFAULT_ID_LENGTH);
+        if !(MIN_ID_LENGTH..=MAX_ID_LENGTH).contains(&id_length) {
+            problems.push(format!(
+                "ID_LENGTH must be between {} and {}",
+                MIN_ID_LENGTH, MAX_ID_LENGTH
+            ));
+        }
+        let node_id = self.node_id.unwrap_or(0);
+        if node_id > MAX_NODE_ID {
+            problems.push(format!("NODE_ID must not exceed {}", MAX_NODE_ID));
+        }
+
+        let log_format = match self.log_format.as_deref() {
+            None | Some("") | Some("text") => LogFormat::Text,
+            Some("json") => LogFormat::Json,
+            Some(other) => {
+                problems.push(format!(
+                    "LOG_FORMAT must be either text or json, got {:?}",
+                    other
+                ));
+                LogFormat::Text
+            }
+        };
+
+        let otlp_endpoint = self.otlp_endpoint.filter(|endpoint| !endpoint.is_empty());
+        if let Some(endpoint) = &otlp_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                problems.push(format!(
+                    "OTLP_ENDPOINT must be an http(s) url, got {:?}",
+                    endpoint
+                ));
+            }
+        }
+
+        let metadata_timeout = self.metadata_timeout.unwrap_or(DEFAULT_METADATA_TIMEOUT);
+        if metadata_timeout == 0 {
+            problems.push("METADATA_TIMEOUT must be greater than 0".to_string());
+        }
+        let metadata_max_bytes = self
+            .metadata_max_bytes
+            .unwrap_or(DEFAULT_METADATA_MAX_BYTES);
+        if metadata_max_bytes == 0 {
+            problems.push("METADATA_MAX_BYTES must be greater than 0".to_string());
+        }
+        let link_check_concurrency = self
+            .link_check_concurrency
+            .unwrap_or(DEFAULT_LINK_CHECK_CONCURRENCY);
+        if link_check_concurrency == 0 {
+            problems.push("LINK_CHECK_CONCURRENCY must be greater than 0".to_string());
+        }
+        let link_check_webhook = self
+            .link_check_webhook
+            .filter(|webhook| !webhook.is_empty());
+        if let Some(webhook) = &link_check_webhook {
+            if !webhook.starts_with("http://") && !webhook.starts_with("https://") {
+                problems.push(format!(
+                    "LINK_CHECK_WEBHOOK must be an http(s) url, got {:?}",
+                    webhook
+                ));
+            }
+        }
+        let webhook_timeout = self.webhook_timeout.unwrap_or(DEFAULT_WEBHOOK_TIMEOUT);
+        if webhook_timeout == 0 {
+            problems.push("WEBHOOK_TIMEOUT must be greater than 0".to_string());
+        }
+        let event_stream_max_length = self
+            .event_stream_max_length
+            .unwrap_or(DEFAULT_EVENT_STREAM_MAX_LENGTH);
+        let count_clicks_in_worker = self.count_clicks_in_worker.unwrap_or(false);
+        if count_clicks_in_worker && event_stream_max_length == 0 {
+            problems.push(
+                "COUNT_CLICKS_IN_WORKER needs the event stream, EVENT_STREAM_MAX_LENGTH is 0"
+                    .to_string(),
+            );
+        }
+        let worker_name = self
+            .worker_name
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| DEFAULT_WORKER_NAME.to_string());
+        let url_cache_capacity = self
+            .url_cache_capacity
+            .unwrap_or(DEFAULT_URL_CACHE_CAPACITY);
+        let url_cache_ttl = self.url_cache_ttl.unwrap_or(DEFAULT_URL_CACHE_TTL);
+        if url_cache_capacity > 0 && url_cache_ttl == 0 {
+            problems.push("URL_CACHE_TTL must be greater than 0".to_string());
+        }
+
+        if !problems.is_empty() {
+            return Err(SettingsError { problems });
+        }
+        Ok(Settings {
+            domain,
+            domains,
+            deny_list,
+            port,
+            bind,
+            workers: self.workers,
+            keep_alive: self.keep_alive.unwrap_or(DEFAULT_KEEP_ALIVE),
+            backlog,
+            max_payload,
+            shutdown_timeout: self.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            redis_url,
+            redis_sentinels,
+            redis_sentinel_master: self
+                .redis_sentinel_master
+                .filter(|master| !master.is_empty())
+                .unwrap_or_else(|| DEFAULT_REDIS_SENTINEL_MASTER.to_string()),
+            redis_namespace,
+            hashid_salt,
+            hashid_min_length,
+            hashid_alphabet,
+            hashid_version,
+            previous_hashids,
+            id_strategy,
+            id_length,
+            node_id,
+            secret,
+            previous_secrets,
+            cookie_secure,
+            cookie_same_site,
+            cookie_domain,
+            cookie_max_age,
+            log_format,
+            otlp_endpoint,
+            fetch_metadata: self.fetch_metadata.unwrap_or(true),
+            metadata_timeout,
+            metadata_max_bytes,
+            link_check_interval: self
+                .link_check_interval
+                .unwrap_or(DEFAULT_LINK_CHECK_INTERVAL),
+            link_check_concurrency,
+            link_check_webhook,
+            webhook_timeout,
+            webhook_allow_private: self.webhook_allow_private.unwrap_or(false),
+            event_stream_max_length,
+            count_clicks_in_worker,
+            worker_name,
+            url_cache_capacity,
+            url_cache_ttl,
+            url_cache_negative_ttl: self
+                .url_cache_negative_ttl
+                .unwrap_or(DEFAULT_URL_CACHE_NEGATIVE_TTL),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const SECRET: &str = "397b6ad60d93275af050599ea9a6ec8a4e4eed0ebf0a19938c65d515dfe2";
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_from_env() {
+        let settings = Settings::from_sources(
+            None,
+            env(&[
+                ("DOMAIN", "localhost:8080"),
+                ("DOMAINS", "go.example.com, short.example.com"),
+                ("DENY_LIST", "acme,"),
+                ("PORT", "8080"),
+                ("REDIS_URL", "redis://127.0.0.1"),
+                ("HASHID_SALT", "salt"),
+                ("HASHID_MIN_LENGTH", "6"),
+                ("SECRET", SECRET),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(settings.domain, "localhost:8080");
+        assert_eq!(
+            settings.domains,
+            vec!["go.example.com", "short.example.com"]
+        );
+        assert_eq!(settings.deny_list, vec!["acme"]);
+        assert_eq!(settings.port, 8080);
+        assert_eq!(settings.hashid_min_length, 6);
+        assert_eq!(settings.id_strategy, IdStrategy::Hashids);
+        assert_eq!(settings.log_format, LogFormat::Text);
+        assert_eq!(settings.otlp_endpoint, None);
+        assert!(settings.fetch_metadata);
+        assert_eq!(settings.metadata_timeout, DEFAULT_METADATA_TIMEOUT);
+        assert_eq!(settings.link_check_interval, DEFAULT_LINK_CHECK_INTERVAL);
+        assert_eq!(settings.link_check_webhook, None);
+        assert_eq!(settings.webhook_timeout, DEFAULT_WEBHOOK_TIMEOUT);
+        assert!(!settings.webhook_allow_private);
+        assert_eq!(
+            settings.event_stream_max_length,
+            DEFAULT_EVENT_STREAM_MAX_LENGTH
+        );
+        assert!(!settings.count_clicks_in_worker);
+        assert_eq!(settings.worker_name, DEFAULT_WORKER_NAME);
+        assert_eq!(settings.url_cache_capacity, DEFAULT_URL_CACHE_CAPACITY);
+        assert_eq!(settings.url_cache_ttl, DEFAULT_URL_CACHE_TTL);
+        assert!(settings.redis_sentinels.is_empty());
+        assert_eq!(
+            settings.redis_sentinel_master,
+            DEFAULT_REDIS_SENTINEL_MASTER
+        );
+        assert_eq!(settings.redis_namespace, DEFAULT_REDIS_NAMESPACE);
+        assert!(settings.previous_secrets.is_empty());
+        assert!(!settings.cookie_secure);
+        assert_eq!(settings.cookie_same_site, CookieSameSite::Lax);
+        assert_eq!(settings.cookie_domain, None);
+        assert_eq!(settings.cookie_max_age, DEFAULT_COOKIE_MAX_AGE);
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let file = format!(
+            r#"
+            domain = "urls.lol"
+            domains = ["go.example.com"]
+            port = 8000
+            redis_url = "redis://redis"
+            hashid_salt = "salt"
+            hashid_min_length = 6
+            id_strategy = "snowflake"
+            node_id = 7
+            secret = "{}"
+            "#,
+            SECRET
+        );
+        let settings = Settings::from_sources(
+            Some(&file),
+            env(&[
+                ("PORT", "9000"),
+                ("FETCH_METADATA", "false"),
+                ("LINK_CHECK_INTERVAL", "0"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(settings.domain, "urls.lol");
+        assert_eq!(settings.domains, vec!["go.example.com"]);
+        assert_eq!(settings.port, 9000);
+        assert_eq!(settings.id_strategy, IdStrategy::Snowflake);
+        assert_eq!(settings.node_id, 7);
+        assert!(!settings.fetch_metadata);
+        assert_eq!(settings.link_check_interval, 0);
+    }
+
+    #[test]
+    fn test_reports_all_problems() {
+        let error = Settings::from_sources(
+            None,
+            env(&[
+                ("DOMAIN", "https://urls.lol"),
+                ("PORT", "http"),
+                ("REDIS_URL", "127.0.0.1"),
+                ("HASHID_MIN_LENGTH", "-1"),
+                ("SECRET", "short"),
+                ("ID_STRATEGY", "uuid"),
+                ("LOG_FORMAT", "xml"),
+                ("OTLP_ENDPOINT", "collector:4318"),
+            ]),
+        )
+        .unwrap_err();
+        assert_eq!(error.problems.len(), 9);
+        assert!(error
+            .problems
+            .contains(&"HASHID_SALT must be set".to_string()));
+    }
+
+    #[test]
+    fn test_redis_topology() {
+        let required = [
+            ("DOMAIN", "localhost:8080"),
+            ("PORT", "8080"),
+            ("REDIS_URL", "redis://:secret@redis"),
+            ("HASHID_SALT", "salt"),
+            ("HASHID_MIN_LENGTH", "6"),
+            ("SECRET", SECRET),
+        ];
+        let with = |vars: &[(&'static str, &'static str)]| {
+            let mut all = required.to_vec();
+            all.extend_from_slice(vars);
+            Settings::from_sources(None, env(&all))
+        };
+        let settings = with(&[
+            ("REDIS_SENTINELS", "sentinel-1:26379, sentinel-2:26379"),
+            ("REDIS_SENTINEL_MASTER", "urls"),
+        ])
+        .unwrap();
+        assert_eq!(
+            settings.redis_sentinels,
+            vec!["sentinel-1:26379", "sentinel-2:26379"]
+        );
+        assert_eq!(settings.redis_sentinel_master, "urls");
+
+        let error = with(&[("REDIS_SENTINELS", "sentinel")]).unwrap_err();
+        assert_eq!(error.problems.len(), 1);
+
+        let settings = with(&[("REDIS_NAMESPACE", "staging")]).unwrap();
+        assert_eq!(settings.redis_namespace, "staging");
+        assert!(with(&[("REDIS_NAMESPACE", "url_shortener:staging")]).is_err());
+    }
+
+    #[test]
+    fn test_cookies() {
+        let required = [
+            ("DOMAIN", "urls.lol"),
+            ("PORT", "8080"),
+            ("REDIS_URL", "redis://redis"),
+            ("HASHID_SALT", "salt"),
+            ("HASHID_MIN_LENGTH", "6"),
+            ("SECRET", SECRET),
+        ];
+        let with = |vars: &[(&'static str, &'static str)]| {
+            let mut all = required.to_vec();
+            all.extend_from_slice(vars);
+            Settings::from_sources(None, env(&all))
+        };
+        let settings = with(&[]).unwrap();
+        assert!(settings.cookie_secure);
+
+        let settings = with(&[
+            ("PREVIOUS_SECRETS", &SECRET[1..]),
+            ("COOKIE_SAME_SITE", "strict"),
+            ("COOKIE_DOMAIN", "urls.lol"),
+            ("COOKIE_MAX_AGE", "3600"),
+        ])
+        .unwrap();
+        assert_eq!(settings.previous_secrets, vec![&SECRET[1..]]);
+        assert_eq!(settings.cookie_same_site, CookieSameSite::Strict);
+        assert_eq!(settings.cookie_domain, Some("urls.lol".to_string()));
+        assert_eq!(settings.cookie_max_age, 3600);
+
+        let error = with(&[
+            ("PREVIOUS_SECRETS", "short"),
+            ("COOKIE_SECURE", "false"),
+            ("COOKIE_SAME_SITE", "none"),
+            ("COOKIE_MAX_AGE", "0"),
+        ])
+        .unwrap_err();
+        assert_eq!(error.problems.len(), 3);
+    }
+
+    #[test]
+    fn test_previous_hashids() {
+        let file = format!(
+            r#"
+            domain = "urls.lol"
+            port = 8000
+            redis_url = "redis://redis"
+            hashid_salt = "new salt"
+            hashid_min_length = 8
+            hashid_version = 2
+            secret = "{}"
+
+            [[previous_hashids]]
+            version = 1
+            salt = "salt"
+            min_length = 6
+            "#,
+            SECRET
+        );
+        let settings = Settings::from_sources(Some(&file), env(&[])).unwrap();
+        let configs = settings.hashid_configs();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[0].version, 2);
+        assert_eq!(configs[1].salt, "salt");
+
+        let error = Settings::from_sources(
+            Some(&file),
+            env(&[("HASHID_VERSION", "1"), ("HASHID_ALPHABET", "abc")]),
+        )
+        .unwrap_err();
+        assert_eq!(error.problems.len(), 2, "{:?}", error.problems);
+    }
+
+    #[test]
+    fn test_server_tuning() {
+        let required = [
+            ("DOMAIN", "localhost:8080"),
+            ("PORT", "8080"),
+            ("REDIS_URL", "redis://127.0.0.1"),
+            ("HASHID_SALT", "salt"),
+            ("HASHID_MIN_LENGTH", "6"),
+            ("SECRET", SECRET),
+        ];
+        let settings = Settings::from_sources(None, env(&required)).unwrap();
+        assert_eq!(settings.bind, Bind::Tcp("0.0.0.0:8080".to_string()));
+        assert_eq!(settings.workers, None);
+        assert_eq!(settings.backlog, DEFAULT_BACKLOG);
+        assert_eq!(settings.shutdown_timeout, DEFAULT_SHUTDOWN_TIMEOUT);
+
+        let mut vars = required.to_vec();
+        vars.extend_from_slice(&[
+            ("BIND", "unix:/tmp/url_shortener.sock"),
+            ("WORKERS", "4"),
+            ("KEEP_ALIVE", "0"),
+            ("MAX_PAYLOAD", "4096"),
+        ]);
+        let settings = Settings::from_sources(None, env(&vars)).unwrap();
+        assert_eq!(
+            settings.bind,
+            Bind::Unix("/tmp/url_shortener.sock".to_string())
+        );
+        assert_eq!(settings.workers, Some(4));
+        assert_eq!(settings.keep_alive, 0);
+        assert_eq!(settings.max_payload, 4096);
+
+        let mut vars = required.to_vec();
+        vars.extend_from_slice(&[("BIND", "8080"), ("WORKERS", "0"), ("BACKLOG", "-1")]);
+        let error = Settings::from_sources(None, env(&vars)).unwrap_err();
+        assert_eq!(error.problems.len(), 3);
+    }
+
+    #[test]
+    fn test_unknown_file_key() {
+        let error = Settings::from_sources(Some("domian = \"urls.lol\""), env(&[])).unwrap_err();
+        assert!(error.problems[0].starts_with("config file"));
+    }
+}
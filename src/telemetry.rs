@@ -0,0 +1,198 @@
+use crate::settings::{LogFormat, Settings};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::task::{Context, Poll};
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const SERVICE_NAME: &str = "url_shortener";
+/// Longest request id accepted from clients, longer ones are replaced.
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+/// Keeps the span exporter alive, spans are flushed on `shutdown`.
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush spans: {}", e);
+            }
+        }
+    }
+}
+
+/// Installs the global subscriber: `RUST_LOG` filtering, text or JSON output
+/// and an optional OTLP/HTTP span exporter.
+pub fn configure(settings: &Settings) -> Telemetry {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let output = match settings.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+
+    let provider = settings.otlp_endpoint.as_ref().map(|endpoint| {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint.as_str())
+            .build()
+            .expect("Unable to configure OTLP exporter");
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+            .build()
+    });
+    let otlp = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME)));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .with(otlp)
+        .init();
+
+    Telemetry { provider }
+}
+
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty()
+                && value.len() <= MAX_REQUEST_ID_LENGTH
+                && value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()))
+}
+
+/// Wraps every request into a span carrying a request id, the id is taken from
+/// the `X-Request-Id` header when present and echoed back in the response.
+pub struct RequestId;
+
+impl<S, B> Transform<S> for RequestId
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdMiddleware { service })
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestIdMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let request_id = request_id(&req);
+        let span = tracing::info_span!(
+            "http_request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %req.path(),
+            status = tracing::field::Empty,
+        );
+        let response = {
+            let _entered = span.enter();
+            self.service.call(req)
+        };
+
+        Box::pin(
+            async move {
+                let mut response = response.await?;
+                let status = response.status();
+                tracing::Span::current().record("status", status.as_u16());
+                if status.is_server_error() {
+                    tracing::error!("request failed");
+                } else {
+                    tracing::info!("request finished");
+                }
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+                Ok(response)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_web::main]
+    #[test]
+    async fn test_request_id() {
+        let mut sut = test::init_service(
+            App::new()
+                .wrap(RequestId)
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "from-client")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(
+            resp.headers().get(REQUEST_ID_HEADER).unwrap(),
+            "from-client"
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "not valid")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        let generated = resp.headers().get(REQUEST_ID_HEADER).unwrap();
+        assert_eq!(generated.len(), 32);
+    }
+}
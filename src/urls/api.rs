@@ -1,15 +1,101 @@
-use actix_web::{error, http, web, Error, HttpResponse, Result};
-use validator::Validate;
+use actix_web::cookie::Cookie;
+use actix_web::{error, http, web, Error, HttpMessage, HttpRequest, HttpResponse, Result};
+use validator::{Validate, ValidationError, ValidationErrors};
 
+use super::error::UrlError;
+use super::qr::{self, QrParams};
 use super::types::*;
+use super::utils::BuildUrl;
+use crate::domains::Domains;
+use crate::security_headers;
+use crate::session;
 use actix_identity::Identity;
+use std::collections::BTreeMap;
 use tera::Tera;
 
-pub fn configure<T: 'static + UrlService>(service: web::Data<T>, cfg: &mut web::ServiceConfig) {
+pub fn configure<T: 'static + UrlService>(
+    service: web::Data<T>,
+    domains: web::Data<Domains>,
+    cfg: &mut web::ServiceConfig,
+) {
     cfg.app_data(service);
+    cfg.app_data(domains);
+    // registered before `/{id}`, which would match them otherwise
+    cfg.route("/healthz", web::get().to(healthz));
+    cfg.route("/readyz", web::get().to(readyz::<T>));
+    cfg.route("/workspaces", web::get().to(list_workspaces::<T>));
+    cfg.route("/workspaces", web::post().to(create_workspace::<T>));
+    cfg.route(
+        "/workspaces/{workspace}/urls",
+        web::get().to(workspace_urls::<T>),
+    );
+    cfg.route(
+        "/workspaces/{workspace}/invites",
+        web::post().to(create_invite::<T>),
+    );
+    cfg.route("/invites/{token}", web::post().to(accept_invite::<T>));
+    cfg.route("/webhooks", web::get().to(list_webhooks::<T>));
+    cfg.route("/webhooks", web::post().to(create_webhook::<T>));
+    cfg.route("/webhooks/deliveries", web::get().to(deliveries::<T>));
+    cfg.route("/webhooks/dead_letters", web::get().to(dead_letters::<T>));
+    cfg.route("/webhooks/{webhook}", web::delete().to(delete_webhook::<T>));
     cfg.route("/", web::get().to(index::<T>));
     cfg.route("/", web::post().to(shorten::<T>));
     cfg.route("/{id}", web::get().to(redirect::<T>));
+    cfg.route("/{id}", web::patch().to(update_url::<T>));
+    cfg.route("/{id}", web::delete().to(delete_url::<T>));
+    cfg.route("/{id}/stats", web::get().to(stats::<T>));
+    cfg.route("/{id}/move", web::post().to(move_url::<T>));
+    cfg.route("/{id}/qr.png", web::get().to(qr_png::<T>));
+    cfg.route("/{id}/qr.svg", web::get().to(qr_svg::<T>));
+}
+
+/// Cookie pinning a visitor to one variant of a split link, scoped to the link path.
+const VARIANT_COOKIE: &str = "variant";
+const VARIANT_COOKIE_DAYS: i64 = 30;
+
+/// User agent fragments of link preview crawlers, lowercase.
+const PREVIEW_CRAWLERS: &[&str] = &[
+    "applebot",
+    "bluesky",
+    "discordbot",
+    "embedly",
+    "facebookexternalhit",
+    "iframely",
+    "linkedinbot",
+    "mastodon",
+    "pinterest",
+    "redditbot",
+    "skypeuripreview",
+    "slackbot",
+    "slack-imgproxy",
+    "telegrambot",
+    "twitterbot",
+    "vkshare",
+    "whatsapp",
+];
+
+fn is_preview_crawler(request: &HttpRequest) -> bool {
+    match request
+        .headers()
+        .get(http::header::USER_AGENT)
+        .and_then(|agent| agent.to_str().ok())
+    {
+        Some(agent) => {
+            let agent = agent.to_lowercase();
+            PREVIEW_CRAWLERS
+                .iter()
+                .any(|crawler| agent.contains(crawler))
+        }
+        None => false,
+    }
+}
+
+/// Domain whose links are addressed by the request `Host`.
+fn request_domain(domains: &Domains, request: &HttpRequest) -> String {
+    domains
+        .resolve(request.connection_info().host())
+        .to_string()
 }
 
 pub async fn index<T: UrlService>(
@@ -17,21 +103,30 @@ pub async fn index<T: UrlService>(
     page_params: web::Query<PageParams>,
     identity: Identity,
     template: web::Data<Tera>,
+    domains: web::Data<Domains>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
-    let user = identity
-        .identity()
-        .unwrap_or(service.new_user().await.unwrap());
-    identity.remember(user.clone());
-
-    match page_params.page {
-        Some(page) => {
-            let urls: Paginated<ResponseUrl> = service.get_urls_for_user(&*user, page).await.into();
-            Ok(HttpResponse::Ok().json(urls))
+    // first time visitors have no links yet, they become users once they shorten one
+    let query = page_params.query();
+    let urls: Paginated<ResponseUrl> = match identity.identity() {
+        Some(user) => {
+            identity.remember(user.clone());
+            service
+                .get_urls_for_user(&user, page_params.page.unwrap_or(0), &query)
+                .await
+                .into()
         }
+        None => Paginated::empty(),
+    };
+    match page_params.page {
+        Some(_) => Ok(HttpResponse::Ok().json(urls)),
         None => {
             let mut ctx = tera::Context::new();
-            let urls: Paginated<ResponseUrl> = service.get_urls_for_user(&*user, 0).await.into();
             ctx.insert("urls", &urls);
+            ctx.insert("domains", &domains.all());
+            ctx.insert("query", &query);
+            ctx.insert("csrf_token", &session::csrf_token(&req));
+            ctx.insert("csp_nonce", &security_headers::csp_nonce(&req));
 
             let res = template
                 .render("index.html", &ctx)
@@ -41,75 +136,594 @@ pub async fn index<T: UrlService>(
     }
 }
 
-async fn shorten<T: UrlService>(
+async fn shorten<T: 'static + UrlService>(
     service: web::Data<T>,
     identity: Identity,
     data: web::Json<CreateUrl>,
+    domains: web::Data<Domains>,
 ) -> Result<HttpResponse, Error> {
-    let user = identity
-        .identity()
-        .unwrap_or(service.new_user().await.unwrap());
-    identity.remember(user.clone());
-
     let url_create = data.into_inner();
-    url_create
-        .validate()
-        .map_err(|e| error::ErrorBadRequest(serde_json::to_string(&e).unwrap()))?;
+    url_create.validate().map_err(validation_error)?;
+    let user = current_user(&service, &identity).await?;
 
-    let result = service.shorten(&url_create.url, &user).await;
+    let mut new_url = NewUrl::from(url_create);
+    if new_url.domain.is_empty() {
+        new_url.domain = domains.default_domain().to_string();
+    } else if !domains.is_known(&new_url.domain) {
+        return Err(error::ErrorBadRequest("Unknown domain"));
+    }
+
+    let result = service.shorten(&new_url, &user).await;
     match result {
-        Ok(url) => Ok(HttpResponse::Ok().json(ResponseUrl::from(url))),
-        _ => Ok(HttpResponse::BadRequest().finish()),
+        Ok(url) => {
+            let (domain, id) = (url.domain.clone(), url.id.clone());
+            let service = service.clone();
+            actix_rt::spawn(async move {
+                if let Err(e) = service.refresh_metadata(&domain, &id).await {
+                    tracing::warn!(domain = %domain, id = %id, error = %e, "metadata was not saved");
+                }
+            });
+            Ok(HttpResponse::Ok().json(ResponseUrl::from(url)))
+        }
+        Err(UrlError::AliasTaken) => Err(error::ErrorConflict(UrlError::AliasTaken)),
+        Err(e @ UrlError::AliasNotAllowed(_)) => {
+            // same shape as the validation errors above
+            let mut alias_error = ValidationError::new("alias");
+            alias_error.message = Some(e.to_string().into());
+            let mut errors = ValidationErrors::new();
+            errors.add("alias", alias_error);
+            Err(validation_error(errors))
+        }
+        Err(e) => Err(e.into()),
     }
 }
 
 pub async fn redirect<T: UrlService>(
     service: web::Data<T>,
     params: web::Path<RedirectParams>,
+    request: HttpRequest,
+    domains: web::Data<Domains>,
+    template: Option<web::Data<Tera>>,
 ) -> Result<HttpResponse, Error> {
-    let result = service.get(&params.id).await;
+    if let Some(template) = template.filter(|_| is_preview_crawler(&request)) {
+        let domain = request_domain(&domains, &request);
+        return preview_card(&service, &template, &domain, &params.id).await;
+    }
+    let variant = request
+        .cookie(VARIANT_COOKIE)
+        .and_then(|cookie| cookie.value().parse().ok());
+    let domain = request_domain(&domains, &request);
+    let result = service.get(&domain, &params.id, variant).await;
     match result {
-        Ok(result) => Ok(HttpResponse::Found()
-            .header(http::header::LOCATION, result.url)
-            .finish()),
-        _ => Ok(HttpResponse::BadRequest().finish()),
+        Ok(visit) => {
+            let mut response = HttpResponse::Found();
+            response.header(http::header::LOCATION, visit.location());
+            // crawlers get a preview card from the same url
+            response.header(http::header::VARY, "User-Agent");
+            if let Some(variant) = visit.variant {
+                response.cookie(
+                    Cookie::build(VARIANT_COOKIE, variant.to_string())
+                        .path(format!("/{}", params.id))
+                        .max_age(time::Duration::days(VARIANT_COOKIE_DAYS))
+                        .http_only(true)
+                        .finish(),
+                );
+            }
+            Ok(response.finish())
+        }
+        Err(UrlError::NotFound) => Ok(HttpResponse::BadRequest().finish()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn update_url<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+    params: web::Path<RedirectParams>,
+    data: web::Json<UpdateUrl>,
+    request: HttpRequest,
+    domains: web::Data<Domains>,
+) -> Result<HttpResponse, Error> {
+    data.validate().map_err(validation_error)?;
+    let user = current_user(&service, &identity).await?;
+    let domain = request_domain(&domains, &request);
+    match service.update_url(&user, &domain, &params.id, &data).await {
+        Ok(url) => Ok(HttpResponse::Ok().json(ResponseUrl::from(url))),
+        Err(UrlError::NotFound) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn delete_url<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+    params: web::Path<RedirectParams>,
+    request: HttpRequest,
+    domains: web::Data<Domains>,
+) -> Result<HttpResponse, Error> {
+    // visitors without a session own no links
+    let user = existing_user(&identity).ok_or(UrlError::NotFound)?;
+    let domain = request_domain(&domains, &request);
+    service.delete_url(&user, &domain, &params.id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Page with the Open Graph tags of a link for crawlers, which would otherwise
+/// show the card of the destination. Not counted as a click.
+async fn preview_card<T: UrlService>(
+    service: &web::Data<T>,
+    template: &Tera,
+    domain: &str,
+    id: &str,
+) -> Result<HttpResponse, Error> {
+    match service.lookup(domain, id).await {
+        Ok(url) => {
+            let mut ctx = tera::Context::new();
+            ctx.insert("card", &url.preview_card());
+            ctx.insert("short_url", &url.build_url());
+            ctx.insert("long_url", &url.url);
+            let body = template
+                .render("preview.html", &ctx)
+                .map_err(|_| error::ErrorInternalServerError("Template error"))?;
+            Ok(HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .header(http::header::VARY, "User-Agent")
+                .body(body))
+        }
+        Err(UrlError::NotFound) => Ok(HttpResponse::BadRequest().finish()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn stats<T: UrlService>(
+    service: web::Data<T>,
+    params: web::Path<RedirectParams>,
+    request: HttpRequest,
+    domains: web::Data<Domains>,
+) -> Result<HttpResponse, Error> {
+    let domain = request_domain(&domains, &request);
+    match service.lookup(&domain, &params.id).await {
+        Ok(url) => Ok(HttpResponse::Ok().json(UrlStats::from(url))),
+        Err(UrlError::NotFound) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Remembered user, a new one is created for first time visitors. Only for
+/// handlers that write, reads use `existing_user`.
+async fn current_user<T: UrlService>(
+    service: &web::Data<T>,
+    identity: &Identity,
+) -> Result<String, Error> {
+    let user = match identity.identity() {
+        Some(user) => user,
+        None => service.new_user().await?,
+    };
+    identity.remember(user.clone());
+    Ok(user)
+}
+
+/// Remembered user, `None` for first time visitors and crawlers.
+fn existing_user(identity: &Identity) -> Option<String> {
+    identity.identity()
+}
+
+/// Field errors as JSON, like the validator reports them.
+fn validation_error(errors: ValidationErrors) -> Error {
+    match serde_json::to_string(&errors) {
+        Ok(body) => error::ErrorBadRequest(body),
+        Err(_) => error::ErrorBadRequest("Invalid request"),
+    }
+}
+
+async fn list_workspaces<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+) -> Result<HttpResponse, Error> {
+    let workspaces = match existing_user(&identity) {
+        Some(user) => service.get_workspaces_for_user(&user).await?,
+        None => vec![],
+    };
+    Ok(HttpResponse::Ok().json(workspaces))
+}
+
+async fn create_workspace<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+    data: web::Json<CreateWorkspace>,
+) -> Result<HttpResponse, Error> {
+    data.validate().map_err(validation_error)?;
+    let user = current_user(&service, &identity).await?;
+    let workspace = service.create_workspace(&user, &data.name).await?;
+    Ok(HttpResponse::Ok().json(Membership {
+        workspace,
+        role: Role::Owner,
+    }))
+}
+
+async fn workspace_urls<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+    params: web::Path<WorkspaceParams>,
+    page_params: web::Query<PageParams>,
+) -> Result<HttpResponse, Error> {
+    let user = existing_user(&identity).ok_or_else(|| error::ErrorUnauthorized("Unknown user"))?;
+    let urls: Paginated<ResponseUrl> = service
+        .get_urls_for_workspace(&user, &params.workspace, page_params.page.unwrap_or(0))
+        .await?
+        .into();
+    Ok(HttpResponse::Ok().json(urls))
+}
+
+async fn create_invite<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+    params: web::Path<WorkspaceParams>,
+    data: web::Json<CreateInvite>,
+) -> Result<HttpResponse, Error> {
+    let user = current_user(&service, &identity).await?;
+    let invite = service
+        .create_invite(&user, &params.workspace, data.role)
+        .await?;
+    Ok(HttpResponse::Ok().json(invite))
+}
+
+async fn accept_invite<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+    params: web::Path<InviteParams>,
+) -> Result<HttpResponse, Error> {
+    let user = current_user(&service, &identity).await?;
+    let membership = service.accept_invite(&user, &params.token).await?;
+    Ok(HttpResponse::Ok().json(membership))
+}
+
+async fn list_webhooks<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+) -> Result<HttpResponse, Error> {
+    let webhooks = match existing_user(&identity) {
+        Some(user) => service.get_webhooks(&user).await?,
+        None => vec![],
+    };
+    Ok(HttpResponse::Ok().json(webhooks))
+}
+
+async fn create_webhook<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+    data: web::Json<CreateWebhook>,
+) -> Result<HttpResponse, Error> {
+    data.validate().map_err(validation_error)?;
+    let user = current_user(&service, &identity).await?;
+    let webhook = service.create_webhook(&user, &data).await?;
+    Ok(HttpResponse::Ok().json(webhook))
+}
+
+async fn delete_webhook<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+    params: web::Path<WebhookParams>,
+) -> Result<HttpResponse, Error> {
+    let user = current_user(&service, &identity).await?;
+    service.delete_webhook(&user, &params.webhook).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn deliveries<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+) -> Result<HttpResponse, Error> {
+    let deliveries = match existing_user(&identity) {
+        Some(user) => service.get_deliveries(&user).await?,
+        None => vec![],
+    };
+    Ok(HttpResponse::Ok().json(deliveries))
+}
+
+async fn dead_letters<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+) -> Result<HttpResponse, Error> {
+    let dead_letters = match existing_user(&identity) {
+        Some(user) => service.get_dead_letters(&user).await?,
+        None => vec![],
+    };
+    Ok(HttpResponse::Ok().json(dead_letters))
+}
+
+async fn move_url<T: UrlService>(
+    service: web::Data<T>,
+    identity: Identity,
+    params: web::Path<RedirectParams>,
+    data: web::Json<MoveUrl>,
+    request: HttpRequest,
+    domains: web::Data<Domains>,
+) -> Result<HttpResponse, Error> {
+    let user = current_user(&service, &identity).await?;
+    let scope = |workspace: &Option<String>| match workspace {
+        Some(workspace) => Scope::Workspace(workspace.clone()),
+        None => Scope::User(user.clone()),
+    };
+    let domain = request_domain(&domains, &request);
+    service
+        .move_url(
+            &user,
+            &domain,
+            &params.id,
+            &scope(&data.from),
+            &scope(&data.to),
+        )
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(Health::new(BTreeMap::new()))
+}
+
+pub async fn readyz<T: UrlService>(
+    service: web::Data<T>,
+    template: Option<web::Data<Tera>>,
+) -> HttpResponse {
+    let mut components = BTreeMap::new();
+    let redis = match service.ping().await {
+        Ok(()) => ComponentStatus::Ok,
+        Err(_) => ComponentStatus::Error,
+    };
+    components.insert("redis", redis);
+    let templates = match template {
+        Some(template) if template.templates.contains_key("index.html") => ComponentStatus::Ok,
+        _ => ComponentStatus::Error,
+    };
+    components.insert("templates", templates);
+
+    let health = Health::new(components);
+    match health.status {
+        ComponentStatus::Ok => HttpResponse::Ok().json(health),
+        ComponentStatus::Error => HttpResponse::ServiceUnavailable().json(health),
     }
 }
 
+/// QR images never change for a given link and parameters.
+const QR_CACHE_CONTROL: &str = "public, max-age=86400";
+
+async fn short_url_for_qr<T: UrlService>(
+    service: &web::Data<T>,
+    domain: &str,
+    id: &str,
+    params: &QrParams,
+) -> Result<(String, qr::QrOptions), Error> {
+    let options = params.options().map_err(error::ErrorBadRequest)?;
+    let url = service.lookup(domain, id).await.map_err(|e| match e {
+        UrlError::NotFound => error::ErrorNotFound("Url not found"),
+        e => e.into(),
+    })?;
+    Ok((ResponseUrl::from(url).short_url, options))
+}
+
+pub async fn qr_png<T: UrlService>(
+    service: web::Data<T>,
+    params: web::Path<RedirectParams>,
+    qr_params: web::Query<QrParams>,
+    request: HttpRequest,
+    domains: web::Data<Domains>,
+) -> Result<HttpResponse, Error> {
+    let domain = request_domain(&domains, &request);
+    let (short_url, options) = short_url_for_qr(&service, &domain, &params.id, &qr_params).await?;
+    let image = qr::render_png(&short_url, &options).map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .header(http::header::CACHE_CONTROL, QR_CACHE_CONTROL)
+        .body(image))
+}
+
+pub async fn qr_svg<T: UrlService>(
+    service: web::Data<T>,
+    params: web::Path<RedirectParams>,
+    qr_params: web::Query<QrParams>,
+    request: HttpRequest,
+    domains: web::Data<Domains>,
+) -> Result<HttpResponse, Error> {
+    let domain = request_domain(&domains, &request);
+    let (short_url, options) = short_url_for_qr(&service, &domain, &params.id, &qr_params).await?;
+    let image = qr::render_svg(&short_url, &options).map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type("image/svg+xml")
+        .header(http::header::CACHE_CONTROL, QR_CACHE_CONTROL)
+        .body(image))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::urls::slug_filter::SlugProblem;
+    use crate::urls::url_service::UrlServiceImpl;
+    use actix_identity::{CookieIdentityPolicy, IdentityService};
     use actix_web::http::StatusCode;
     use actix_web::{test, web, App};
     use mockall::predicate::*;
 
+    fn domains() -> web::Data<Domains> {
+        web::Data::new(Domains::new("localhost", &["other.localhost"]))
+    }
+
+    fn identity_service() -> IdentityService<CookieIdentityPolicy> {
+        IdentityService::new(CookieIdentityPolicy::new(&[0; 32]).name("auth"))
+    }
+
+    /// Remembers `user` like a visitor who created links before.
+    async fn login(identity: Identity) -> HttpResponse {
+        identity.remember("user".to_string());
+        HttpResponse::Ok().finish()
+    }
+
+    /// Repository of a Redis that is down.
+    fn failing_repo() -> MockUrlRepo {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_new_user()
+            .return_const(Err(UrlError::Internal));
+        url_repo
+            .expect_generate()
+            .return_const(Err(UrlError::Internal));
+        url_repo
+            .expect_generate_for_user()
+            .return_const(Err(UrlError::Internal));
+        url_repo.expect_get().return_const(Err(UrlError::Internal));
+        url_repo
+            .expect_count_urls_for_user()
+            .return_const(Err(UrlError::Internal));
+        url_repo.expect_get_urls_for_user().return_const(vec![]);
+        url_repo.expect_ping().return_const(Err(UrlError::Internal));
+        url_repo
+            .expect_get_workspaces_for_user()
+            .return_const(Err(UrlError::Internal));
+        url_repo
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_failing_repo() {
+        let url_service = web::Data::new(UrlServiceImpl {
+            url_repo: failing_repo(),
+            metadata_fetcher: None,
+            link_checker: None,
+            events: vec![],
+            count_clicks: true,
+            cache: None,
+        });
+        let mut sut = test::init_service(
+            App::new()
+                .data(Tera::default())
+                .wrap(identity_service())
+                .route("/login", web::post().to(login))
+                .configure(|cfg| configure(url_service, domains(), cfg)),
+        )
+        .await;
+
+        // a first visit needs no user, so it works without Redis
+        let req = test::TestRequest::get().uri("/?page=0").to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.response().cookies().next().is_none());
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["total"], 0);
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(&CreateUrl {
+                url: "http://test.com".to_string(),
+                ..Default::default()
+            })
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let resp = test::call_service(
+            &mut sut,
+            test::TestRequest::post().uri("/login").to_request(),
+        )
+        .await;
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let req = test::TestRequest::post()
+            .uri("/")
+            .cookie(cookie.clone())
+            .set_json(&CreateUrl {
+                url: "http://test.com".to_string(),
+                ..Default::default()
+            })
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let req = test::TestRequest::get()
+            .uri("/?page=0")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = test::TestRequest::get()
+            .uri("/workspaces")
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        for uri in ["/test", "/test/stats", "/test/qr.png"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&mut sut, req).await;
+            assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR, "{}", uri);
+        }
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_index_creates_no_user() {
+        let mut url_service = MockUrlService::new();
+        url_service.expect_new_user().times(0);
+        url_service.expect_get_urls_for_user().times(0);
+        let url_service = web::Data::new(url_service);
+        let mut sut = test::init_service(
+            App::new()
+                .data(Tera::new("templates/**/*").unwrap())
+                .wrap(identity_service())
+                .configure(|cfg| configure(url_service, domains(), cfg)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.response().cookies().next().is_none());
+
+        for uri in [
+            "/workspaces",
+            "/webhooks",
+            "/webhooks/deliveries",
+            "/webhooks/dead_letters",
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&mut sut, req).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", uri);
+            assert!(resp.response().cookies().next().is_none(), "{}", uri);
+            assert_eq!(test::read_body(resp).await, "[]", "{}", uri);
+        }
+        let req = test::TestRequest::get()
+            .uri("/workspaces/team/urls")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.response().cookies().next().is_none());
+    }
+
     #[actix_web::main]
     #[test]
     async fn test_shorten_wrong() {
-        std::env::set_var("DOMAIN", "localhost");
         let url = Url {
             id: "test".to_string(),
             url: "http://test.com".to_string(),
             count: 0,
+            ..Default::default()
         };
 
         let input = CreateUrl {
             url: "test".to_string(),
+            ..Default::default()
         };
 
         let mut url_service = MockUrlService::new();
-        url_service
-            .expect_shorten()
-            .with(eq("test"), eq("user"))
-            .times(0)
-            .return_const(Ok(url));
+        url_service.expect_shorten().times(0).return_const(Ok(url));
         url_service
             .expect_new_user()
             .return_const(Ok("user".to_string()));
         let url_service = web::Data::new(url_service);
 
         let mut sut =
-            test::init_service(App::new().configure(|cfg| configure(url_service, cfg))).await;
+            test::init_service(App::new().configure(|cfg| configure(url_service, domains(), cfg)))
+                .await;
 
         let req = test::TestRequest::post()
             .uri("/")
@@ -122,31 +736,41 @@ mod tests {
     #[actix_web::main]
     #[test]
     async fn test_shorten_correct() {
-        std::env::set_var("DOMAIN", "localhost");
         let url = Url {
             id: "test".to_string(),
             url: "http://test.com".to_string(),
             count: 0,
+            ..Default::default()
         };
 
         let mut url_service = MockUrlService::new();
         url_service
             .expect_shorten()
-            .with(eq("http://test.com"), eq("user"))
+            .with(
+                eq(NewUrl {
+                    url: "http://test.com".to_string(),
+                    domain: "localhost".to_string(),
+                    ..Default::default()
+                }),
+                eq("user"),
+            )
             .times(1)
             .return_const(Ok(url.clone()));
+        url_service.expect_refresh_metadata().return_const(Ok(()));
         url_service
             .expect_new_user()
             .return_const(Ok("user".to_string()));
         let url_service = web::Data::new(url_service);
 
         let mut sut =
-            test::init_service(App::new().configure(|cfg| configure(url_service, cfg))).await;
+            test::init_service(App::new().configure(|cfg| configure(url_service, domains(), cfg)))
+                .await;
 
         let req = test::TestRequest::post()
             .uri("/")
             .set_json(&CreateUrl {
                 url: "http://test.com".to_string(),
+                ..Default::default()
             })
             .to_request();
         let resp = test::call_service(&mut sut, req).await;
@@ -156,22 +780,618 @@ mod tests {
     #[actix_web::main]
     #[test]
     async fn test_redirect() {
-        std::env::set_var("DOMAIN", "localhost");
         let url = Url {
             id: "test".to_string(),
             url: "http://test.com".to_string(),
             count: 0,
+            ..Default::default()
         };
 
         let mut url_service = MockUrlService::new();
-        url_service.expect_get().return_const(Ok(url.clone()));
+        url_service.expect_get().return_const(Ok(Visit {
+            url: url.clone(),
+            variant: None,
+        }));
         let url_service = web::Data::new(url_service);
 
         let mut sut =
-            test::init_service(App::new().configure(|cfg| configure(url_service, cfg))).await;
+            test::init_service(App::new().configure(|cfg| configure(url_service, domains(), cfg)))
+                .await;
 
         let req = test::TestRequest::get().uri("/test").to_request();
         let resp = test::call_service(&mut sut, req).await;
         assert_eq!(resp.status(), StatusCode::FOUND);
+        assert!(resp.response().cookies().next().is_none());
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_stats_hide_owner_fields() {
+        let url = Url {
+            id: "test".to_string(),
+            url: "http://test.com".to_string(),
+            count: 3,
+            title: Some("Launch".to_string()),
+            notes: Some("private".to_string()),
+            tags: vec!["secret".to_string()],
+            ..Default::default()
+        };
+
+        let mut url_service = MockUrlService::new();
+        url_service.expect_lookup().return_const(Ok(url));
+        let url_service = web::Data::new(url_service);
+
+        let mut sut =
+            test::init_service(App::new().configure(|cfg| configure(url_service, domains(), cfg)))
+                .await;
+
+        let req = test::TestRequest::get().uri("/test/stats").to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut sut, req).await;
+        assert_eq!(resp["count"], 3);
+        assert_eq!(resp["long_url"], "http://test.com");
+        assert!(resp.get("notes").is_none());
+        assert!(resp.get("title").is_none());
+        assert!(resp.get("tags").is_none());
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_redirect_sticky_variant() {
+        let url = Url {
+            id: "test".to_string(),
+            url: "http://a.com".to_string(),
+            count: 0,
+            variants: vec![
+                Variant {
+                    url: "http://a.com".to_string(),
+                    weight: 1,
+                    count: 0,
+                },
+                Variant {
+                    url: "http://b.com".to_string(),
+                    weight: 1,
+                    count: 0,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let mut url_service = MockUrlService::new();
+        url_service
+            .expect_get()
+            .with(eq("localhost"), eq("test"), eq(Some(1)))
+            .return_const(Ok(Visit {
+                url: url.clone(),
+                variant: Some(1),
+            }));
+        let url_service = web::Data::new(url_service);
+
+        let mut sut =
+            test::init_service(App::new().configure(|cfg| configure(url_service, domains(), cfg)))
+                .await;
+
+        let req = test::TestRequest::get()
+            .uri("/test")
+            .cookie(Cookie::new(VARIANT_COOKIE, "1"))
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(
+            resp.headers().get(http::header::LOCATION).unwrap(),
+            "http://b.com"
+        );
+        let cookie = resp.response().cookies().next().unwrap();
+        assert_eq!(cookie.value(), "1");
+        assert_eq!(cookie.path(), Some("/test"));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_qr_codes() {
+        let url = Url {
+            id: "test".to_string(),
+            url: "http://test.com".to_string(),
+            count: 0,
+            ..Default::default()
+        };
+
+        let mut url_service = MockUrlService::new();
+        url_service
+            .expect_lookup()
+            .with(eq("localhost"), eq("test"))
+            .return_const(Ok(url));
+        url_service.expect_get().times(0);
+        let url_service = web::Data::new(url_service);
+
+        let mut sut =
+            test::init_service(App::new().configure(|cfg| configure(url_service, domains(), cfg)))
+                .await;
+
+        let req = test::TestRequest::get()
+            .uri("/test/qr.png?size=128&ec=H")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            "image/png"
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/test/qr.svg?color=%23ff8c00&margin=2")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            "image/svg+xml"
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/test/qr.svg?size=1")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_redirect_scoped_by_host() {
+        let url = Url {
+            id: "test".to_string(),
+            url: "http://test.com".to_string(),
+            domain: "other.localhost".to_string(),
+            ..Default::default()
+        };
+
+        let mut url_service = MockUrlService::new();
+        url_service
+            .expect_get()
+            .with(eq("other.localhost"), eq("test"), eq(None))
+            .times(2)
+            .return_const(Ok(Visit { url, variant: None }));
+        url_service
+            .expect_get()
+            .with(eq("localhost"), eq("test"), eq(None))
+            .times(1)
+            .return_const(Err(UrlError::NotFound));
+        let url_service = web::Data::new(url_service);
+
+        let mut sut =
+            test::init_service(App::new().configure(|cfg| configure(url_service, domains(), cfg)))
+                .await;
+
+        let req = test::TestRequest::get()
+            .uri("/test")
+            .header(http::header::HOST, "other.localhost")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+
+        // hosts on a non default port resolve to the same domain
+        let req = test::TestRequest::get()
+            .uri("/test")
+            .header(http::header::HOST, "other.localhost:8080")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+
+        let req = test::TestRequest::get()
+            .uri("/test")
+            .header(http::header::HOST, "unknown.localhost")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_shorten_unknown_domain() {
+        let mut url_service = MockUrlService::new();
+        url_service.expect_shorten().times(0);
+        url_service
+            .expect_new_user()
+            .return_const(Ok("user".to_string()));
+        let url_service = web::Data::new(url_service);
+
+        let mut sut =
+            test::init_service(App::new().configure(|cfg| configure(url_service, domains(), cfg)))
+                .await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(&CreateUrl {
+                url: "http://test.com".to_string(),
+                domain: Some("unknown.localhost".to_string()),
+                ..Default::default()
+            })
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(&CreateUrl {
+                url: "http://test.com".to_string(),
+                alias: Some("no spaces".to_string()),
+                ..Default::default()
+            })
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_shorten_alias_not_allowed() {
+        let mut url_service = MockUrlService::new();
+        url_service
+            .expect_shorten()
+            .return_const(Err(UrlError::AliasNotAllowed(SlugProblem::Reserved)));
+        url_service
+            .expect_new_user()
+            .return_const(Ok("user".to_string()));
+        let url_service = web::Data::new(url_service);
+
+        let mut sut =
+            test::init_service(App::new().configure(|cfg| configure(url_service, domains(), cfg)))
+                .await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(&CreateUrl {
+                url: "http://test.com".to_string(),
+                alias: Some("static".to_string()),
+                ..Default::default()
+            })
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["alias"][0]["message"], "Alias is reserved");
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_workspace_errors() {
+        let mut url_service = MockUrlService::new();
+        url_service
+            .expect_get_urls_for_workspace()
+            .return_const(Err(UrlError::NotFound));
+        url_service
+            .expect_create_invite()
+            .return_const(Err(UrlError::Forbidden));
+        url_service
+            .expect_new_user()
+            .return_const(Ok("user".to_string()));
+        let url_service = web::Data::new(url_service);
+
+        let mut sut = test::init_service(
+            App::new()
+                .wrap(identity_service())
+                .route("/login", web::post().to(login))
+                .configure(|cfg| configure(url_service, domains(), cfg)),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &mut sut,
+            test::TestRequest::post().uri("/login").to_request(),
+        )
+        .await;
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let req = test::TestRequest::get()
+            .uri("/workspaces/team/urls")
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::post()
+            .uri("/workspaces/team/invites")
+            .set_json(&CreateInvite { role: Role::Owner })
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_webhooks() {
+        let webhook = Webhook {
+            id: "hook".to_string(),
+            url: "https://hooks.example.com".to_string(),
+            events: vec![EventKind::LinkClicked],
+            secret: "secret".to_string(),
+        };
+        let mut url_service = MockUrlService::new();
+        url_service
+            .expect_create_webhook()
+            .withf(|user, data| user == "user" && data.events == vec![EventKind::LinkClicked])
+            .times(1)
+            .return_const(Ok(webhook.clone()));
+        url_service
+            .expect_delete_webhook()
+            .with(eq("user"), eq("unknown"))
+            .return_const(Err(UrlError::NotFound));
+        url_service.expect_get_deliveries().return_const(Ok(vec![]));
+        url_service
+            .expect_new_user()
+            .return_const(Ok("user".to_string()));
+        let url_service = web::Data::new(url_service);
+
+        let mut sut =
+            test::init_service(App::new().configure(|cfg| configure(url_service, domains(), cfg)))
+                .await;
+
+        let req = test::TestRequest::post()
+            .uri("/webhooks")
+            .set_json(&CreateWebhook {
+                url: "not a url".to_string(),
+                events: vec![],
+            })
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri("/webhooks")
+            .set_json(&CreateWebhook {
+                url: "https://hooks.example.com".to_string(),
+                events: vec![EventKind::LinkClicked],
+            })
+            .to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut sut, req).await;
+        assert_eq!(resp["id"], "hook");
+        assert_eq!(resp["events"][0], "link.clicked");
+        assert_eq!(resp["secret"], "secret");
+
+        let req = test::TestRequest::delete()
+            .uri("/webhooks/unknown")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // not taken for a webhook id
+        let req = test::TestRequest::get()
+            .uri("/webhooks/deliveries")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_health_endpoints() {
+        let mut url_service = MockUrlService::new();
+        url_service.expect_get().times(0);
+        url_service.expect_ping().times(1).return_const(Ok(()));
+        let url_service = web::Data::new(url_service);
+
+        let mut sut =
+            test::init_service(App::new().configure(|cfg| configure(url_service, domains(), cfg)))
+                .await;
+
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let resp: serde_json::Value = test::read_response_json(&mut sut, req).await;
+        assert_eq!(resp["status"], "ok");
+
+        // templates are not registered in this app
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], "error");
+        assert_eq!(body["components"]["redis"], "ok");
+        assert_eq!(body["components"]["templates"], "error");
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_list_with_query() {
+        let mut url_service = MockUrlService::new();
+        url_service
+            .expect_get_urls_for_user()
+            .with(
+                eq("user"),
+                eq(1),
+                eq(UrlQuery {
+                    tag: Some("work".to_string()),
+                    broken: Some(true),
+                    sort: Sort::MostClicked,
+                    ..Default::default()
+                }),
+            )
+            .times(1)
+            .return_const(Paginated::empty());
+        url_service.expect_new_user().times(0);
+        let url_service = web::Data::new(url_service);
+
+        // the json listing doesn't render, but the handler takes the templates
+        let mut sut = test::init_service(
+            App::new()
+                .data(Tera::default())
+                .wrap(identity_service())
+                .route("/login", web::post().to(login))
+                .configure(|cfg| configure(url_service, domains(), cfg)),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &mut sut,
+            test::TestRequest::post().uri("/login").to_request(),
+        )
+        .await;
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let req = test::TestRequest::get()
+            .uri("/?page=1&tag=work&q=&broken=true&sort=most_clicked")
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_update_url() {
+        let url = Url {
+            id: "test".to_string(),
+            url: "http://test.com".to_string(),
+            title: Some("Test".to_string()),
+            ..Default::default()
+        };
+
+        let mut url_service = MockUrlService::new();
+        url_service
+            .expect_update_url()
+            .with(
+                eq("user"),
+                eq("localhost"),
+                eq("test"),
+                eq(UpdateUrl {
+                    title: Some("Test".to_string()),
+                    ..Default::default()
+                }),
+            )
+            .times(1)
+            .return_const(Ok(url));
+        url_service
+            .expect_new_user()
+            .return_const(Ok("user".to_string()));
+        let url_service = web::Data::new(url_service);
+
+        let mut sut =
+            test::init_service(App::new().configure(|cfg| configure(url_service, domains(), cfg)))
+                .await;
+
+        let req = test::TestRequest::patch()
+            .uri("/test")
+            .set_json(&UpdateUrl {
+                tags: Some(vec!["not a tag".to_string()]),
+                ..Default::default()
+            })
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::patch()
+            .uri("/test")
+            .set_json(&UpdateUrl {
+                title: Some("Test".to_string()),
+                ..Default::default()
+            })
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["title"], "Test");
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_delete_url() {
+        let mut url_service = MockUrlService::new();
+        url_service
+            .expect_delete_url()
+            .with(eq("user"), eq("localhost"), eq("test"))
+            .times(1)
+            .return_const(Ok(()));
+        url_service
+            .expect_delete_url()
+            .return_const(Err(UrlError::NotFound));
+        url_service.expect_new_user().times(0);
+        let url_service = web::Data::new(url_service);
+
+        let mut sut = test::init_service(
+            App::new()
+                .wrap(identity_service())
+                .route("/login", web::post().to(login))
+                .configure(|cfg| configure(url_service, domains(), cfg)),
+        )
+        .await;
+
+        // without a session nothing is deleted and no user is created
+        let req = test::TestRequest::delete().uri("/test").to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = test::call_service(
+            &mut sut,
+            test::TestRequest::post().uri("/login").to_request(),
+        )
+        .await;
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let req = test::TestRequest::delete()
+            .uri("/test")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let req = test::TestRequest::delete()
+            .uri("/other")
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_preview_card_for_crawlers() {
+        let url = Url {
+            id: "test".to_string(),
+            url: "http://test.com".to_string(),
+            domain: "localhost".to_string(),
+            metadata: Some(Metadata {
+                title: Some("Destination title".to_string()),
+                image: Some("http://test.com/card.png".to_string()),
+                ..Default::default()
+            }),
+            preview: Some(Preview {
+                title: Some("Our <title>".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let mut url_service = MockUrlService::new();
+        url_service.expect_lookup().return_const(Ok(url.clone()));
+        url_service
+            .expect_get()
+            .times(1)
+            .return_const(Ok(Visit { url, variant: None }));
+        let url_service = web::Data::new(url_service);
+
+        let mut sut = test::init_service(
+            App::new()
+                .data(Tera::new("templates/**/*").unwrap())
+                .configure(|cfg| configure(url_service, domains(), cfg)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/test")
+            .header(
+                http::header::USER_AGENT,
+                "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)",
+            )
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains(r#"<meta property="og:title" content="Our &lt;title&gt;">"#));
+        assert!(body.contains("card.png"));
+
+        let req = test::TestRequest::get()
+            .uri("/test")
+            .header(http::header::USER_AGENT, "Mozilla/5.0")
+            .to_request();
+        let resp = test::call_service(&mut sut, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(
+            resp.headers().get(http::header::VARY).unwrap(),
+            "User-Agent"
+        );
     }
 }
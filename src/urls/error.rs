@@ -1,12 +1,43 @@
+use super::slug_filter::SlugProblem;
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
 use std::{error, fmt};
 
-#[derive(Debug, Clone)]
-pub struct UrlError;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UrlError {
+    Internal,
+    AliasTaken,
+    AliasNotAllowed(SlugProblem),
+    NotFound,
+    Forbidden,
+}
 
 impl fmt::Display for UrlError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Url Error")
+        match self {
+            UrlError::Internal => write!(f, "Url Error"),
+            UrlError::AliasTaken => write!(f, "Alias is already taken"),
+            UrlError::AliasNotAllowed(SlugProblem::Reserved) => write!(f, "Alias is reserved"),
+            UrlError::AliasNotAllowed(SlugProblem::Denied) => {
+                write!(f, "Alias contains a word that is not allowed")
+            }
+            UrlError::NotFound => write!(f, "Not found"),
+            UrlError::Forbidden => write!(f, "Not allowed"),
+        }
     }
 }
 
 impl error::Error for UrlError {}
+
+/// Lets handlers return repository errors with `?`, the body is the message.
+impl ResponseError for UrlError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UrlError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            UrlError::AliasTaken => StatusCode::CONFLICT,
+            UrlError::AliasNotAllowed(_) => StatusCode::BAD_REQUEST,
+            UrlError::NotFound => StatusCode::NOT_FOUND,
+            UrlError::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+}
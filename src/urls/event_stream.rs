@@ -0,0 +1,190 @@
+use super::types::{Event, EventKind, EventLink};
+use super::webhooks::{EventSink, Flush};
+use crate::metrics::{self, observe_redis};
+use crate::redis::{Connection, RedisClient};
+use crate::settings::Settings;
+use actix::prelude::*;
+use futures::future::LocalBoxFuture;
+use redis::streams::StreamMaxlen;
+use redis::{AsyncCommands, RedisError, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Stream every link creation and click is appended to, within the namespace.
+pub const STREAM_KEY: &str = "events";
+
+/// Flat fields of a stream entry, optional values are left out.
+pub fn entry_fields(event: &Event) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("event", event.event.as_str().to_string()),
+        ("created_at", event.created_at.to_string()),
+        ("domain", event.link.domain.clone()),
+        ("id", event.link.id.clone()),
+        ("url", event.link.url.clone()),
+        ("short_url", event.link.short_url.clone()),
+    ];
+    if let Some(owner) = &event.owner {
+        fields.push(("owner", owner.clone()));
+    }
+    if let Some(variant) = event.variant {
+        fields.push(("variant", variant.to_string()));
+    }
+    fields
+}
+
+/// Reads an entry written by `entry_fields`, `None` for malformed entries.
+pub fn parse_entry(fields: &HashMap<String, Value>) -> Option<Event> {
+    let field = |name: &str| -> Option<String> {
+        fields
+            .get(name)
+            .and_then(|value| redis::from_redis_value(value).ok())
+    };
+    Some(Event {
+        event: EventKind::parse(&field("event")?)?,
+        owner: field("owner"),
+        created_at: field("created_at")?.parse().ok()?,
+        link: EventLink {
+            domain: field("domain")?,
+            id: field("id")?,
+            url: field("url")?,
+            short_url: field("short_url")?,
+        },
+        variant: match field("variant") {
+            Some(variant) => Some(variant.parse().ok()?),
+            None => None,
+        },
+    })
+}
+
+/// Appends events to the stream one at a time, in the order they were published.
+pub struct StreamPublisher {
+    redis_client: Arc<RedisClient>,
+    stream_key: String,
+    /// Opened on the first event and again after a failure.
+    connection: Option<Connection>,
+    max_length: usize,
+}
+
+impl Actor for StreamPublisher {
+    type Context = Context<Self>;
+}
+
+impl Handler<Event> for StreamPublisher {
+    type Result = ();
+
+    fn handle(&mut self, event: Event, ctx: &mut Context<Self>) {
+        let redis_client = self.redis_client.clone();
+        let stream_key = self.stream_key.clone();
+        let connection = self.connection.take();
+        let max_length = StreamMaxlen::Approx(self.max_length);
+        let publish = async move {
+            let mut connection = match connection {
+                Some(connection) => connection,
+                None => observe_redis("connect", redis_client.get_async_connection()).await?,
+            };
+            let _: String = observe_redis(
+                "xadd",
+                connection.xadd_maxlen(stream_key, max_length, "*", &entry_fields(&event)),
+            )
+            .await?;
+            Ok::<_, RedisError>(connection)
+        };
+        ctx.wait(publish.into_actor(self).map(|result, act, _| match result {
+            Ok(connection) => {
+                metrics::STREAM_EVENTS
+                    .with_label_values(&["published"])
+                    .inc();
+                act.connection = Some(connection);
+            }
+            Err(_) => metrics::STREAM_EVENTS.with_label_values(&["dropped"]).inc(),
+        }));
+    }
+}
+
+// events are appended with `ctx.wait`, so a flush is answered after all of them
+impl Handler<Flush> for StreamPublisher {
+    type Result = ();
+
+    fn handle(&mut self, _: Flush, _: &mut Context<Self>) {}
+}
+
+impl EventSink for Addr<StreamPublisher> {
+    fn publish(&self, event: Event) {
+        self.do_send(event);
+    }
+
+    fn flush(&self) -> LocalBoxFuture<'static, ()> {
+        let flushed = self.send(Flush);
+        Box::pin(async move {
+            let _ = flushed.await;
+        })
+    }
+}
+
+/// Starts the publisher on the current arbiter, `None` when the stream is disabled.
+pub fn start(
+    settings: &Settings,
+    redis_client: Arc<RedisClient>,
+) -> Option<Arc<dyn EventSink + Send + Sync>> {
+    if settings.event_stream_max_length == 0 {
+        return None;
+    }
+    let publisher = StreamPublisher {
+        redis_client,
+        stream_key: crate::redis::namespace(settings).key(STREAM_KEY),
+        connection: None,
+        max_length: settings.event_stream_max_length,
+    };
+    Some(Arc::new(publisher.start()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::urls::types::Url;
+
+    fn stored(fields: Vec<(&'static str, String)>) -> HashMap<String, Value> {
+        fields
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), Value::Data(value.into_bytes())))
+            .collect()
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let url = Url {
+            id: "test".to_string(),
+            url: "https://example.com".to_string(),
+            domain: "localhost".to_string(),
+            owner: Some("user".to_string()),
+            ..Default::default()
+        };
+        let clicked = Event::new(EventKind::LinkClicked, &url, Some(1));
+        assert_eq!(parse_entry(&stored(entry_fields(&clicked))), Some(clicked));
+
+        let url = Url { owner: None, ..url };
+        let created = Event::new(EventKind::LinkCreated, &url, None);
+        let fields = entry_fields(&created);
+        assert!(fields
+            .iter()
+            .all(|(name, _)| *name != "owner" && *name != "variant"));
+        assert_eq!(parse_entry(&stored(fields)), Some(created));
+    }
+
+    #[test]
+    fn test_parse_malformed_entry() {
+        let mut fields = stored(vec![
+            ("event", "link.archived".to_string()),
+            ("created_at", "1".to_string()),
+            ("domain", "localhost".to_string()),
+            ("id", "test".to_string()),
+            ("url", "https://example.com".to_string()),
+            ("short_url", "http://localhost/test".to_string()),
+        ]);
+        assert_eq!(parse_entry(&fields), None);
+        fields.insert("event".to_string(), Value::Data(b"link.clicked".to_vec()));
+        assert!(parse_entry(&fields).is_some());
+        fields.insert("variant".to_string(), Value::Data(b"first".to_vec()));
+        assert_eq!(parse_entry(&fields), None);
+    }
+}
@@ -0,0 +1,58 @@
+use super::types::UrlService;
+use actix::prelude::*;
+use actix_web::web;
+use std::time::Duration;
+
+/// How often the scheduler looks for expired links.
+const TICK: Duration = Duration::from_secs(60);
+/// Links expired per batch at most.
+const BATCH_SIZE: usize = 100;
+
+/// Sends `link.expired` for links whose expiry passed, batch after batch until
+/// none are left.
+pub struct ExpiryScheduler<T: UrlService + 'static> {
+    url_service: web::Data<T>,
+    running: bool,
+}
+
+impl<T: UrlService + 'static> ExpiryScheduler<T> {
+    fn tick(&mut self, ctx: &mut Context<Self>) {
+        // a slow sweep is not overlapped by the next one
+        if self.running {
+            return;
+        }
+        self.running = true;
+        let url_service = self.url_service.clone();
+        let run = async move {
+            loop {
+                match url_service.expire_links(BATCH_SIZE).await {
+                    Ok(expired) if expired.len() < BATCH_SIZE => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::warn!(error = %e, "expired links were not processed");
+                        break;
+                    }
+                }
+            }
+        };
+        ctx.spawn(run.into_actor(self).map(|_, act, _| act.running = false));
+    }
+}
+
+impl<T: UrlService + 'static> Actor for ExpiryScheduler<T> {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.tick(ctx);
+        ctx.run_interval(TICK, |act, ctx| act.tick(ctx));
+    }
+}
+
+/// Starts the scheduler on the current arbiter.
+pub fn start<T: UrlService + 'static>(url_service: web::Data<T>) {
+    ExpiryScheduler {
+        url_service,
+        running: false,
+    }
+    .start();
+}
@@ -0,0 +1,259 @@
+use crate::hashids::Hashids;
+use crate::metrics::observe_redis;
+use crate::redis::RedisClient;
+use crate::settings::{IdStrategy, Settings};
+use crate::urls::error::UrlError;
+use async_trait::async_trait;
+use rand::distributions::Alphanumeric;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use redis::AsyncCommands;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the counter within the namespace.
+const URL_COUNTER_KEY: &str = "url_counter";
+
+/// Digits in ASCII order, so equally long ids sort like the numbers they encode.
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+/// `62^11 > 2^64`, snowflake ids are padded to this length.
+const SNOWFLAKE_LENGTH: usize = 11;
+/// 2020-01-01T00:00:00Z in milliseconds.
+const SNOWFLAKE_EPOCH: u64 = 1_577_836_800_000;
+const NODE_BITS: u64 = 10;
+const SEQUENCE_BITS: u64 = 12;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+const ADJECTIVES: &[&str] = &[
+    "able", "bold", "brave", "bright", "brisk", "calm", "clever", "cool", "cosy", "crisp", "curly",
+    "daring", "eager", "early", "easy", "fair", "fancy", "fast", "fine", "fluffy", "fresh",
+    "friendly", "funny", "gentle", "giant", "glad", "golden", "good", "grand", "green", "happy",
+    "hidden", "honest", "humble", "jolly", "keen", "kind", "large", "lively", "lucky", "mellow",
+    "merry", "mighty", "modest", "neat", "nimble", "noble", "odd", "polite", "proud", "quick",
+    "quiet", "rapid", "rare", "rosy", "round", "shiny", "silent", "silver", "simple", "sleek",
+    "small", "smart", "snowy", "soft", "solid", "sparkly", "spicy", "steady", "sunny", "super",
+    "swift", "tall", "tidy", "tiny", "vivid", "warm", "wild", "wise", "witty", "young", "zesty",
+];
+
+const NOUNS: &[&str] = &[
+    "apple", "badger", "banana", "beaver", "bison", "breeze", "brook", "bunny", "canyon", "cactus",
+    "cloud", "comet", "coral", "cricket", "daisy", "dolphin", "dragon", "eagle", "falcon",
+    "feather", "fern", "forest", "fox", "galaxy", "garden", "gecko", "glacier", "goose", "harbor",
+    "hawk", "hedgehog", "heron", "island", "jaguar", "koala", "lagoon", "lemon", "lemur", "lion",
+    "lizard", "llama", "maple", "meadow", "moon", "moose", "mountain", "nebula", "ocean", "orchid",
+    "otter", "owl", "panda", "parrot", "peach", "pebble", "penguin", "pepper", "pine", "planet",
+    "pony", "puffin", "rabbit", "raven", "river", "robin", "rocket", "salmon", "shark", "sparrow",
+    "spruce", "squid", "star", "sun", "tiger", "tulip", "turtle", "valley", "walrus", "whale",
+    "willow", "wolf", "zebra",
+];
+
+/// Source of candidate ids for new links. Ids may collide with existing links,
+/// the repository detects that and asks for another one.
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait IdGenerator {
+    async fn next_id(&self) -> Result<String, UrlError>;
+}
+
+/// Global `INCR` counter encoded with hashids, the original strategy.
+pub struct HashidsGenerator {
+    pub redis_client: Arc<RedisClient>,
+    pub counter_key: String,
+    pub hashids: Hashids,
+}
+
+#[async_trait]
+impl IdGenerator for HashidsGenerator {
+    async fn next_id(&self) -> Result<String, UrlError> {
+        let mut conn = observe_redis("connect", self.redis_client.get_async_connection()).await?;
+        observe_redis("incr", conn.incr(&self.counter_key, 1))
+            .await
+            .map(|result| self.hashids.encode(result))
+            .map_err(|_| UrlError::Internal)
+    }
+}
+
+/// Random base62 ids, unguessable and free of any shared state.
+pub struct RandomGenerator {
+    pub length: usize,
+}
+
+#[async_trait]
+impl IdGenerator for RandomGenerator {
+    async fn next_id(&self) -> Result<String, UrlError> {
+        Ok(rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(self.length)
+            .map(char::from)
+            .collect())
+    }
+}
+
+/// Time ordered ids: milliseconds since `SNOWFLAKE_EPOCH`, node id and a
+/// per-millisecond sequence, so nodes never hand out the same id.
+pub struct SnowflakeGenerator {
+    node_id: u64,
+    /// Last used millisecond and sequence within it.
+    state: Mutex<(u64, u64)>,
+}
+
+impl SnowflakeGenerator {
+    pub fn new(node_id: u16) -> SnowflakeGenerator {
+        SnowflakeGenerator {
+            node_id: u64::from(node_id) & ((1 << NODE_BITS) - 1),
+            state: Mutex::new((0, 0)),
+        }
+    }
+
+    fn now() -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0))
+            .as_millis() as u64;
+        now.saturating_sub(SNOWFLAKE_EPOCH)
+    }
+
+    /// Next value, or how long to wait once the sequence of the millisecond is used up.
+    fn next_value(&self) -> Result<u64, Duration> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (last, sequence) = *state;
+        let now = Self::now();
+        let sequence = if now > last {
+            0
+        } else if sequence < MAX_SEQUENCE {
+            // a clock going backwards keeps using the last millisecond
+            sequence + 1
+        } else {
+            return Err(Duration::from_millis(last - now + 1));
+        };
+        let now = std::cmp::max(now, last);
+        *state = (now, sequence);
+        Ok(now << (NODE_BITS + SEQUENCE_BITS) | self.node_id << SEQUENCE_BITS | sequence)
+    }
+}
+
+#[async_trait]
+impl IdGenerator for SnowflakeGenerator {
+    async fn next_id(&self) -> Result<String, UrlError> {
+        loop {
+            match self.next_value() {
+                Ok(value) => return Ok(encode_base62(value, SNOWFLAKE_LENGTH)),
+                // sleeps without the lock, other requests of the worker keep running
+                Err(wait) => actix_rt::time::delay_for(wait).await,
+            }
+        }
+    }
+}
+
+/// Human readable `adjective-noun-number` slugs, e.g. `brave-otter-42`.
+pub struct WordsGenerator;
+
+#[async_trait]
+impl IdGenerator for WordsGenerator {
+    async fn next_id(&self) -> Result<String, UrlError> {
+        let mut rng = rand::thread_rng();
+        let adjective = ADJECTIVES.choose(&mut rng).ok_or(UrlError::Internal)?;
+        let noun = NOUNS.choose(&mut rng).ok_or(UrlError::Internal)?;
+        Ok(format!("{}-{}-{}", adjective, noun, rng.gen_range(0..100)))
+    }
+}
+
+fn encode_base62(mut value: u64, length: usize) -> String {
+    let mut digits = vec![BASE62[0]; length];
+    for digit in digits.iter_mut().rev() {
+        *digit = BASE62[(value % 62) as usize];
+        value /= 62;
+    }
+    String::from_utf8(digits).unwrap_or_default()
+}
+
+pub async fn configure(
+    settings: &Settings,
+    redis_client: Arc<RedisClient>,
+    hashids: Hashids,
+) -> Arc<dyn IdGenerator + Send + Sync> {
+    match settings.id_strategy {
+        IdStrategy::Hashids => Arc::new(HashidsGenerator {
+            redis_client,
+            counter_key: crate::redis::namespace(settings).key(URL_COUNTER_KEY),
+            hashids,
+        }),
+        IdStrategy::Random => Arc::new(RandomGenerator {
+            length: settings.id_length,
+        }),
+        IdStrategy::Snowflake => Arc::new(SnowflakeGenerator::new(settings.node_id)),
+        IdStrategy::Words => Arc::new(WordsGenerator),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::urls::types::CreateUrl;
+    use validator::Validate;
+
+    /// Generated ids must pass the same validation as custom aliases.
+    fn assert_valid_alias(id: &str) {
+        let url = CreateUrl {
+            url: "http://test.com".to_string(),
+            alias: Some(id.to_string()),
+            ..Default::default()
+        };
+        assert!(url.validate().is_ok(), "{} is not a valid alias", id);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_random() {
+        let sut = RandomGenerator { length: 12 };
+        let id_1 = sut.next_id().await.unwrap();
+        let id_2 = sut.next_id().await.unwrap();
+        assert_eq!(id_1.len(), 12);
+        assert_ne!(id_1, id_2);
+        assert_valid_alias(&id_1);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_snowflake() {
+        let sut = SnowflakeGenerator::new(5);
+        let mut ids = vec![];
+        for _ in 0..5000 {
+            ids.push(sut.next_id().await.unwrap());
+        }
+        assert!(ids.iter().all(|id| id.len() == SNOWFLAKE_LENGTH));
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_valid_alias(&ids[0]);
+
+        let other = SnowflakeGenerator::new(6);
+        assert_eq!(other.next_value().unwrap() >> SEQUENCE_BITS & 1023, 6);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_snowflake_exhausted_sequence() {
+        let sut = SnowflakeGenerator::new(5);
+        let last = SnowflakeGenerator::now() + 20;
+        *sut.state.lock().unwrap() = (last, MAX_SEQUENCE);
+        assert!(sut.next_value().unwrap_err() >= Duration::from_millis(1));
+
+        let id = sut.next_id().await.unwrap();
+        assert!(id > encode_base62(last << (NODE_BITS + SEQUENCE_BITS), SNOWFLAKE_LENGTH));
+        assert!(SnowflakeGenerator::now() > last);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_words() {
+        let id = WordsGenerator.next_id().await.unwrap();
+        assert_eq!(id.split('-').count(), 3);
+        assert_valid_alias(&id);
+    }
+
+    #[test]
+    fn test_encode_base62() {
+        assert_eq!(encode_base62(0, 3), "000");
+        assert_eq!(encode_base62(61, 3), "00z");
+        assert_eq!(encode_base62(62, 3), "010");
+    }
+}
@@ -0,0 +1,334 @@
+use super::metadata::{public_client, MetadataError, MAX_REDIRECTS};
+use super::types::{LinkHealth, Url, UrlService};
+use super::utils::{unix_time, BuildUrl};
+use crate::settings::Settings;
+use actix::prelude::*;
+use actix_web::error::BlockingError;
+use actix_web::web;
+use async_trait::async_trait;
+use reqwest::blocking::Client;
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often the scheduler looks for due links.
+const TICK: Duration = Duration::from_secs(60);
+/// Links checked per tick at most.
+const BATCH_SIZE: usize = 100;
+
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait LinkChecker {
+    async fn check(&self, url: &str) -> LinkHealth;
+}
+
+/// Requests destinations with HEAD, falling back to GET for servers that don't
+/// support it, and follows redirects with the same address checks as metadata fetches.
+#[derive(Debug, Clone)]
+pub struct HttpLinkChecker {
+    /// For the whole check, redirects included.
+    timeout: Duration,
+    /// Only for tests against local servers.
+    allow_private: bool,
+}
+
+impl HttpLinkChecker {
+    pub fn new(timeout: Duration) -> HttpLinkChecker {
+        HttpLinkChecker {
+            timeout,
+            allow_private: false,
+        }
+    }
+
+    fn check_blocking(&self, url: &str) -> LinkHealth {
+        let mut health = LinkHealth {
+            checked_at: unix_time(),
+            ..Default::default()
+        };
+        match self.follow(url, &mut health.redirects) {
+            Ok(status) => {
+                health.status = Some(status);
+                health.broken = LinkHealth::is_broken_status(status);
+            }
+            Err(e) => {
+                // internal destinations are skipped, not broken
+                health.broken = !matches!(e, MetadataError::Blocked | MetadataError::InvalidUrl);
+                health.error = Some(e.to_string());
+            }
+        }
+        health
+    }
+
+    /// Status of the final response, every redirect target is pushed to `redirects`.
+    fn follow(&self, url: &str, redirects: &mut Vec<String>) -> Result<u16, MetadataError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut url = reqwest::Url::parse(url).map_err(|_| MetadataError::InvalidUrl)?;
+        for _ in 0..=MAX_REDIRECTS {
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .ok_or(MetadataError::Timeout)?;
+            let client = public_client(&url, remaining, self.allow_private)?;
+            let mut response = client.head(url.clone()).send()?;
+            if matches!(response.status().as_u16(), 405 | 501) {
+                response = client.get(url.clone()).send()?;
+            }
+            let status = response.status();
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok());
+            match location {
+                Some(location) if status.is_redirection() => {
+                    url = url.join(location).map_err(|_| MetadataError::InvalidUrl)?;
+                    redirects.push(url.to_string());
+                }
+                _ => return Ok(status.as_u16()),
+            }
+        }
+        Err(MetadataError::TooManyRedirects)
+    }
+}
+
+#[async_trait]
+impl LinkChecker for HttpLinkChecker {
+    #[tracing::instrument(skip(self))]
+    async fn check(&self, url: &str) -> LinkHealth {
+        let checker = self.clone();
+        let url = url.to_string();
+        match web::block(move || Ok::<_, ()>(checker.check_blocking(&url))).await {
+            Ok(health) => health,
+            Err(_) => LinkHealth {
+                error: Some("canceled".to_string()),
+                checked_at: unix_time(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BrokenLinkEvent<'a> {
+    event: &'static str,
+    domain: &'a str,
+    id: &'a str,
+    short_url: String,
+    url: &'a str,
+    health: &'a LinkHealth,
+}
+
+/// Operator hook posting every link of the deployment that breaks to one url.
+/// Owners are notified through the `link.broken` event of their own webhooks.
+#[derive(Debug, Clone)]
+pub struct WebhookNotifier {
+    webhook: String,
+    timeout: Duration,
+}
+
+impl WebhookNotifier {
+    pub fn new(webhook: String, timeout: Duration) -> WebhookNotifier {
+        WebhookNotifier { webhook, timeout }
+    }
+
+    fn notify_blocking(&self, body: String) -> Result<(), MetadataError> {
+        let response = Client::builder()
+            .timeout(self.timeout)
+            .build()?
+            .post(&self.webhook)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()?;
+        if !response.status().is_success() {
+            return Err(MetadataError::Status(response.status().as_u16()));
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, url), fields(domain = %url.domain, id = %url.id))]
+    pub async fn notify(&self, url: &Url) {
+        let health = match &url.health {
+            Some(health) => health,
+            None => return,
+        };
+        let event = BrokenLinkEvent {
+            event: "link.broken",
+            domain: &url.domain,
+            id: &url.id,
+            short_url: url.build_url(),
+            url: &url.url,
+            health,
+        };
+        let body = match serde_json::to_string(&event) {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        let notifier = self.clone();
+        if let Err(e) = web::block(move || notifier.notify_blocking(body)).await {
+            let e = match e {
+                BlockingError::Error(e) => e,
+                BlockingError::Canceled => MetadataError::Request("canceled".to_string()),
+            };
+            tracing::warn!(error = %e, "broken link webhook failed");
+        }
+    }
+}
+
+/// Checks due links every minute, one batch at a time.
+pub struct LinkCheckScheduler<T: UrlService + 'static> {
+    url_service: web::Data<T>,
+    notifier: Option<WebhookNotifier>,
+    concurrency: usize,
+    /// Seconds between checks of the same link.
+    interval: u64,
+    running: bool,
+}
+
+impl<T: UrlService + 'static> LinkCheckScheduler<T> {
+    fn tick(&mut self, ctx: &mut Context<Self>) {
+        // a slow batch is not overlapped by the next one
+        if self.running {
+            return;
+        }
+        self.running = true;
+        let url_service = self.url_service.clone();
+        let notifier = self.notifier.clone();
+        let (concurrency, interval) = (self.concurrency, self.interval);
+        let run = async move {
+            match url_service
+                .check_links(BATCH_SIZE, concurrency, interval)
+                .await
+            {
+                Ok(broken) => {
+                    if let Some(notifier) = notifier {
+                        for url in broken.iter() {
+                            notifier.notify(url).await;
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!(error = %e, "links were not checked"),
+            }
+        };
+        ctx.spawn(run.into_actor(self).map(|_, act, _| act.running = false));
+    }
+}
+
+impl<T: UrlService + 'static> Actor for LinkCheckScheduler<T> {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.tick(ctx);
+        ctx.run_interval(TICK, |act, ctx| act.tick(ctx));
+    }
+}
+
+/// Checker used by the url service, `None` when link checking is disabled.
+pub async fn configure(settings: &Settings) -> Option<Arc<dyn LinkChecker + Send + Sync>> {
+    if settings.link_check_interval == 0 {
+        return None;
+    }
+    Some(Arc::new(HttpLinkChecker::new(Duration::from_secs(
+        settings.metadata_timeout,
+    ))))
+}
+
+/// Starts the scheduler on the current arbiter when link checking is enabled.
+pub fn start<T: UrlService + 'static>(settings: &Settings, url_service: web::Data<T>) {
+    if settings.link_check_interval == 0 {
+        return;
+    }
+    let notifier = settings.link_check_webhook.clone().map(|webhook| {
+        WebhookNotifier::new(webhook, Duration::from_secs(settings.metadata_timeout))
+    });
+    LinkCheckScheduler {
+        url_service,
+        notifier,
+        concurrency: settings.link_check_concurrency,
+        interval: settings.link_check_interval,
+        running: false,
+    }
+    .start();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serves canned responses on a local port, one per connection.
+    fn stub_server(responses: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for (response, stream) in responses.into_iter().zip(listener.incoming()) {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        address
+    }
+
+    fn response(status: &str, headers: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            status, headers
+        )
+    }
+
+    fn local_checker() -> HttpLinkChecker {
+        HttpLinkChecker {
+            allow_private: true,
+            ..HttpLinkChecker::new(Duration::from_secs(2))
+        }
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_check_follows_redirects() {
+        let target = stub_server(vec![
+            response("405 Method Not Allowed", ""),
+            response("200 OK", ""),
+        ]);
+        let address = stub_server(vec![response(
+            "301 Moved Permanently",
+            &format!("Location: {}/moved\r\n", target),
+        )]);
+        let health = local_checker().check(&format!("{}/", address)).await;
+        assert_eq!(health.status, Some(200));
+        assert_eq!(health.redirects, vec![format!("{}/moved", target)]);
+        assert_eq!(health.error, None);
+        assert!(!health.broken);
+        assert!(health.checked_at > 0);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_check_broken() {
+        let address = stub_server(vec![response("404 Not Found", "")]);
+        let health = local_checker().check(&address).await;
+        assert_eq!(health.status, Some(404));
+        assert!(health.broken);
+
+        let address = stub_server(vec![response("403 Forbidden", "")]);
+        assert!(!local_checker().check(&address).await.broken);
+
+        // nothing listens on the port anymore
+        let address = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let health = local_checker().check(&address).await;
+        assert_eq!(health.status, None);
+        assert!(health.error.is_some());
+        assert!(health.broken);
+
+        let health = HttpLinkChecker::new(Duration::from_secs(2))
+            .check("http://127.0.0.1/")
+            .await;
+        assert_eq!(health.error, Some(MetadataError::Blocked.to_string()));
+        assert!(!health.broken);
+    }
+}
@@ -0,0 +1,585 @@
+use super::types::Metadata;
+use crate::settings::Settings;
+use actix_web::error::BlockingError;
+use actix_web::web;
+use async_trait::async_trait;
+use reqwest::blocking::Client;
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{error, fmt};
+
+/// Redirects followed before giving up, each hop is checked like the first request.
+pub const MAX_REDIRECTS: usize = 5;
+const USER_AGENT: &str = concat!("url_shortener/", env!("CARGO_PKG_VERSION"));
+const MAX_TEXT_LENGTH: usize = 300;
+const MAX_URL_LENGTH: usize = 2048;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataError {
+    InvalidUrl,
+    /// The destination resolves to a loopback, private or otherwise internal address.
+    Blocked,
+    Timeout,
+    TooLarge,
+    TooManyRedirects,
+    NotHtml,
+    Status(u16),
+    Request(String),
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetadataError::InvalidUrl => write!(f, "Not an http(s) url"),
+            MetadataError::Blocked => write!(f, "Destination address is not public"),
+            MetadataError::Timeout => write!(f, "Destination did not respond in time"),
+            MetadataError::TooLarge => write!(f, "Destination page is too large"),
+            MetadataError::TooManyRedirects => write!(f, "Too many redirects"),
+            MetadataError::NotHtml => write!(f, "Destination is not an html page"),
+            MetadataError::Status(status) => write!(f, "Destination responded with {}", status),
+            MetadataError::Request(e) => write!(f, "Request failed: {}", e),
+        }
+    }
+}
+
+impl error::Error for MetadataError {}
+
+impl From<reqwest::Error> for MetadataError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            MetadataError::Timeout
+        } else {
+            MetadataError::Request(e.to_string())
+        }
+    }
+}
+
+impl From<io::Error> for MetadataError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => MetadataError::Timeout,
+            _ => MetadataError::Request(e.to_string()),
+        }
+    }
+}
+
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait MetadataFetcher {
+    async fn fetch(&self, url: &str) -> Result<Metadata, MetadataError>;
+}
+
+/// Reads the `<head>` of destination pages over HTTP.
+#[derive(Debug, Clone)]
+pub struct HttpMetadataFetcher {
+    /// For the whole fetch, redirects included.
+    timeout: Duration,
+    max_bytes: usize,
+    /// Only for tests against local servers.
+    allow_private: bool,
+}
+
+impl HttpMetadataFetcher {
+    pub fn new(timeout: Duration, max_bytes: usize) -> HttpMetadataFetcher {
+        HttpMetadataFetcher {
+            timeout,
+            max_bytes,
+            allow_private: false,
+        }
+    }
+
+    fn fetch_blocking(&self, url: &str) -> Result<Metadata, MetadataError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut url = Url::parse(url).map_err(|_| MetadataError::InvalidUrl)?;
+        for _ in 0..=MAX_REDIRECTS {
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .ok_or(MetadataError::Timeout)?;
+            let response = public_client(&url, remaining, self.allow_private)?
+                .get(url.clone())
+                .send()?;
+            let status = response.status();
+            if status.is_redirection() {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or(MetadataError::Status(status.as_u16()))?;
+                url = url.join(location).map_err(|_| MetadataError::InvalidUrl)?;
+                continue;
+            }
+            if !status.is_success() {
+                return Err(MetadataError::Status(status.as_u16()));
+            }
+            let is_html = match response.headers().get(CONTENT_TYPE) {
+                Some(content_type) => content_type
+                    .to_str()
+                    .map(|content_type| content_type.contains("html"))
+                    .unwrap_or(false),
+                None => true,
+            };
+            if !is_html {
+                return Err(MetadataError::NotHtml);
+            }
+            let mut body = vec![];
+            response
+                .take(self.max_bytes as u64 + 1)
+                .read_to_end(&mut body)?;
+            if body.len() > self.max_bytes {
+                return Err(MetadataError::TooLarge);
+            }
+            return Ok(parse_metadata(&String::from_utf8_lossy(&body), &url));
+        }
+        Err(MetadataError::TooManyRedirects)
+    }
+}
+
+/// Client for a single request to `url` that doesn't follow redirects and only
+/// connects to the checked addresses of the url host, so a second DNS answer
+/// can't point the request somewhere else.
+pub fn public_client(
+    url: &Url,
+    timeout: Duration,
+    allow_private: bool,
+) -> Result<Client, MetadataError> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(MetadataError::InvalidUrl);
+    }
+    let host = url.host_str().ok_or(MetadataError::InvalidUrl)?;
+    let port = url
+        .port_or_known_default()
+        .ok_or(MetadataError::InvalidUrl)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addresses: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+    if addresses.is_empty() {
+        return Err(MetadataError::Request(format!("{} does not resolve", host)));
+    }
+    if !allow_private && !addresses.iter().all(|address| is_public(address.ip())) {
+        return Err(MetadataError::Blocked);
+    }
+    let mut builder = Client::builder()
+        .timeout(timeout)
+        .redirect(Policy::none())
+        .no_proxy()
+        .user_agent(USER_AGENT);
+    if let Some(domain) = url.domain() {
+        builder = builder.resolve_to_addrs(domain, &addresses);
+    }
+    Ok(builder.build()?)
+}
+
+#[async_trait]
+impl MetadataFetcher for HttpMetadataFetcher {
+    #[tracing::instrument(skip(self))]
+    async fn fetch(&self, url: &str) -> Result<Metadata, MetadataError> {
+        let fetcher = self.clone();
+        let url = url.to_string();
+        web::block(move || fetcher.fetch_blocking(&url))
+            .await
+            .map_err(|e| match e {
+                BlockingError::Error(e) => e,
+                BlockingError::Canceled => MetadataError::Request("canceled".to_string()),
+            })
+    }
+}
+
+/// Addresses reachable from the internet, everything else could be an internal service.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // shared address space of carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                // IETF protocol assignments
+                || (a == 192 && b == 0 && c == 0)
+                // benchmarking
+                || (a == 198 && (b == 18 || b == 19))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // unique local
+                || (segments[0] & 0xfe00) == 0xfc00
+                // link local
+                || (segments[0] & 0xffc0) == 0xfe80
+                // documentation
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+        }
+    }
+}
+
+/// Title, Open Graph tags and favicon of a page, relative urls are resolved against `base`.
+pub fn parse_metadata(html: &str, base: &Url) -> Metadata {
+    let mut title = None;
+    let mut metadata = Metadata::default();
+    let mut og_title = None;
+    let mut description = None;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = &rest[..end];
+        let name_end = tag
+            .find(|c: char| c.is_ascii_whitespace() || c == '/')
+            .unwrap_or(tag.len());
+        let name = tag[..name_end].to_ascii_lowercase();
+        let attributes = parse_attributes(&tag[name_end..]);
+        let attribute = |key: &str| {
+            attributes
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.as_str())
+        };
+        rest = &rest[end + 1..];
+        match name.as_str() {
+            "title" if title.is_none() => {
+                let close = find_ignore_case(rest, "</title").unwrap_or(rest.len());
+                title = clean_text(&rest[..close]);
+                rest = &rest[close..];
+            }
+            "meta" => {
+                let key = attribute("property")
+                    .or_else(|| attribute("name"))
+                    .map(str::to_ascii_lowercase);
+                let content = attribute("content");
+                match (key.as_deref(), content) {
+                    (Some("og:title"), Some(content)) => og_title = clean_text(content),
+                    (Some("og:description"), Some(content)) => {
+                        metadata.description = clean_text(content)
+                    }
+                    (Some("description"), Some(content)) => description = clean_text(content),
+                    (Some("og:image"), Some(content)) => {
+                        metadata.image = absolute_url(base, content)
+                    }
+                    (Some("og:site_name"), Some(content)) => {
+                        metadata.site_name = clean_text(content)
+                    }
+                    _ => {}
+                }
+            }
+            "link" => {
+                let is_icon = attribute("rel")
+                    .map(|rel| {
+                        rel.split_ascii_whitespace()
+                            .any(|rel| rel.eq_ignore_ascii_case("icon"))
+                    })
+                    .unwrap_or(false);
+                if is_icon && metadata.favicon.is_none() {
+                    metadata.favicon = attribute("href").and_then(|href| absolute_url(base, href));
+                }
+            }
+            "script" | "style" => {
+                let close = find_ignore_case(rest, &format!("</{}", name)).unwrap_or(rest.len());
+                rest = &rest[close..];
+            }
+            "body" | "/head" => break,
+            _ => {}
+        }
+    }
+    metadata.title = og_title.or(title);
+    metadata.description = metadata.description.or(description);
+    if metadata.favicon.is_none() {
+        metadata.favicon = absolute_url(base, "/favicon.ico");
+    }
+    metadata
+}
+
+/// `name="value"`, `name='value'`, `name=value` and bare names, names are lowercased.
+fn parse_attributes(mut input: &str) -> Vec<(String, String)> {
+    let mut attributes = vec![];
+    loop {
+        input = input.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+        if input.is_empty() {
+            return attributes;
+        }
+        let name_end = input
+            .find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '/')
+            .unwrap_or(input.len());
+        let name = input[..name_end].to_ascii_lowercase();
+        input = input[name_end..].trim_start();
+        let value = match input.strip_prefix('=') {
+            Some(after) => {
+                let after = after.trim_start();
+                let (value, remaining) = match after.chars().next() {
+                    Some(quote @ '"') | Some(quote @ '\'') => {
+                        let after = &after[1..];
+                        let end = after.find(quote).unwrap_or(after.len());
+                        (&after[..end], after.get(end + 1..).unwrap_or(""))
+                    }
+                    _ => {
+                        let end = after
+                            .find(|c: char| c.is_ascii_whitespace())
+                            .unwrap_or(after.len());
+                        (&after[..end], &after[end..])
+                    }
+                };
+                input = remaining;
+                decode_entities(value)
+            }
+            None => String::new(),
+        };
+        if !name.is_empty() {
+            attributes.push((name, value));
+        }
+    }
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..].find(';').map(|end| &rest[1..end + 1]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => match entity.strip_prefix('#') {
+                Some(code) => match code.strip_prefix(|c| c == 'x' || c == 'X') {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => code.parse().ok(),
+                }
+                .and_then(char::from_u32),
+                None => None,
+            },
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Decoded text with collapsed whitespace, `None` when blank.
+fn clean_text(text: &str) -> Option<String> {
+    let text = decode_entities(text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if text.is_empty() {
+        return None;
+    }
+    Some(text.chars().take(MAX_TEXT_LENGTH).collect())
+}
+
+fn absolute_url(base: &Url, href: &str) -> Option<String> {
+    let url = base.join(href.trim()).ok()?;
+    let url = url.to_string();
+    let is_http = url.starts_with("http://") || url.starts_with("https://");
+    Some(url).filter(|url| is_http && url.len() <= MAX_URL_LENGTH)
+}
+
+pub async fn configure(settings: &Settings) -> Option<Arc<dyn MetadataFetcher + Send + Sync>> {
+    if !settings.fetch_metadata {
+        return None;
+    }
+    Some(Arc::new(HttpMetadataFetcher::new(
+        Duration::from_secs(settings.metadata_timeout),
+        settings.metadata_max_bytes,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serves canned responses on a local port, one per connection.
+    fn stub_server(responses: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for (response, stream) in responses.into_iter().zip(listener.incoming()) {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request);
+                if let Some(delay) = response.strip_prefix("delay:") {
+                    thread::sleep(Duration::from_millis(delay.parse().unwrap()));
+                    continue;
+                }
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        address
+    }
+
+    fn html_response(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    fn local_fetcher() -> HttpMetadataFetcher {
+        HttpMetadataFetcher {
+            allow_private: true,
+            ..HttpMetadataFetcher::new(Duration::from_secs(2), 1024)
+        }
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let html = r#"<!DOCTYPE html>
+            <html><head>
+            <TITLE>Rust &amp;
+                friends</TITLE>
+            <meta name="description" content="Plain description">
+            <meta property='og:image' content=/images/card.png>
+            <meta property="og:site_name" content="Example">
+            <link rel="shortcut icon" href="/favicon.png"/>
+            <script>let title = "<title>not this</title>";</script>
+            </head><body><meta property="og:title" content="too late"></body></html>"#;
+        let base = Url::parse("https://example.com/blog/post").unwrap();
+        let metadata = parse_metadata(html, &base);
+        assert_eq!(metadata.title.as_deref(), Some("Rust & friends"));
+        assert_eq!(metadata.description.as_deref(), Some("Plain description"));
+        assert_eq!(
+            metadata.image.as_deref(),
+            Some("https://example.com/images/card.png")
+        );
+        assert_eq!(metadata.site_name.as_deref(), Some("Example"));
+        assert_eq!(
+            metadata.favicon.as_deref(),
+            Some("https://example.com/favicon.png")
+        );
+
+        let html = r#"<meta property="og:title" content="Card &#x2014; title"><title>Page</title>
+            <meta property="og:image" content="javascript:alert(1)">"#;
+        let metadata = parse_metadata(html, &base);
+        assert_eq!(metadata.title.as_deref(), Some("Card \u{2014} title"));
+        assert_eq!(metadata.image, None);
+        assert_eq!(
+            metadata.favicon.as_deref(),
+            Some("https://example.com/favicon.ico")
+        );
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in &["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_fetch() {
+        let address = stub_server(vec![
+            format!(
+                "HTTP/1.1 301 Moved Permanently\r\nLocation: /page\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            ),
+            html_response("<head><title>Stub page</title></head>"),
+        ]);
+        let metadata = local_fetcher().fetch(&address).await.unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Stub page"));
+        assert_eq!(metadata.favicon, Some(format!("{}/favicon.ico", address)));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_fetch_limits() {
+        let address = stub_server(vec![html_response(&"x".repeat(2048))]);
+        assert_eq!(
+            local_fetcher().fetch(&address).await,
+            Err(MetadataError::TooLarge)
+        );
+
+        let address = stub_server(vec!["delay:3000".to_string()]);
+        assert_eq!(
+            local_fetcher().fetch(&address).await,
+            Err(MetadataError::Timeout)
+        );
+
+        let address = stub_server(vec![
+            "HTTP/1.1 200 OK\r\nContent-Type: application/pdf\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+        ]);
+        assert_eq!(
+            local_fetcher().fetch(&address).await,
+            Err(MetadataError::NotHtml)
+        );
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_fetch_blocks_internal_addresses() {
+        let address = stub_server(vec![html_response("<title>Internal</title>")]);
+        let fetcher = HttpMetadataFetcher::new(Duration::from_secs(2), 1024);
+        assert_eq!(fetcher.fetch(&address).await, Err(MetadataError::Blocked));
+        assert_eq!(
+            fetcher.fetch("http://localhost/").await,
+            Err(MetadataError::Blocked)
+        );
+        assert_eq!(
+            fetcher.fetch("file:///etc/passwd").await,
+            Err(MetadataError::InvalidUrl)
+        );
+
+        // every redirect hop gets the same check
+        let url = Url::parse("http://127.0.0.1:1/admin").unwrap();
+        assert!(public_client(&url, Duration::from_secs(1), true).is_ok());
+        assert_eq!(
+            public_client(&url, Duration::from_secs(1), false).err(),
+            Some(MetadataError::Blocked)
+        );
+    }
+}
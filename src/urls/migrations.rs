@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+/// Layout of link hashes written by this version. New links store it in their
+/// `schema` field, the `schema_version` key holds it once every link is upgraded.
+pub const SCHEMA_VERSION: u32 = 2;
+/// Links written before versioning have no `schema` field.
+pub const BASELINE_VERSION: u32 = 1;
+
+/// Fields to add to a link given its fields, id and the default domain. They are
+/// written only where still absent, so concurrent writes win and a link can be
+/// upgraded twice without harm.
+pub type Upgrade = fn(&HashMap<String, String>, &str, &str) -> Vec<(&'static str, String)>;
+
+/// Upgrade of link hashes from the previous version to `version`.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub upgrade: Upgrade,
+}
+
+/// All migrations in order, the last one upgrades to `SCHEMA_VERSION`.
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    version: 2,
+    description: "store id, domain and count in every link",
+    upgrade: fill_required_fields,
+}];
+
+/// Version of a link hash.
+pub fn link_version(fields: &HashMap<String, String>) -> u32 {
+    fields
+        .get("schema")
+        .and_then(|version| version.parse().ok())
+        .unwrap_or(BASELINE_VERSION)
+}
+
+/// Migrations still to run on a keyspace at `version`.
+pub fn pending(version: u32) -> impl Iterator<Item = &'static Migration> {
+    MIGRATIONS
+        .iter()
+        .filter(move |migration| migration.version > version)
+}
+
+/// Links of the default domain were written without `domain`, and early links may
+/// miss their id or counter.
+fn fill_required_fields(
+    fields: &HashMap<String, String>,
+    id: &str,
+    default_domain: &str,
+) -> Vec<(&'static str, String)> {
+    let mut missing = vec![];
+    if !fields.contains_key("id") {
+        missing.push(("id", id.to_string()));
+    }
+    if !fields.contains_key("domain") {
+        missing.push(("domain", default_domain.to_string()));
+    }
+    if !fields.contains_key("count") {
+        missing.push(("count", "0".to_string()));
+    }
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        let versions: Vec<u32> = MIGRATIONS
+            .iter()
+            .map(|migration| migration.version)
+            .collect();
+        let expected: Vec<u32> = (BASELINE_VERSION + 1..=SCHEMA_VERSION).collect();
+        assert_eq!(versions, expected);
+        assert_eq!(pending(BASELINE_VERSION).count(), MIGRATIONS.len());
+        assert_eq!(pending(SCHEMA_VERSION).count(), 0);
+    }
+
+    #[test]
+    fn test_link_version() {
+        assert_eq!(link_version(&fields(&[])), BASELINE_VERSION);
+        assert_eq!(link_version(&fields(&[("schema", "2")])), 2);
+        assert_eq!(link_version(&fields(&[("schema", "x")])), BASELINE_VERSION);
+    }
+
+    #[test]
+    fn test_fill_required_fields() {
+        let legacy = fields(&[("url", "https://example.com")]);
+        assert_eq!(
+            fill_required_fields(&legacy, "abc", "urls.lol"),
+            vec![
+                ("id", "abc".to_string()),
+                ("domain", "urls.lol".to_string()),
+                ("count", "0".to_string())
+            ]
+        );
+        let complete = fields(&[
+            ("id", "abc"),
+            ("url", "https://example.com"),
+            ("domain", "go.example.com"),
+            ("count", "7"),
+        ]);
+        assert!(fill_required_fields(&complete, "abc", "urls.lol").is_empty());
+    }
+}
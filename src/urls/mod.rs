@@ -1,6 +1,16 @@
 pub mod api;
 pub mod error;
+pub mod event_stream;
+pub mod expiry;
+pub mod id_generator;
+pub mod link_checker;
+pub mod metadata;
+pub mod migrations;
+pub mod qr;
 pub mod redis_url_repo;
+pub mod slug_filter;
 pub mod types;
+pub mod url_cache;
 pub mod url_service;
 pub mod utils;
+pub mod webhooks;
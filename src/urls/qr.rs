@@ -0,0 +1,254 @@
+use qrcode::{Color, EcLevel, QrCode};
+use serde::Deserialize;
+
+const DEFAULT_SIZE: u32 = 256;
+const MIN_SIZE: u32 = 64;
+const MAX_SIZE: u32 = 2048;
+const DEFAULT_MARGIN: u32 = 4;
+const MAX_MARGIN: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl Rgb {
+    /// Parses `rrggbb` or `rgb`, with or without a leading `#`.
+    fn parse(value: &str) -> Option<Rgb> {
+        let hex = value.trim_start_matches('#');
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let channel = |s: &str| u8::from_str_radix(s, 16).ok();
+        match hex.len() {
+            6 => Some(Rgb(
+                channel(&hex[0..2])?,
+                channel(&hex[2..4])?,
+                channel(&hex[4..6])?,
+            )),
+            3 => Some(Rgb(
+                channel(&hex[0..1])? * 17,
+                channel(&hex[1..2])? * 17,
+                channel(&hex[2..3])? * 17,
+            )),
+            _ => None,
+        }
+    }
+
+    fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+}
+
+/// Query parameters accepted by the QR code endpoints.
+#[derive(Debug, Default, Deserialize)]
+pub struct QrParams {
+    pub size: Option<u32>,
+    pub margin: Option<u32>,
+    pub ec: Option<String>,
+    pub color: Option<String>,
+    pub background: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QrOptions {
+    /// Requested side of the image in pixels.
+    pub size: u32,
+    /// Quiet zone around the code in modules.
+    pub margin: u32,
+    pub ec_level: EcLevel,
+    pub color: Rgb,
+    pub background: Rgb,
+}
+
+impl QrParams {
+    pub fn options(&self) -> Result<QrOptions, String> {
+        let size = self.size.unwrap_or(DEFAULT_SIZE);
+        if !(MIN_SIZE..=MAX_SIZE).contains(&size) {
+            return Err(format!(
+                "size must be between {} and {}",
+                MIN_SIZE, MAX_SIZE
+            ));
+        }
+        let margin = self.margin.unwrap_or(DEFAULT_MARGIN);
+        if margin > MAX_MARGIN {
+            return Err(format!("margin must not exceed {}", MAX_MARGIN));
+        }
+        let ec_level = match self.ec.as_deref().unwrap_or("M") {
+            "L" | "l" => EcLevel::L,
+            "M" | "m" => EcLevel::M,
+            "Q" | "q" => EcLevel::Q,
+            "H" | "h" => EcLevel::H,
+            _ => return Err("ec must be one of L, M, Q, H".to_string()),
+        };
+        let color = match &self.color {
+            Some(color) => Rgb::parse(color).ok_or("color must be a hex color")?,
+            None => Rgb(0, 0, 0),
+        };
+        let background = match &self.background {
+            Some(background) => Rgb::parse(background).ok_or("background must be a hex color")?,
+            None => Rgb(255, 255, 255),
+        };
+        Ok(QrOptions {
+            size,
+            margin,
+            ec_level,
+            color,
+            background,
+        })
+    }
+}
+
+/// Module matrix of a QR code together with its quiet zone.
+struct Matrix {
+    width: usize,
+    margin: usize,
+    dark: Vec<bool>,
+}
+
+impl Matrix {
+    fn new(data: &str, options: &QrOptions) -> Result<Matrix, String> {
+        let code = QrCode::with_error_correction_level(data, options.ec_level)
+            .map_err(|e| e.to_string())?;
+        Ok(Matrix {
+            width: code.width(),
+            margin: options.margin as usize,
+            dark: code
+                .to_colors()
+                .into_iter()
+                .map(|color| color == Color::Dark)
+                .collect(),
+        })
+    }
+
+    /// Side in modules including the quiet zone.
+    fn side(&self) -> usize {
+        self.width + 2 * self.margin
+    }
+
+    fn is_dark(&self, x: usize, y: usize) -> bool {
+        if x < self.margin || y < self.margin {
+            return false;
+        }
+        let (x, y) = (x - self.margin, y - self.margin);
+        x < self.width && y < self.width && self.dark[y * self.width + x]
+    }
+}
+
+pub fn render_png(data: &str, options: &QrOptions) -> Result<Vec<u8>, String> {
+    let matrix = Matrix::new(data, options)?;
+    let side = matrix.side();
+    let scale = std::cmp::max(1, options.size as usize / side);
+    let pixels = side * scale;
+
+    let mut image = Vec::with_capacity(pixels * pixels * 3);
+    for y in 0..pixels {
+        for x in 0..pixels {
+            let Rgb(r, g, b) = if matrix.is_dark(x / scale, y / scale) {
+                options.color
+            } else {
+                options.background
+            };
+            image.extend_from_slice(&[r, g, b]);
+        }
+    }
+
+    let mut result = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut result, pixels as u32, pixels as u32);
+        encoder.set_color(png::ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+        writer.write_image_data(&image).map_err(|e| e.to_string())?;
+    }
+    Ok(result)
+}
+
+pub fn render_svg(data: &str, options: &QrOptions) -> Result<String, String> {
+    let matrix = Matrix::new(data, options)?;
+    let side = matrix.side();
+
+    let mut path = String::new();
+    for y in 0..side {
+        for x in 0..side {
+            if matrix.is_dark(x, y) {
+                path.push_str(&format!("M{} {}h1v1h-1z", x, y));
+            }
+        }
+    }
+
+    Ok(format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" version="1.1" "#,
+            r#"width="{size}" height="{size}" viewBox="0 0 {side} {side}" shape-rendering="crispEdges">"#,
+            r#"<rect width="{side}" height="{side}" fill="{background}"/>"#,
+            r#"<path fill="{color}" d="{path}"/>"#,
+            "</svg>"
+        ),
+        size = options.size,
+        side = side,
+        background = options.background.hex(),
+        color = options.color.hex(),
+        path = path,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_options() {
+        let options = QrParams::default().options().unwrap();
+        assert_eq!(options.size, DEFAULT_SIZE);
+        assert_eq!(options.margin, DEFAULT_MARGIN);
+        assert_eq!(options.ec_level, EcLevel::M);
+        assert_eq!(options.color, Rgb(0, 0, 0));
+        assert_eq!(options.background, Rgb(255, 255, 255));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let params = QrParams {
+            size: Some(10),
+            ..Default::default()
+        };
+        assert!(params.options().is_err());
+        let params = QrParams {
+            ec: Some("X".to_string()),
+            ..Default::default()
+        };
+        assert!(params.options().is_err());
+        let params = QrParams {
+            color: Some("#12345z".to_string()),
+            ..Default::default()
+        };
+        assert!(params.options().is_err());
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(Rgb::parse("#ff8c00"), Some(Rgb(255, 140, 0)));
+        assert_eq!(Rgb::parse("f80"), Some(Rgb(255, 136, 0)));
+        assert_eq!(Rgb::parse("#ff8c0"), None);
+    }
+
+    #[test]
+    fn test_render_png() {
+        let options = QrParams::default().options().unwrap();
+        let png = render_png("http://localhost/test", &options).unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+    }
+
+    #[test]
+    fn test_render_svg() {
+        let params = QrParams {
+            color: Some("ff8c00".to_string()),
+            margin: Some(0),
+            ..Default::default()
+        };
+        let svg = render_svg("http://localhost/test", &params.options().unwrap()).unwrap();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains(r##"fill="#ff8c00""##));
+        // finder pattern always starts in the top left corner without a quiet zone
+        assert!(svg.contains(r#"d="M0 0h1v1h-1z"#));
+    }
+}
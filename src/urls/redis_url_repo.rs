@@ -1,167 +1,1061 @@
 use super::types::*;
+use crate::domains::Domains;
+use crate::hashids::Hashids;
+use crate::metrics::observe_redis;
+use crate::redis::{Connection, Namespace, RedisClient};
+use crate::settings::Settings;
 use crate::urls::error::UrlError;
+use crate::urls::id_generator::{self, IdGenerator};
+use crate::urls::migrations::{self, Migration};
+use crate::urls::slug_filter::{self, SlugFilter};
+use crate::urls::utils::{unix_time, unix_time_millis};
+use crate::{domains, hashids};
 use async_trait::async_trait;
-use harsh::Harsh;
+use rand::Rng;
 use redis::{AsyncCommands, RedisError, RedisResult};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
 
-const URL_COUNTER_KEY: &str = "url_shortener:url_counter";
-const USER_COUNTER_KEY: &str = "url_shortener:user_counter";
-const URLS_KEY: &str = "url_shortener:urls";
-const USERS_KEY: &str = "url_shortener:users";
+/// Names of the keys within the namespace.
+const USER_COUNTER_KEY: &str = "user_counter";
+const URLS_KEY: &str = "urls";
+const USERS_KEY: &str = "users";
+const WORKSPACE_COUNTER_KEY: &str = "workspace_counter";
+const WORKSPACES_KEY: &str = "workspaces";
+const USER_WORKSPACES_KEY: &str = "user_workspaces";
+const INVITES_KEY: &str = "invites";
+/// Sorted set of link members scored by the unix time of their next check.
+const CHECKS_KEY: &str = "checks";
+/// Sorted set of link members scored by the unix time they expire at.
+const EXPIRATIONS_KEY: &str = "expirations";
+const WEBHOOKS_KEY: &str = "webhooks";
+const DELIVERIES_KEY: &str = "deliveries";
+const DEAD_LETTERS_KEY: &str = "dead_letters";
+/// Hashes of event counts per day.
+const STATS_KEY: &str = "stats";
+/// Hashes of clicks per day of a link.
+const CLICKS_KEY: &str = "clicks";
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+/// Hash with the `version` being migrated to and the SCAN `cursor` reached.
+const SCHEMA_PROGRESS_KEY: &str = "schema_version:progress";
+/// Keys per SCAN step of a migration.
+const MIGRATION_BATCH_SIZE: usize = 500;
+
+/// Unused invites expire after a week.
+const INVITE_TTL_SECONDS: usize = 7 * 24 * 60 * 60;
+const INVITE_TOKEN_LENGTH: usize = 32;
+const WEBHOOK_ID_LENGTH: usize = 12;
+const WEBHOOK_SECRET_LENGTH: usize = 32;
+/// Delivery attempts kept per user.
+const MAX_DELIVERIES: isize = 100;
+/// Failed deliveries kept per user.
+const MAX_DEAD_LETTERS: isize = 1000;
+
+/// Attempts to find a free generated id before giving up.
+const MAX_GENERATE_ATTEMPTS: usize = 10;
+
+/// Creates the url hash KEYS[1] only if the id is not taken yet on its domain,
+/// with the fields in ARGV[5..]. Its member ARGV[1] is due for a check in KEYS[2]
+/// at ARGV[2], expires in KEYS[3] at ARGV[3] unless it is empty, and joins the
+/// links of the owner KEYS[4], when given, at ARGV[4].
+const CREATE_URL_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+redis.call('HSET', KEYS[1], unpack(ARGV, 5))
+redis.call('ZADD', KEYS[2], ARGV[2], ARGV[1])
+if ARGV[3] ~= '' then
+    redis.call('ZADD', KEYS[3], ARGV[3], ARGV[1])
+end
+if KEYS[4] then
+    redis.call('ZADD', KEYS[4], ARGV[4], ARGV[1])
+end
+return 1
+";
+
+/// Deletes a link only if its member ARGV[1] is in the sorted set KEYS[1]: the
+/// url hash KEYS[2], its check and expiry in KEYS[3] and KEYS[4], and its clicks
+/// per day KEYS[5].
+const DELETE_URL_SCRIPT: &str = r"
+if not redis.call('ZSCORE', KEYS[1], ARGV[1]) then
+    return 0
+end
+redis.call('ZREM', KEYS[1], ARGV[1])
+redis.call('DEL', KEYS[2], KEYS[5])
+redis.call('ZREM', KEYS[3], ARGV[1])
+redis.call('ZREM', KEYS[4], ARGV[1])
+return 1
+";
+
+/// Moves a link member between sorted sets keeping its score,
+/// only if it is in the source set.
+const MOVE_URL_SCRIPT: &str = r"
+local score = redis.call('ZSCORE', KEYS[1], ARGV[1])
+if not score then
+    return 0
+end
+redis.call('ZREM', KEYS[1], ARGV[1])
+redis.call('ZADD', KEYS[2], score, ARGV[1])
+return 1
+";
+
+/// Takes up to ARGV[2] members due at ARGV[1] and postpones them to ARGV[3].
+const CLAIM_CHECKS_SCRIPT: &str = r"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, member in ipairs(due) do
+    redis.call('ZADD', KEYS[1], ARGV[3], member)
+end
+return due
+";
+
+/// Takes up to ARGV[2] members expired at ARGV[1] out of the expirations, and
+/// out of the checks in KEYS[2].
+const CLAIM_EXPIRED_SCRIPT: &str = r"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+if #due > 0 then
+    redis.call('ZREM', KEYS[1], unpack(due))
+    redis.call('ZREM', KEYS[2], unpack(due))
+end
+return due
+";
+
+/// Variant layout inside the `variants` hash field, clicks live in `count:{index}` fields.
+#[derive(Serialize, Deserialize)]
+struct StoredVariant {
+    url: String,
+    weight: u32,
+}
+
+/// JSON encoded field of a link, `None` when absent or unreadable.
+fn json_field<T: DeserializeOwned>(
+    fields: &HashMap<String, String>,
+    name: &str,
+    domain: &str,
+    id: &str,
+) -> Option<T> {
+    let parsed = serde_json::from_str(fields.get(name)?);
+    if parsed.is_err() {
+        tracing::warn!(domain, id, field = name, "ignoring unreadable field");
+    }
+    parsed.ok()
+}
+
+/// Repository of the configured Redis, for processes other than the server.
+pub async fn configure(settings: &Settings) -> RedisUrlRepoImpl {
+    let redis_client = Arc::new(crate::redis::configure(settings).await);
+    let hashids = hashids::configure(settings).await;
+    RedisUrlRepoImpl {
+        id_generator: id_generator::configure(settings, redis_client.clone(), hashids.clone())
+            .await,
+        redis_client,
+        namespace: crate::redis::namespace(settings),
+        hashids,
+        slug_filter: slug_filter::configure(settings).await,
+        domains: domains::configure(settings).await,
+    }
+}
 
 impl From<RedisError> for UrlError {
     fn from(_: RedisError) -> UrlError {
-        UrlError {}
+        UrlError::Internal
     }
 }
 
+#[derive(Clone)]
 pub struct RedisUrlRepoImpl {
-    pub redis_client: Arc<redis::Client>,
-    pub hashids: Harsh,
+    pub redis_client: Arc<RedisClient>,
+    pub namespace: Namespace,
+    /// Encodes user ids, link ids come from `id_generator`.
+    pub hashids: Hashids,
+    pub id_generator: Arc<dyn IdGenerator + Send + Sync>,
+    pub slug_filter: SlugFilter,
+    pub domains: Domains,
 }
 
 impl RedisUrlRepoImpl {
-    fn get_key(self: &Self, id: &str) -> String {
-        format!("{}:{}", URLS_KEY, id)
+    fn key(&self, name: &str) -> String {
+        self.namespace.key(name)
     }
 
-    fn get_user_key(self: &Self, id: &str) -> String {
-        format!("{}:{}", USERS_KEY, id)
+    fn prefixed_key(&self, prefix: &str, suffix: &str) -> String {
+        self.namespace.key(&format!("{}:{}", prefix, suffix))
     }
 
-    async fn get_next_key(self: &Self) -> Result<String, UrlError> {
-        let redis_client = &*self.redis_client;
-        redis_client
-            .get_async_connection()
-            .await?
-            .incr(URL_COUNTER_KEY, 1)
+    /// Links of the default domain keep the original `urls:{id}` layout.
+    fn get_key(&self, domain: &str, id: &str) -> String {
+        if self.domains.is_default(domain) {
+            self.prefixed_key(URLS_KEY, id)
+        } else {
+            self.prefixed_key(URLS_KEY, &format!("{}:{}", domain, id))
+        }
+    }
+
+    /// Member of the user sorted set, `{domain}/{id}` for non-default domains.
+    fn get_user_member(&self, domain: &str, id: &str) -> String {
+        if self.domains.is_default(domain) {
+            id.to_string()
+        } else {
+            format!("{}/{}", domain, id)
+        }
+    }
+
+    fn parse_user_member<'a>(&'a self, member: &'a str) -> (&'a str, &'a str) {
+        match member.find('/') {
+            Some(index) => (&member[..index], &member[index + 1..]),
+            None => (self.domains.default_domain(), member),
+        }
+    }
+
+    fn get_user_key(&self, id: &str) -> String {
+        self.prefixed_key(USERS_KEY, id)
+    }
+
+    fn get_workspace_key(&self, workspace: &str) -> String {
+        self.prefixed_key(WORKSPACES_KEY, workspace)
+    }
+
+    /// Hash of member user ids to roles.
+    fn get_members_key(&self, workspace: &str) -> String {
+        self.prefixed_key(WORKSPACES_KEY, &format!("{}:members", workspace))
+    }
+
+    /// Sorted set of workspace links, same layout as the user sets.
+    fn get_workspace_urls_key(&self, workspace: &str) -> String {
+        self.prefixed_key(WORKSPACES_KEY, &format!("{}:urls", workspace))
+    }
+
+    fn get_user_workspaces_key(&self, user: &str) -> String {
+        self.prefixed_key(USER_WORKSPACES_KEY, user)
+    }
+
+    fn get_invite_key(&self, token: &str) -> String {
+        self.prefixed_key(INVITES_KEY, token)
+    }
+
+    fn get_webhooks_key(&self, user: &str) -> String {
+        self.prefixed_key(WEBHOOKS_KEY, user)
+    }
+
+    fn get_deliveries_key(&self, user: &str) -> String {
+        self.prefixed_key(DELIVERIES_KEY, user)
+    }
+
+    fn get_dead_letters_key(&self, user: &str) -> String {
+        self.prefixed_key(DEAD_LETTERS_KEY, user)
+    }
+
+    /// Pushes a delivery to the front of a list capped at `max` entries.
+    async fn push_delivery(
+        &self,
+        key: String,
+        max: isize,
+        delivery: &Delivery,
+    ) -> Result<(), UrlError> {
+        let delivery = serde_json::to_string(delivery).map_err(|_| UrlError::Internal)?;
+        let mut conn = self.connection().await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .lpush(&key, delivery)
+            .ignore()
+            .ltrim(&key, 0, max - 1)
+            .ignore();
+        observe_redis("push_delivery", pipe.query_async(&mut conn))
             .await
-            .map(|result| (self.hashids.encode(&[result])))
-            .map_err(|_| UrlError)
+            .map_err(|_| UrlError::Internal)
     }
 
-    async fn save_for_user(self: &Self, id: &str, user: &str) {
-        let redis_client = &*self.redis_client;
-        if let Ok(mut conn) = redis_client.get_async_connection().await {
-            let _: RedisResult<String> = conn
-                .zadd(
-                    self.get_user_key(user),
-                    id,
-                    SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis()
-                        .to_string(),
+    async fn get_delivery_list(&self, key: String) -> Result<Vec<Delivery>, UrlError> {
+        let mut conn = self.connection().await?;
+        let deliveries: Vec<String> = observe_redis("lrange", conn.lrange(key, 0, -1)).await?;
+        Ok(deliveries
+            .iter()
+            .filter_map(|delivery| serde_json::from_str(delivery).ok())
+            .collect())
+    }
+
+    fn get_scope_key(&self, scope: &Scope) -> String {
+        match scope {
+            Scope::User(user) => self.get_user_key(user),
+            Scope::Workspace(workspace) => self.get_workspace_urls_key(workspace),
+        }
+    }
+
+    async fn get_urls_in(&self, key: String, start: isize, stop: isize) -> Vec<Url> {
+        let mut res = vec![];
+        if let Ok(mut conn) = self.connection().await {
+            let members: Vec<String> =
+                observe_redis("zrevrange", conn.zrevrange(key, start, stop - 1))
+                    .await
+                    .unwrap_or_default();
+            for member in members.iter() {
+                let (domain, id) = self.parse_user_member(member);
+                if let Ok(url) = self.get(domain, id).await {
+                    res.push(url)
+                }
+            }
+        }
+        res
+    }
+
+    async fn count_urls_in(&self, key: String) -> Result<isize, UrlError> {
+        let mut conn = self.connection().await?;
+        observe_redis("zcount", conn.zcount(key, "-inf", "+inf"))
+            .await
+            .map_err(|_| UrlError::Internal)
+    }
+
+    async fn connection(&self) -> RedisResult<Connection> {
+        observe_redis("connect", self.redis_client.get_async_connection()).await
+    }
+
+    async fn get_next_key(&self) -> Result<String, UrlError> {
+        self.id_generator.next_id().await
+    }
+
+    fn variant_count_field(index: usize) -> String {
+        format!("count:{}", index)
+    }
+
+    /// Any schema version, only the destination is required.
+    fn parse_url(
+        &self,
+        domain: &str,
+        id: &str,
+        fields: &HashMap<String, String>,
+    ) -> Result<Url, UrlError> {
+        let url = fields.get("url").ok_or(UrlError::Internal)?;
+        let stored: Vec<StoredVariant> =
+            json_field(fields, "variants", domain, id).unwrap_or_default();
+        let variants = stored
+            .into_iter()
+            .enumerate()
+            .map(|(index, variant)| Variant {
+                url: variant.url,
+                weight: variant.weight,
+                count: fields
+                    .get(&Self::variant_count_field(index))
+                    .and_then(|count| count.parse().ok())
+                    .unwrap_or(0),
+            })
+            .collect();
+        Ok(Url {
+            id: fields.get("id").map_or(id, String::as_str).to_string(),
+            url: url.clone(),
+            count: fields
+                .get("count")
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(0),
+            variants,
+            domain: fields
+                .get("domain")
+                .map_or(domain, String::as_str)
+                .to_string(),
+            title: fields.get("title").cloned(),
+            notes: fields.get("notes").cloned(),
+            tags: json_field(fields, "tags", domain, id).unwrap_or_default(),
+            // unreadable metadata and health are fetched again rather than failing the link
+            metadata: json_field(fields, "metadata", domain, id),
+            preview: json_field(fields, "preview", domain, id),
+            health: json_field(fields, "health", domain, id),
+            owner: fields.get("owner").cloned(),
+            expires_at: fields
+                .get("expires_at")
+                .and_then(|expires_at| expires_at.parse().ok()),
+        })
+    }
+
+    fn url_fields(url: &NewUrl, id: &str) -> Result<Vec<(String, String)>, UrlError> {
+        let mut fields = vec![
+            ("id".to_string(), id.to_string()),
+            ("url".to_string(), url.url.clone()),
+            ("count".to_string(), "0".to_string()),
+            ("domain".to_string(), url.domain.clone()),
+            ("schema".to_string(), migrations::SCHEMA_VERSION.to_string()),
+        ];
+        if let Some(expires_at) = url.expires_at {
+            fields.push(("expires_at".to_string(), expires_at.to_string()));
+        }
+        fields.extend(Self::detail_fields(
+            &url.title,
+            &url.notes,
+            &url.tags,
+            &url.preview,
+        )?);
+        if !url.variants.is_empty() {
+            let stored: Vec<StoredVariant> = url
+                .variants
+                .iter()
+                .map(|variant| StoredVariant {
+                    url: variant.url.clone(),
+                    weight: variant.weight,
+                })
+                .collect();
+            let stored = serde_json::to_string(&stored).map_err(|_| UrlError::Internal)?;
+            fields.push(("variants".to_string(), stored));
+            for index in 0..url.variants.len() {
+                fields.push((Self::variant_count_field(index), "0".to_string()));
+            }
+        }
+        Ok(fields)
+    }
+
+    /// Title, notes, tags and preview card, absent values are left out.
+    fn detail_fields(
+        title: &Option<String>,
+        notes: &Option<String>,
+        tags: &[String],
+        preview: &Option<Preview>,
+    ) -> Result<Vec<(String, String)>, UrlError> {
+        let mut fields = vec![];
+        if let Some(title) = title {
+            fields.push(("title".to_string(), title.clone()));
+        }
+        if let Some(notes) = notes {
+            fields.push(("notes".to_string(), notes.clone()));
+        }
+        if !tags.is_empty() {
+            let tags = serde_json::to_string(tags).map_err(|_| UrlError::Internal)?;
+            fields.push(("tags".to_string(), tags));
+        }
+        if let Some(preview) = preview {
+            let preview = serde_json::to_string(preview).map_err(|_| UrlError::Internal)?;
+            fields.push(("preview".to_string(), preview));
+        }
+        Ok(fields)
+    }
+
+    /// Schedules checks of links created before link checking was enabled,
+    /// returns the number of links found.
+    pub async fn schedule_all_checks(&self) -> Result<usize, UrlError> {
+        let keys = self
+            .redis_client
+            .scan_match(&self.prefixed_key(URLS_KEY, "*"))
+            .await?;
+        let mut conn = self.connection().await?;
+        let mut scheduled = 0;
+        for key in keys.iter() {
+            let (id, domain): (Option<String>, Option<String>) =
+                observe_redis("hmget", conn.hget(key, &["id", "domain"])).await?;
+            if let Some(id) = id {
+                let domain = domain.unwrap_or_else(|| self.domains.default_domain().to_string());
+                let member = self.get_user_member(&domain, &id);
+                // NX keeps the time of links that are already scheduled
+                let _: () = observe_redis(
+                    "zadd",
+                    redis::cmd("ZADD")
+                        .arg(self.key(CHECKS_KEY))
+                        .arg("NX")
+                        .arg(unix_time())
+                        .arg(member)
+                        .query_async(&mut conn),
                 )
-                .await;
+                .await?;
+                scheduled += 1;
+            }
+        }
+        Ok(scheduled)
+    }
+
+    /// Schema version all links have been migrated to.
+    pub async fn schema_version(&self) -> Result<u32, UrlError> {
+        let mut conn = self.connection().await?;
+        let version: Option<u32> =
+            observe_redis("get", conn.get(self.key(SCHEMA_VERSION_KEY))).await?;
+        Ok(version.unwrap_or(migrations::BASELINE_VERSION))
+    }
+
+    /// Resumes from the SCAN position saved after each batch, returns the number
+    /// of links upgraded.
+    pub async fn migrate(&self, migration: &Migration) -> Result<usize, UrlError> {
+        let mut conn = self.connection().await?;
+        let progress_key = self.key(SCHEMA_PROGRESS_KEY);
+        let (version, cursor): (Option<u32>, Option<u64>) =
+            observe_redis("hmget", conn.hget(&progress_key, &["version", "cursor"])).await?;
+        let mut position = match (version, cursor) {
+            (Some(version), Some(cursor)) if version == migration.version => {
+                tracing::info!(cursor, "resuming migration");
+                Some(cursor)
+            }
+            _ => Some(0),
         };
+        let pattern = self.prefixed_key(URLS_KEY, "*");
+        let mut upgraded = 0;
+        while let Some(current) = position {
+            let (next, keys) = self
+                .redis_client
+                .scan_step(current, &pattern, MIGRATION_BATCH_SIZE)
+                .await?;
+            for key in keys.iter() {
+                if self.upgrade_link(&mut conn, migration, key).await? {
+                    upgraded += 1;
+                }
+            }
+            if let Some(cursor) = next {
+                let _: () = observe_redis(
+                    "hset",
+                    conn.hset_multiple(
+                        &progress_key,
+                        &[
+                            ("version", migration.version.to_string()),
+                            ("cursor", cursor.to_string()),
+                        ],
+                    ),
+                )
+                .await?;
+            }
+            position = next;
+        }
+        let _: () = observe_redis(
+            "set",
+            conn.set(self.key(SCHEMA_VERSION_KEY), migration.version),
+        )
+        .await?;
+        let _: () = observe_redis("del", conn.del(&progress_key)).await?;
+        Ok(upgraded)
+    }
+
+    async fn upgrade_link(
+        &self,
+        conn: &mut Connection,
+        migration: &Migration,
+        key: &str,
+    ) -> Result<bool, UrlError> {
+        let fields: HashMap<String, String> = observe_redis("hgetall", conn.hgetall(key)).await?;
+        // deleted since the scan, or already upgraded
+        if fields.is_empty() || migrations::link_version(&fields) >= migration.version {
+            return Ok(false);
+        }
+        let id = key.rsplit(':').next().unwrap_or_default();
+        let mut pipe = redis::pipe();
+        for (field, value) in (migration.upgrade)(&fields, id, self.domains.default_domain()) {
+            pipe.hset_nx(key, field, value).ignore();
+        }
+        pipe.hset(key, "schema", migration.version).ignore();
+        let _: () = observe_redis("migrate", pipe.query_async(conn)).await?;
+        Ok(true)
+    }
+
+    /// Stores a new link with its check, expiry and owner in one script, so a
+    /// failure leaves no link behind that its owner can't see.
+    async fn create(&self, url: &NewUrl, owner: Option<&str>) -> Result<Url, UrlError> {
+        if let Some(alias) = &url.alias {
+            self.slug_filter
+                .check(alias)
+                .map_err(UrlError::AliasNotAllowed)?;
+        }
+        let mut conn = self.connection().await?;
+        let script = redis::Script::new(CREATE_URL_SCRIPT);
+
+        for _ in 0..MAX_GENERATE_ATTEMPTS {
+            let id = match &url.alias {
+                Some(alias) => alias.clone(),
+                None => self.get_next_key().await?,
+            };
+            if self.slug_filter.check(&id).is_err() {
+                // skips the counter value, generated ids are never reused
+                continue;
+            }
+            let mut invocation = script.prepare_invoke();
+            invocation
+                .key(self.get_key(&url.domain, &id))
+                .key(self.key(CHECKS_KEY))
+                .key(self.key(EXPIRATIONS_KEY))
+                .arg(self.get_user_member(&url.domain, &id))
+                .arg(unix_time())
+                .arg(
+                    url.expires_at
+                        .map_or(String::new(), |expires_at| expires_at.to_string()),
+                )
+                .arg(unix_time_millis());
+            if let Some(owner) = owner {
+                invocation.key(self.get_user_key(owner));
+            }
+            let mut fields = Self::url_fields(url, &id)?;
+            fields.extend(owner.map(|owner| ("owner".to_string(), owner.to_string())));
+            for (field, value) in fields {
+                invocation.arg(field).arg(value);
+            }
+            let created: bool =
+                observe_redis("create_url", invocation.invoke_async(&mut conn)).await?;
+            if created {
+                return Ok(Url {
+                    id,
+                    url: url.url.clone(),
+                    count: 0,
+                    variants: url.variants.clone(),
+                    domain: url.domain.clone(),
+                    title: url.title.clone(),
+                    notes: url.notes.clone(),
+                    tags: url.tags.clone(),
+                    metadata: None,
+                    preview: url.preview.clone(),
+                    health: None,
+                    owner: owner.map(str::to_string),
+                    expires_at: url.expires_at,
+                });
+            }
+            if url.alias.is_some() {
+                return Err(UrlError::AliasTaken);
+            }
+        }
+        Err(UrlError::Internal)
     }
 }
 
 #[async_trait]
 impl UrlRepo for RedisUrlRepoImpl {
-    async fn generate(&self, url: &str) -> Result<Url, UrlError> {
-        let redis_client = &*self.redis_client;
-        let next_key = self.get_next_key().await.unwrap();
-        let url_key = self.get_key(&next_key);
-        let mut conn = redis_client.get_async_connection().await?;
-
-        conn.hset_multiple(
-            url_key,
-            &[("id", &*next_key), ("url", &*url), ("count", "0")],
-        )
-        .await?;
-        Ok(Url {
-            id: next_key.clone(),
-            url: url.parse().unwrap(),
-            count: 0,
-        })
+    #[tracing::instrument(skip(self, url), fields(domain = %url.domain))]
+    async fn generate(&self, url: &NewUrl) -> Result<Url, UrlError> {
+        self.create(url, None).await
     }
 
-    async fn get(&self, id: &str) -> Result<Url, UrlError> {
-        let redis_client = &*self.redis_client;
-        let url_key = self.get_key(&id);
-        let mut conn = redis_client.get_async_connection().await?;
+    #[tracing::instrument(skip(self))]
+    async fn get(&self, domain: &str, id: &str) -> Result<Url, UrlError> {
+        let url_key = self.get_key(domain, id);
+        let mut conn = self.connection().await?;
 
-        let res: RedisResult<Vec<String>> = conn.hget(url_key, &["id", "url", "count"]).await;
+        let res: RedisResult<HashMap<String, String>> =
+            observe_redis("hgetall", conn.hgetall(url_key)).await;
 
         match res {
-            Ok(result) => {
-                if result.len() == 3 {
-                    Ok(Url {
-                        id: result[0].clone(),
-                        url: result[1].clone(),
-                        count: result[2].parse().unwrap(),
-                    })
-                } else {
-                    Err(UrlError)
-                }
-            }
-            _ => Err(UrlError),
+            Ok(fields) if fields.is_empty() => Err(UrlError::NotFound),
+            Ok(fields) => self.parse_url(domain, id, &fields),
+            _ => Err(UrlError::Internal),
         }
     }
 
-    async fn increment_counter(&self, id: &str) -> Result<bool, UrlError> {
-        let redis_client = &*self.redis_client;
-        let url_key = self.get_key(&id);
-        redis_client
-            .get_async_connection()
-            .await?
-            .hincr(url_key, "count", 1)
+    #[tracing::instrument(skip(self))]
+    async fn increment_counter(
+        &self,
+        domain: &str,
+        id: &str,
+        variant: Option<usize>,
+    ) -> Result<bool, UrlError> {
+        let url_key = self.get_key(domain, id);
+        let mut pipe = redis::pipe();
+        pipe.hincr(&url_key, "count", 1).ignore();
+        if let Some(index) = variant {
+            pipe.hincr(&url_key, Self::variant_count_field(index), 1)
+                .ignore();
+        }
+        let mut conn = self.connection().await?;
+        observe_redis("hincrby", pipe.query_async(&mut conn))
             .await
-            .map(|_: String| true)
-            .map_err(|_| UrlError)
+            .map(|_: ()| true)
+            .map_err(|_| UrlError::Internal)
     }
 
+    #[tracing::instrument(skip(self))]
     async fn new_user(&self) -> Result<String, UrlError> {
-        let redis_client = &*self.redis_client;
-        redis_client
-            .get_async_connection()
-            .await?
-            .incr(USER_COUNTER_KEY, 1)
+        let mut conn = self.connection().await?;
+        observe_redis("incr", conn.incr(self.key(USER_COUNTER_KEY), 1))
             .await
-            .map(|result| (self.hashids.encode(&[result])))
-            .map_err(|_| UrlError)
+            .map(|result| self.hashids.encode(result))
+            .map_err(|_| UrlError::Internal)
     }
 
-    async fn generate_for_user(&self, url: &str, user: &str) -> Result<Url, UrlError> {
-        let url = self.generate(url).await;
-        match url {
-            Ok(url) => {
-                self.save_for_user(&url.id.clone(), user).await;
-                Ok(url)
-            }
-            Err(error) => Err(error),
-        }
+    #[tracing::instrument(skip(self, url), fields(domain = %url.domain))]
+    async fn generate_for_user(&self, url: &NewUrl, user: &str) -> Result<Url, UrlError> {
+        self.create(url, Some(user)).await
     }
 
+    #[tracing::instrument(skip(self))]
     async fn get_urls_for_user(&self, user: &str, start: isize, stop: isize) -> Vec<Url> {
-        let redis_client = &*self.redis_client;
-        let mut res = vec![];
-        if let Ok(mut conn) = redis_client.get_async_connection().await {
-            let ids: Vec<String> = conn
-                .zrevrange(self.get_user_key(user), start, stop - 1)
-                .await
-                .unwrap_or(vec![]);
-            for key in ids.into_iter() {
-                if let Ok(url) = self.get(&*key).await {
-                    res.push(url)
-                }
+        self.get_urls_in(self.get_user_key(user), start, stop).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn count_urls_for_user(&self, user: &str) -> Result<isize, UrlError> {
+        self.count_urls_in(self.get_user_key(user)).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn is_in_scope(&self, domain: &str, id: &str, scope: &Scope) -> Result<bool, UrlError> {
+        let mut conn = self.connection().await?;
+        let score: Option<f64> = observe_redis(
+            "zscore",
+            conn.zscore(self.get_scope_key(scope), self.get_user_member(domain, id)),
+        )
+        .await?;
+        Ok(score.is_some())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_url(&self, domain: &str, id: &str, scope: &Scope) -> Result<bool, UrlError> {
+        let member = self.get_user_member(domain, id);
+        let mut conn = self.connection().await?;
+        let script = redis::Script::new(DELETE_URL_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(self.get_scope_key(scope))
+            .key(self.get_key(domain, id))
+            .key(self.key(CHECKS_KEY))
+            .key(self.key(EXPIRATIONS_KEY))
+            .key(self.prefixed_key(CLICKS_KEY, &member))
+            .arg(&member);
+        observe_redis("delete_url", invocation.invoke_async(&mut conn))
+            .await
+            .map_err(|_| UrlError::Internal)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn claim_expired(&self, limit: usize) -> Result<Vec<(String, String)>, UrlError> {
+        let mut conn = self.connection().await?;
+        let script = redis::Script::new(CLAIM_EXPIRED_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(self.key(EXPIRATIONS_KEY))
+            .key(self.key(CHECKS_KEY))
+            .arg(unix_time())
+            .arg(limit);
+        let members: Vec<String> =
+            observe_redis("claim_expired", invocation.invoke_async(&mut conn)).await?;
+        Ok(members
+            .iter()
+            .map(|member| {
+                let (domain, id) = self.parse_user_member(member);
+                (domain.to_string(), id.to_string())
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self, url), fields(domain = %url.domain, id = %url.id))]
+    async fn save_details(&self, url: &Url) -> Result<(), UrlError> {
+        let key = self.get_key(&url.domain, &url.id);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hdel(&key, &["title", "notes", "tags", "preview"])
+            .ignore();
+        let fields = Self::detail_fields(&url.title, &url.notes, &url.tags, &url.preview)?;
+        if !fields.is_empty() {
+            pipe.hset_multiple(&key, &fields).ignore();
+        }
+        let mut conn = self.connection().await?;
+        observe_redis("save_details", pipe.query_async(&mut conn))
+            .await
+            .map_err(|_| UrlError::Internal)
+    }
+
+    #[tracing::instrument(skip(self, metadata))]
+    async fn save_metadata(
+        &self,
+        domain: &str,
+        id: &str,
+        metadata: &Metadata,
+    ) -> Result<(), UrlError> {
+        let metadata = serde_json::to_string(metadata).map_err(|_| UrlError::Internal)?;
+        let mut conn = self.connection().await?;
+        observe_redis(
+            "hset",
+            conn.hset(self.get_key(domain, id), "metadata", metadata),
+        )
+        .await
+        .map_err(|_| UrlError::Internal)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn claim_due_checks(
+        &self,
+        limit: usize,
+        lease: u64,
+    ) -> Result<Vec<(String, String)>, UrlError> {
+        let now = unix_time();
+        let mut conn = self.connection().await?;
+        let script = redis::Script::new(CLAIM_CHECKS_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(self.key(CHECKS_KEY))
+            .arg(now)
+            .arg(limit)
+            .arg(now + lease);
+        let members: Vec<String> =
+            observe_redis("claim_checks", invocation.invoke_async(&mut conn)).await?;
+        Ok(members
+            .iter()
+            .map(|member| {
+                let (domain, id) = self.parse_user_member(member);
+                (domain.to_string(), id.to_string())
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self, health))]
+    async fn save_health(
+        &self,
+        domain: &str,
+        id: &str,
+        health: &LinkHealth,
+    ) -> Result<(), UrlError> {
+        let health = serde_json::to_string(health).map_err(|_| UrlError::Internal)?;
+        let mut conn = self.connection().await?;
+        observe_redis(
+            "hset",
+            conn.hset(self.get_key(domain, id), "health", health),
+        )
+        .await
+        .map_err(|_| UrlError::Internal)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn ping(&self) -> Result<(), UrlError> {
+        let mut conn = self.connection().await?;
+        observe_redis("ping", redis::cmd("PING").query_async(&mut conn))
+            .await
+            .map(|_: String| ())
+            .map_err(|_| UrlError::Internal)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn create_workspace(&self, name: &str, owner: &str) -> Result<Workspace, UrlError> {
+        let mut conn = self.connection().await?;
+        let counter: u64 =
+            observe_redis("incr", conn.incr(self.key(WORKSPACE_COUNTER_KEY), 1)).await?;
+        let workspace = Workspace {
+            id: self.hashids.encode(counter),
+            name: name.to_string(),
+        };
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset_multiple(
+                self.get_workspace_key(&workspace.id),
+                &[("id", &workspace.id), ("name", &workspace.name)],
+            )
+            .ignore()
+            .hset(
+                self.get_members_key(&workspace.id),
+                owner,
+                Role::Owner.as_str(),
+            )
+            .ignore()
+            .sadd(self.get_user_workspaces_key(owner), &workspace.id)
+            .ignore();
+        observe_redis("create_workspace", pipe.query_async(&mut conn))
+            .await
+            .map(|_: ()| workspace)
+            .map_err(|_| UrlError::Internal)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_workspace(&self, workspace: &str) -> Result<Workspace, UrlError> {
+        let mut conn = self.connection().await?;
+        let fields: HashMap<String, String> =
+            observe_redis("hgetall", conn.hgetall(self.get_workspace_key(workspace))).await?;
+        match (fields.get("id"), fields.get("name")) {
+            (Some(id), Some(name)) => Ok(Workspace {
+                id: id.clone(),
+                name: name.clone(),
+            }),
+            _ => Err(UrlError::NotFound),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_role(&self, workspace: &str, user: &str) -> Result<Option<Role>, UrlError> {
+        let mut conn = self.connection().await?;
+        let role: Option<String> =
+            observe_redis("hget", conn.hget(self.get_members_key(workspace), user)).await?;
+        Ok(role.as_deref().and_then(Role::parse))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_member(&self, workspace: &str, user: &str, role: Role) -> Result<(), UrlError> {
+        let mut conn = self.connection().await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset(self.get_members_key(workspace), user, role.as_str())
+            .ignore()
+            .sadd(self.get_user_workspaces_key(user), workspace)
+            .ignore();
+        observe_redis("add_member", pipe.query_async(&mut conn))
+            .await
+            .map_err(|_| UrlError::Internal)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_workspaces_for_user(&self, user: &str) -> Result<Vec<Membership>, UrlError> {
+        let mut conn = self.connection().await?;
+        let ids: Vec<String> = observe_redis(
+            "smembers",
+            conn.smembers(self.get_user_workspaces_key(user)),
+        )
+        .await?;
+        let mut memberships = vec![];
+        for id in ids.iter() {
+            if let (Ok(workspace), Ok(Some(role))) =
+                (self.get_workspace(id).await, self.get_role(id, user).await)
+            {
+                memberships.push(Membership { workspace, role });
             }
         }
-        res
+        memberships.sort_by(|a, b| a.workspace.name.cmp(&b.workspace.name));
+        Ok(memberships)
     }
 
-    async fn count_urls_for_user(&self, user: &str) -> Result<isize, UrlError> {
-        let redis_client = &*self.redis_client;
-        redis_client
-            .get_async_connection()
-            .await?
-            .zcount(self.get_user_key(user), "-inf", "+inf")
+    #[tracing::instrument(skip(self))]
+    async fn create_invite(&self, workspace: &str, role: Role) -> Result<Invite, UrlError> {
+        let invite = Invite {
+            token: rand::thread_rng()
+                .sample_iter(&rand::distributions::Alphanumeric)
+                .take(INVITE_TOKEN_LENGTH)
+                .map(char::from)
+                .collect(),
+            workspace: workspace.to_string(),
+            role,
+        };
+        let key = self.get_invite_key(&invite.token);
+        let mut conn = self.connection().await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset_multiple(&key, &[("workspace", workspace), ("role", role.as_str())])
+            .ignore()
+            .expire(&key, INVITE_TTL_SECONDS)
+            .ignore();
+        observe_redis("create_invite", pipe.query_async(&mut conn))
+            .await
+            .map(|_: ()| invite)
+            .map_err(|_| UrlError::Internal)
+    }
+
+    #[tracing::instrument(skip(self, token))]
+    async fn take_invite(&self, token: &str) -> Result<Option<Invite>, UrlError> {
+        let key = self.get_invite_key(token);
+        let mut conn = self.connection().await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic().hgetall(&key).del(&key).ignore();
+        let (fields,): (HashMap<String, String>,) =
+            observe_redis("take_invite", pipe.query_async(&mut conn)).await?;
+        let role = fields.get("role").and_then(|role| Role::parse(role));
+        Ok(match (fields.get("workspace"), role) {
+            (Some(workspace), Some(role)) => Some(Invite {
+                token: token.to_string(),
+                workspace: workspace.clone(),
+                role,
+            }),
+            _ => None,
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_urls_for_workspace(&self, workspace: &str, start: isize, stop: isize) -> Vec<Url> {
+        self.get_urls_in(self.get_workspace_urls_key(workspace), start, stop)
+            .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn count_urls_for_workspace(&self, workspace: &str) -> Result<isize, UrlError> {
+        self.count_urls_in(self.get_workspace_urls_key(workspace))
+            .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn move_url(
+        &self,
+        domain: &str,
+        id: &str,
+        from: &Scope,
+        to: &Scope,
+    ) -> Result<bool, UrlError> {
+        let mut conn = self.connection().await?;
+        let script = redis::Script::new(MOVE_URL_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(self.get_scope_key(from))
+            .key(self.get_scope_key(to))
+            .arg(self.get_user_member(domain, id));
+        observe_redis("move_url", invocation.invoke_async(&mut conn))
+            .await
+            .map_err(|_| UrlError::Internal)
+    }
+
+    #[tracing::instrument(skip(self, data))]
+    async fn create_webhook(&self, user: &str, data: &CreateWebhook) -> Result<Webhook, UrlError> {
+        let token = |length| {
+            rand::thread_rng()
+                .sample_iter(&rand::distributions::Alphanumeric)
+                .take(length)
+                .map(char::from)
+                .collect()
+        };
+        let webhook = Webhook {
+            id: token(WEBHOOK_ID_LENGTH),
+            url: data.url.clone(),
+            events: data.events.clone(),
+            secret: token(WEBHOOK_SECRET_LENGTH),
+        };
+        let stored = serde_json::to_string(&webhook).map_err(|_| UrlError::Internal)?;
+        let mut conn = self.connection().await?;
+        observe_redis(
+            "hset",
+            conn.hset(self.get_webhooks_key(user), &webhook.id, stored),
+        )
+        .await
+        .map(|_: ()| webhook)
+        .map_err(|_| UrlError::Internal)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_webhooks(&self, user: &str) -> Result<Vec<Webhook>, UrlError> {
+        let mut conn = self.connection().await?;
+        let stored: HashMap<String, String> =
+            observe_redis("hgetall", conn.hgetall(self.get_webhooks_key(user))).await?;
+        let mut webhooks: Vec<Webhook> = stored
+            .values()
+            .map(|webhook| serde_json::from_str(webhook).map_err(|_| UrlError::Internal))
+            .collect::<Result<_, _>>()?;
+        webhooks.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(webhooks)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_webhook(&self, user: &str, id: &str) -> Result<bool, UrlError> {
+        let mut conn = self.connection().await?;
+        let deleted: usize =
+            observe_redis("hdel", conn.hdel(self.get_webhooks_key(user), id)).await?;
+        Ok(deleted > 0)
+    }
+
+    #[tracing::instrument(skip(self, delivery), fields(id = %delivery.id))]
+    async fn log_delivery(&self, user: &str, delivery: &Delivery) -> Result<(), UrlError> {
+        self.push_delivery(self.get_deliveries_key(user), MAX_DELIVERIES, delivery)
+            .await
+    }
+
+    #[tracing::instrument(skip(self, delivery), fields(id = %delivery.id))]
+    async fn dead_letter(&self, user: &str, delivery: &Delivery) -> Result<(), UrlError> {
+        self.push_delivery(self.get_dead_letters_key(user), MAX_DEAD_LETTERS, delivery)
+            .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_deliveries(&self, user: &str) -> Result<Vec<Delivery>, UrlError> {
+        self.get_delivery_list(self.get_deliveries_key(user)).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_dead_letters(&self, user: &str) -> Result<Vec<Delivery>, UrlError> {
+        self.get_delivery_list(self.get_dead_letters_key(user))
+            .await
+    }
+
+    #[tracing::instrument(skip(self, event), fields(id = %event.link.id))]
+    async fn add_to_daily_stats(&self, event: &Event) -> Result<(), UrlError> {
+        let day = time::OffsetDateTime::from_unix_timestamp(event.created_at as i64)
+            .date()
+            .format("%F");
+        let mut pipe = redis::pipe();
+        pipe.hincr(self.prefixed_key(STATS_KEY, &day), event.event.as_str(), 1)
+            .ignore();
+        if event.event == EventKind::LinkClicked {
+            let member = self.get_user_member(&event.link.domain, &event.link.id);
+            pipe.hincr(self.prefixed_key(CLICKS_KEY, &member), &day, 1)
+                .ignore();
+        }
+        let mut conn = self.connection().await?;
+        observe_redis("daily_stats", pipe.query_async(&mut conn))
             .await
-            .map_err(|_| UrlError)
+            .map_err(|_| UrlError::Internal)
     }
 }
 
@@ -169,15 +1063,37 @@ impl UrlRepo for RedisUrlRepoImpl {
 mod tests {
     use super::*;
     use crate::hashids;
+    use crate::redis;
+    use crate::settings::Settings;
+    use crate::urls::id_generator;
+    use crate::urls::slug_filter::SlugProblem;
+    use crate::urls::utils::BuildUrl;
     use std::sync::Arc;
 
+    const DEFAULT_DOMAIN: &str = "localhost";
+    const OTHER_DOMAIN: &str = "other.localhost";
+
     async fn setup() -> RedisUrlRepoImpl {
-        let redis_url = std::env::var("REDIS_URL").expect("REDIS_URL env var needs to be set");
-        let redis_client = redis::Client::open(redis_url).unwrap();
-        let hashids = hashids::configure().await;
+        let settings = Settings::load().expect("Tests need a valid configuration");
+        let redis_client = Arc::new(redis::configure(&settings).await);
+        let hashids = hashids::configure(&settings).await;
+        let id_generator =
+            id_generator::configure(&settings, redis_client.clone(), hashids.clone()).await;
         RedisUrlRepoImpl {
-            redis_client: Arc::new(redis_client),
+            redis_client,
+            namespace: redis::namespace(&settings),
             hashids,
+            id_generator,
+            slug_filter: SlugFilter::new(&["blocked".to_string()]),
+            domains: Domains::new(DEFAULT_DOMAIN, &[OTHER_DOMAIN]),
+        }
+    }
+
+    fn new_url(url: &str) -> NewUrl {
+        NewUrl {
+            url: url.to_string(),
+            domain: DEFAULT_DOMAIN.to_string(),
+            ..Default::default()
         }
     }
 
@@ -185,8 +1101,19 @@ mod tests {
     #[test]
     async fn test_get_key() {
         let sut = setup().await;
-        let key = sut.get_key("test_id");
+        let key = sut.get_key(DEFAULT_DOMAIN, "test_id");
         assert_eq!("url_shortener:urls:test_id", key);
+        let key = sut.get_key(OTHER_DOMAIN, "test_id");
+        assert_eq!("url_shortener:urls:other.localhost:test_id", key);
+
+        let sut = RedisUrlRepoImpl {
+            namespace: Namespace::parse("staging").unwrap(),
+            ..sut
+        };
+        let key = sut.get_key(DEFAULT_DOMAIN, "test_id");
+        assert_eq!("staging:urls:test_id", key);
+        assert_eq!("staging:users:1", sut.get_user_key("1"));
+        assert_eq!("staging:workspaces:1:members", sut.get_members_key("1"));
     }
 
     #[actix_web::main]
@@ -203,7 +1130,7 @@ mod tests {
     #[test]
     async fn test_generate() {
         let sut = setup().await;
-        let url = sut.generate("http://test.com").await.ok();
+        let url = sut.generate(&new_url("http://test.com")).await.ok();
         assert!(matches!(url, Some(_url)));
     }
 
@@ -211,24 +1138,214 @@ mod tests {
     #[test]
     async fn test_generate_and_get() {
         let sut = setup().await;
-        let unexists = sut.get("unexists").await.ok();
+        let unexists = sut.get(DEFAULT_DOMAIN, "unexists").await.ok();
         assert_eq!(None, unexists);
-        let url_1 = sut.generate("http://test.com").await.ok().unwrap();
-        let url_2 = sut.get(&url_1.id).await.ok().unwrap();
+        let url_1 = sut
+            .generate(&new_url("http://test.com"))
+            .await
+            .ok()
+            .unwrap();
+        let url_2 = sut.get(DEFAULT_DOMAIN, &url_1.id).await.ok().unwrap();
         assert_eq!(url_2, url_1);
     }
 
+    #[actix_web::main]
+    #[test]
+    async fn test_parse_url() {
+        let sut = setup().await;
+        let fields = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+        // a link of the baseline layout with an unknown field
+        let url = sut
+            .parse_url(
+                DEFAULT_DOMAIN,
+                "legacy",
+                &fields(&[("url", "http://test.com"), ("shared", "0")]),
+            )
+            .unwrap();
+        assert_eq!(url.id, "legacy");
+        assert_eq!(url.domain, DEFAULT_DOMAIN);
+        assert_eq!(url.count, 0);
+        let url = sut
+            .parse_url(
+                DEFAULT_DOMAIN,
+                "broken",
+                &fields(&[
+                    ("url", "http://test.com"),
+                    ("count", "many"),
+                    ("tags", "work"),
+                    ("variants", "["),
+                ]),
+            )
+            .unwrap();
+        assert_eq!(url.count, 0);
+        assert!(url.tags.is_empty() && url.variants.is_empty());
+        assert_eq!(
+            sut.parse_url(DEFAULT_DOMAIN, "empty", &fields(&[("count", "1")])),
+            Err(UrlError::Internal)
+        );
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_migrate() {
+        let sut = setup().await;
+        let mut conn = sut.connection().await.unwrap();
+        let id = format!("legacy-{}", sut.get_next_key().await.unwrap());
+        let key = sut.get_key(DEFAULT_DOMAIN, &id);
+        let _: () = conn.hset(&key, "url", "http://test.com").await.unwrap();
+        let created = sut.generate(&new_url("http://test.com")).await.unwrap();
+        sut.increment_counter(DEFAULT_DOMAIN, &created.id, None)
+            .await
+            .unwrap();
+
+        for migration in migrations::MIGRATIONS {
+            assert!(sut.migrate(migration).await.unwrap() >= 1);
+            // upgraded links are skipped when run again
+            let _: () = conn.hdel(&key, "count").await.unwrap();
+            sut.migrate(migration).await.unwrap();
+        }
+        let fields: HashMap<String, String> = conn.hgetall(&key).await.unwrap();
+        assert_eq!(fields.get("id"), Some(&id));
+        assert_eq!(
+            fields.get("domain").map(String::as_str),
+            Some(DEFAULT_DOMAIN)
+        );
+        assert_eq!(
+            migrations::link_version(&fields),
+            migrations::SCHEMA_VERSION
+        );
+        assert_eq!(
+            sut.schema_version().await.unwrap(),
+            migrations::SCHEMA_VERSION
+        );
+        // links written by this version are left alone
+        let url = sut.get(DEFAULT_DOMAIN, &created.id).await.unwrap();
+        assert_eq!(url.count, 1);
+        let progress: bool = conn.exists(sut.key(SCHEMA_PROGRESS_KEY)).await.unwrap();
+        assert!(!progress);
+
+        // the progress of another migration is not resumed
+        let migration = migrations::MIGRATIONS.last().unwrap();
+        let _: () = conn.hdel(&key, "schema").await.unwrap();
+        let _: () = conn
+            .hset_multiple(
+                sut.key(SCHEMA_PROGRESS_KEY),
+                &[
+                    ("version", (migration.version + 1).to_string()),
+                    ("cursor", "12345".to_string()),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(sut.migrate(migration).await.unwrap(), 1);
+        let progress: bool = conn.exists(sut.key(SCHEMA_PROGRESS_KEY)).await.unwrap();
+        assert!(!progress);
+        let _: () = conn.del(&key).await.unwrap();
+    }
+
     #[actix_web::main]
     #[test]
     async fn test_incr() {
         let sut = setup().await;
-        let url_1 = sut.generate("http://test.com").await.unwrap();
+        let url_1 = sut.generate(&new_url("http://test.com")).await.unwrap();
         assert_eq!(url_1.count, 0);
-        sut.increment_counter(&url_1.id).await.ok();
-        let url_2 = sut.get(&url_1.id).await.unwrap();
+        sut.increment_counter(DEFAULT_DOMAIN, &url_1.id, None)
+            .await
+            .ok();
+        let url_2 = sut.get(DEFAULT_DOMAIN, &url_1.id).await.unwrap();
         assert_eq!(url_2.count, 1);
     }
 
+    #[actix_web::main]
+    #[test]
+    async fn test_incr_variant() {
+        let sut = setup().await;
+        let split = NewUrl {
+            url: "http://a.com".to_string(),
+            variants: vec![
+                Variant {
+                    url: "http://a.com".to_string(),
+                    weight: 1,
+                    count: 0,
+                },
+                Variant {
+                    url: "http://b.com".to_string(),
+                    weight: 2,
+                    count: 0,
+                },
+            ],
+            domain: DEFAULT_DOMAIN.to_string(),
+            ..Default::default()
+        };
+        let url_1 = sut.generate(&split).await.unwrap();
+        sut.increment_counter(DEFAULT_DOMAIN, &url_1.id, Some(1))
+            .await
+            .ok();
+        let url_2 = sut.get(DEFAULT_DOMAIN, &url_1.id).await.unwrap();
+        assert_eq!(url_2.count, 1);
+        assert_eq!(url_2.variants[0].count, 0);
+        assert_eq!(url_2.variants[1].count, 1);
+        assert_eq!(url_2.variants[1].weight, 2);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_alias_per_domain() {
+        let sut = setup().await;
+        let alias = format!("alias-{}", sut.get_next_key().await.unwrap());
+        let aliased = |domain: &str| NewUrl {
+            url: "http://test.com".to_string(),
+            domain: domain.to_string(),
+            alias: Some(alias.clone()),
+            ..Default::default()
+        };
+        let url_1 = sut.generate(&aliased(DEFAULT_DOMAIN)).await.unwrap();
+        assert_eq!(url_1.id, alias);
+        assert_eq!(
+            sut.generate(&aliased(DEFAULT_DOMAIN)).await,
+            Err(UrlError::AliasTaken)
+        );
+        let url_2 = sut.generate(&aliased(OTHER_DOMAIN)).await.unwrap();
+        assert_eq!(
+            url_2.build_url(),
+            format!("https://{}/{}", OTHER_DOMAIN, alias)
+        );
+        assert_eq!(sut.get(OTHER_DOMAIN, &alias).await.unwrap(), url_2);
+        assert_eq!(sut.get(DEFAULT_DOMAIN, &alias).await.unwrap(), url_1);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_alias_not_allowed() {
+        let sut = setup().await;
+        let aliased = |alias: &str| NewUrl {
+            url: "http://test.com".to_string(),
+            domain: DEFAULT_DOMAIN.to_string(),
+            alias: Some(alias.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            sut.generate(&aliased("metrics")).await,
+            Err(UrlError::AliasNotAllowed(SlugProblem::Reserved))
+        );
+        assert_eq!(
+            sut.generate(&aliased("is-Blocked-here")).await,
+            Err(UrlError::AliasNotAllowed(SlugProblem::Denied))
+        );
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_ping() {
+        let sut = setup().await;
+        assert_eq!(sut.ping().await, Ok(()));
+    }
+
     #[actix_web::main]
     #[test]
     async fn test_new_user() {
@@ -243,9 +1360,36 @@ mod tests {
     #[test]
     async fn test_generate_for_user() {
         let sut = setup().await;
-        let url = sut.generate_for_user("http://test.com", "user").await.ok();
+        let url = sut
+            .generate_for_user(&new_url("http://test.com"), "user")
+            .await
+            .ok();
         assert_ne!(None, url);
-        assert!(matches!(url, Some(url) if url.url == "http://test.com"))
+        assert!(matches!(&url, Some(url) if url.url == "http://test.com"));
+
+        // the owner is stored with the link
+        let url = url.unwrap();
+        assert_eq!(url.owner.as_deref(), Some("user"));
+        assert_eq!(sut.get(DEFAULT_DOMAIN, &url.id).await, Ok(url.clone()));
+        let scope = Scope::User("user".to_string());
+        assert_eq!(
+            sut.is_in_scope(DEFAULT_DOMAIN, &url.id, &scope).await,
+            Ok(true)
+        );
+        // a taken alias adds nothing to the user's links
+        let aliased = NewUrl {
+            alias: Some(url.id.clone()),
+            ..new_url("http://test.com")
+        };
+        let scope = Scope::User("alias_user".to_string());
+        assert_eq!(
+            sut.generate_for_user(&aliased, "alias_user").await,
+            Err(UrlError::AliasTaken)
+        );
+        assert_eq!(
+            sut.is_in_scope(DEFAULT_DOMAIN, &url.id, &scope).await,
+            Ok(false)
+        );
     }
 
     #[actix_web::main]
@@ -264,11 +1408,11 @@ mod tests {
             .await
             .ok();
         let url_1 = sut
-            .generate_for_user("http://test.com", user)
+            .generate_for_user(&new_url("http://test.com"), user)
             .await
             .unwrap();
         let url_2 = sut
-            .generate_for_user("http://test.com", user)
+            .generate_for_user(&new_url("http://test.com"), user)
             .await
             .unwrap();
         let res = sut.get_urls_for_user(user, 0, 1).await;
@@ -277,6 +1421,174 @@ mod tests {
         assert_eq!(res, vec![url_2.clone(), url_1.clone()]);
     }
 
+    #[actix_web::main]
+    #[test]
+    async fn test_details() {
+        let sut = setup().await;
+        let mut new = new_url("http://test.com");
+        new.title = Some("Test".to_string());
+        new.tags = vec!["work".to_string()];
+        new.preview = Some(Preview {
+            image: Some("https://test.com/card.png".to_string()),
+            ..Default::default()
+        });
+        let mut url = sut.generate_for_user(&new, "details_user").await.unwrap();
+        assert_eq!(sut.get(DEFAULT_DOMAIN, &url.id).await, Ok(url.clone()));
+
+        let scope = Scope::User("details_user".to_string());
+        assert_eq!(
+            sut.is_in_scope(DEFAULT_DOMAIN, &url.id, &scope).await,
+            Ok(true)
+        );
+        let other = Scope::User("other_user".to_string());
+        assert_eq!(
+            sut.is_in_scope(DEFAULT_DOMAIN, &url.id, &other).await,
+            Ok(false)
+        );
+
+        url.title = None;
+        url.notes = Some("Notes".to_string());
+        url.tags = vec![];
+        url.preview = None;
+        sut.save_details(&url).await.unwrap();
+        assert_eq!(sut.get(DEFAULT_DOMAIN, &url.id).await, Ok(url));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_webhooks() {
+        let user = "webhooks_user";
+        let sut = setup().await;
+        for webhook in sut.get_webhooks(user).await.unwrap() {
+            sut.delete_webhook(user, &webhook.id).await.unwrap();
+        }
+        let data = CreateWebhook {
+            url: "https://hooks.example.com".to_string(),
+            events: vec![EventKind::LinkClicked],
+        };
+        let webhook = sut.create_webhook(user, &data).await.unwrap();
+        assert_eq!(webhook.secret.len(), WEBHOOK_SECRET_LENGTH);
+        assert_eq!(sut.get_webhooks(user).await, Ok(vec![webhook.clone()]));
+
+        let delivery = Delivery {
+            id: "delivery".to_string(),
+            webhook: webhook.id.clone(),
+            event: EventKind::LinkClicked,
+            payload: "{}".to_string(),
+            attempt: 1,
+            status: Some(500),
+            error: None,
+            delivered: false,
+            attempted_at: 1,
+        };
+        sut.log_delivery(user, &delivery).await.unwrap();
+        sut.dead_letter(user, &delivery).await.unwrap();
+        assert_eq!(sut.get_deliveries(user).await.unwrap()[0], delivery);
+        assert_eq!(sut.get_dead_letters(user).await.unwrap()[0], delivery);
+
+        assert_eq!(sut.delete_webhook(user, &webhook.id).await, Ok(true));
+        assert_eq!(sut.delete_webhook(user, &webhook.id).await, Ok(false));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_link_checks() {
+        let sut = setup().await;
+        let mut url = sut
+            .generate_for_user(&new_url("http://test.com"), "checks_user")
+            .await
+            .unwrap();
+        let member = (DEFAULT_DOMAIN.to_string(), url.id.clone());
+        let due = sut.claim_due_checks(10_000, 60).await.unwrap();
+        assert!(due.contains(&member));
+        // claimed links are not due again until the lease runs out
+        let due = sut.claim_due_checks(10_000, 60).await.unwrap();
+        assert!(!due.contains(&member));
+
+        let health = LinkHealth {
+            status: Some(404),
+            redirects: vec!["https://test.com/".to_string()],
+            checked_at: 1,
+            broken: true,
+            ..Default::default()
+        };
+        sut.save_health(DEFAULT_DOMAIN, &url.id, &health)
+            .await
+            .unwrap();
+        url.health = Some(health);
+        assert_eq!(sut.get(DEFAULT_DOMAIN, &url.id).await, Ok(url));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_delete_url() {
+        let sut = setup().await;
+        let mut new = new_url("http://test.com");
+        new.expires_at = Some(unix_time() + 3600);
+        let url = sut.generate_for_user(&new, "delete_user").await.unwrap();
+        sut.add_to_daily_stats(&Event::new(EventKind::LinkClicked, &url, None))
+            .await
+            .unwrap();
+
+        let other = Scope::User("other_user".to_string());
+        assert_eq!(
+            sut.delete_url(DEFAULT_DOMAIN, &url.id, &other).await,
+            Ok(false)
+        );
+        assert_eq!(sut.get(DEFAULT_DOMAIN, &url.id).await, Ok(url.clone()));
+
+        let scope = Scope::User("delete_user".to_string());
+        assert_eq!(
+            sut.delete_url(DEFAULT_DOMAIN, &url.id, &scope).await,
+            Ok(true)
+        );
+        assert_eq!(
+            sut.get(DEFAULT_DOMAIN, &url.id).await,
+            Err(UrlError::NotFound)
+        );
+        assert_eq!(
+            sut.is_in_scope(DEFAULT_DOMAIN, &url.id, &scope).await,
+            Ok(false)
+        );
+        let member = sut.get_user_member(DEFAULT_DOMAIN, &url.id);
+        let mut conn = sut.connection().await.unwrap();
+        let clicks: bool = conn
+            .exists(sut.prefixed_key(CLICKS_KEY, &member))
+            .await
+            .unwrap();
+        assert!(!clicks);
+        for key in [CHECKS_KEY, EXPIRATIONS_KEY] {
+            let score: Option<f64> = conn.zscore(sut.key(key), &member).await.unwrap();
+            assert_eq!(score, None);
+        }
+        assert_eq!(
+            sut.delete_url(DEFAULT_DOMAIN, &url.id, &scope).await,
+            Ok(false)
+        );
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_claim_expired() {
+        let sut = setup().await;
+        let mut new = new_url("http://test.com");
+        new.expires_at = Some(1);
+        let url = sut.generate_for_user(&new, "expiry_user").await.unwrap();
+        assert_eq!(sut.get(DEFAULT_DOMAIN, &url.id).await, Ok(url.clone()));
+        let later = sut
+            .generate_for_user(&new_url("http://test.com"), "expiry_user")
+            .await
+            .unwrap();
+
+        let member = (DEFAULT_DOMAIN.to_string(), url.id.clone());
+        let expired = sut.claim_expired(10_000).await.unwrap();
+        assert!(expired.contains(&member));
+        assert!(!expired.contains(&(DEFAULT_DOMAIN.to_string(), later.id)));
+        // every expiry is claimed once, the link itself is kept
+        assert!(!sut.claim_expired(10_000).await.unwrap().contains(&member));
+        assert_eq!(sut.get(DEFAULT_DOMAIN, &url.id).await, Ok(url));
+    }
+
     #[actix_web::main]
     #[test]
     async fn test_count_for_user() {
@@ -292,9 +1604,69 @@ mod tests {
             .del(user_key)
             .await
             .ok();
-        let _ = sut.generate_for_user("http://test.com", user).await;
-        let _ = sut.generate_for_user("http://test.com", user).await;
+        let _ = sut
+            .generate_for_user(&new_url("http://test.com"), user)
+            .await;
+        let _ = sut
+            .generate_for_user(&new_url("http://test.com"), user)
+            .await;
         let res = sut.count_urls_for_user(user).await.unwrap();
         assert_eq!(res, 2);
     }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_workspaces() {
+        let sut = setup().await;
+        let owner = sut.new_user().await.unwrap();
+        let member = sut.new_user().await.unwrap();
+        let workspace = sut.create_workspace("Team", &owner).await.unwrap();
+        assert_eq!(sut.get_workspace(&workspace.id).await.unwrap(), workspace);
+        assert_eq!(
+            sut.get_role(&workspace.id, &owner).await.unwrap(),
+            Some(Role::Owner)
+        );
+        assert_eq!(sut.get_role(&workspace.id, &member).await.unwrap(), None);
+
+        let invite = sut
+            .create_invite(&workspace.id, Role::Editor)
+            .await
+            .unwrap();
+        assert_eq!(
+            sut.take_invite(&invite.token).await.unwrap(),
+            Some(invite.clone())
+        );
+        assert_eq!(sut.take_invite(&invite.token).await.unwrap(), None);
+        sut.add_member(&workspace.id, &member, invite.role)
+            .await
+            .unwrap();
+        let memberships = sut.get_workspaces_for_user(&member).await.unwrap();
+        assert_eq!(
+            memberships,
+            vec![Membership {
+                workspace: workspace.clone(),
+                role: Role::Editor
+            }]
+        );
+
+        let url = sut
+            .generate_for_user(&new_url("http://test.com"), &owner)
+            .await
+            .unwrap();
+        let personal = Scope::User(owner.clone());
+        let team = Scope::Workspace(workspace.id.clone());
+        assert!(sut
+            .move_url(&url.domain, &url.id, &personal, &team)
+            .await
+            .unwrap());
+        assert!(!sut
+            .move_url(&url.domain, &url.id, &personal, &team)
+            .await
+            .unwrap());
+        assert_eq!(sut.count_urls_for_user(&owner).await.unwrap(), 0);
+        assert_eq!(
+            sut.get_urls_for_workspace(&workspace.id, 0, 25).await,
+            vec![url]
+        );
+    }
 }
@@ -0,0 +1,168 @@
+use crate::settings::Settings;
+
+/// Paths served by the application or likely to be in the future.
+const RESERVED_WORDS: &[&str] = &[
+    "about",
+    "admin",
+    "api",
+    "app",
+    "assets",
+    "healthz",
+    "help",
+    "invites",
+    "login",
+    "logout",
+    "metrics",
+    "readyz",
+    "settings",
+    "signup",
+    "static",
+    "stats",
+    "webhooks",
+    "workspaces",
+    "www",
+];
+
+/// Matched against whole words of a slug, so `grape` or `peacock` stay allowed.
+const PROFANITY: &[&str] = &[
+    "bitch", "bollocks", "boner", "cock", "cunt", "dick", "dildo", "fag", "fuck", "jizz", "nazi",
+    "nigger", "penis", "piss", "porn", "pussy", "rape", "shit", "slut", "tits", "twat", "vagina",
+    "wank", "whore",
+];
+
+/// Endings under which a denied word is still recognised, e.g. `dicks` or `fucking`.
+const SUFFIXES: &[&str] = &["s", "es", "er", "ers", "ed", "ing", "y"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlugProblem {
+    Reserved,
+    Denied,
+}
+
+/// Decides which slugs may be used as link ids, applies to generated ids and
+/// custom aliases alike. Matching is case-insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct SlugFilter {
+    /// Configured words, matched like the built-in profanity list.
+    denied: Vec<String>,
+}
+
+/// Lowercase words of a slug. Words are separated by `-`, `_`, other punctuation,
+/// letter/digit boundaries and camel case, `myFuckLink2` has four of them.
+fn words(slug: &str) -> Vec<String> {
+    let mut words: Vec<String> = vec![];
+    let mut previous: Option<char> = None;
+    for c in slug.chars() {
+        if !c.is_alphanumeric() {
+            previous = None;
+            continue;
+        }
+        let boundary = match previous {
+            None => true,
+            Some(p) => p.is_numeric() != c.is_numeric() || (p.is_lowercase() && c.is_uppercase()),
+        };
+        if boundary {
+            words.push(String::new());
+        }
+        if let Some(word) = words.last_mut() {
+            word.extend(c.to_lowercase());
+        }
+        previous = Some(c);
+    }
+    words
+}
+
+fn is_denied_word(word: &str, denied: &str) -> bool {
+    word == denied
+        || SUFFIXES
+            .iter()
+            .any(|suffix| word.strip_suffix(suffix) == Some(denied))
+}
+
+/// Whether the words of `denied` occur as consecutive words of the slug,
+/// configured entries like `acme-corp` span several words.
+fn contains_denied(words: &[String], denied: &str) -> bool {
+    let denied = self::words(denied);
+    match denied.split_last() {
+        Some((last, init)) => words.windows(denied.len()).any(|window| {
+            window[..init.len()] == *init && is_denied_word(&window[init.len()], last)
+        }),
+        None => false,
+    }
+}
+
+impl SlugFilter {
+    pub fn new(denied: &[String]) -> SlugFilter {
+        SlugFilter {
+            denied: denied.iter().map(|word| word.to_lowercase()).collect(),
+        }
+    }
+
+    pub fn check(&self, slug: &str) -> Result<(), SlugProblem> {
+        if RESERVED_WORDS.contains(&slug.to_lowercase().as_str()) {
+            return Err(SlugProblem::Reserved);
+        }
+        let words = words(slug);
+        let denied = PROFANITY
+            .iter()
+            .copied()
+            .chain(self.denied.iter().map(|word| &**word))
+            .any(|denied| contains_denied(&words, denied));
+        if denied {
+            return Err(SlugProblem::Denied);
+        }
+        Ok(())
+    }
+}
+
+pub async fn configure(settings: &Settings) -> SlugFilter {
+    SlugFilter::new(&settings.deny_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let sut = SlugFilter::new(&["Acme".to_string()]);
+        assert_eq!(sut.check("my-link"), Ok(()));
+        assert_eq!(sut.check("Static"), Err(SlugProblem::Reserved));
+        assert_eq!(sut.check("metrics-2020"), Ok(()));
+        assert_eq!(sut.check("FUCK"), Err(SlugProblem::Denied));
+        assert_eq!(sut.check("my_fucking_link"), Err(SlugProblem::Denied));
+        assert_eq!(sut.check("myDickLink"), Err(SlugProblem::Denied));
+        assert_eq!(sut.check("shit2020"), Err(SlugProblem::Denied));
+        assert_eq!(sut.check("not-acme-links"), Err(SlugProblem::Denied));
+        assert_eq!(sut.check("acmes"), Err(SlugProblem::Denied));
+    }
+
+    #[test]
+    fn test_check_innocent_words() {
+        let sut = SlugFilter::new(&["acme-corp".to_string()]);
+        for slug in [
+            "grape",
+            "brave-grape-42",
+            "peacock",
+            "dickens",
+            "therapist",
+            "scunthorpe",
+            "cocktail-bar",
+            "shiitake",
+            "acme",
+            "corp-acme",
+        ] {
+            assert_eq!(sut.check(slug), Ok(()), "{}", slug);
+        }
+        assert_eq!(sut.check("Acme-Corp-2020"), Err(SlugProblem::Denied));
+    }
+
+    #[test]
+    fn test_words() {
+        assert_eq!(
+            words("my-FuckLink_2020x"),
+            vec!["my", "fuck", "link", "2020", "x"]
+        );
+        assert_eq!(words("--"), Vec::<String>::new());
+    }
+}
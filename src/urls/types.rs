@@ -1,15 +1,206 @@
 use super::error::UrlError;
-use crate::urls::utils::BuildUrl;
+use crate::urls::utils::{unix_time, BuildUrl};
 use async_trait::async_trait;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::vec::Vec;
-use validator::Validate;
+use validator::{Validate, ValidationError};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct Variant {
+    pub url: String,
+    pub weight: u32,
+    pub count: u64,
+}
+
+/// Description of a destination page, taken from its `<head>`.
+/// Urls are absolute http(s) urls.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct Metadata {
+    /// Open Graph title, or the page title.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub favicon: Option<String>,
+}
+
+/// Social preview card of a link as shown by chat apps and social networks.
+/// Set fields override the metadata of the destination.
+#[derive(Debug, Validate, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct Preview {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(length(max = 200, message = "Title must be at most 200 characters long"))]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(length(max = 500, message = "Description must be at most 500 characters long"))]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[validate(custom = "validate_image")]
+    pub image: Option<String>,
+}
+
+impl Preview {
+    /// Blank fields removed, `None` when nothing is left.
+    fn normalize(&self) -> Option<Preview> {
+        let preview = Preview {
+            title: self.title.as_deref().and_then(non_empty),
+            description: self.description.as_deref().and_then(non_empty),
+            image: self.image.as_deref().and_then(non_empty),
+        };
+        Some(preview).filter(|preview| preview != &Preview::default())
+    }
+}
+
+/// Result of the latest check of a destination.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct LinkHealth {
+    /// Status of the final response, `None` when no response was received.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Urls the destination redirected to, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redirects: Vec<String>,
+    /// Unix timestamp in seconds.
+    pub checked_at: u64,
+    pub broken: bool,
+}
+
+impl LinkHealth {
+    /// Missing pages, server errors and failed requests. Statuses sites
+    /// commonly send to bots, like 401, 403 and 429, don't count.
+    pub fn is_broken_status(status: u16) -> bool {
+        status >= 400 && !matches!(status, 401 | 403 | 429)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
 pub struct Url {
     pub id: String,
     pub url: String,
     pub count: u64,
+    /// Weighted destinations of a split link, the first one is always `url`.
+    /// Empty for links with a single destination.
+    #[serde(default)]
+    pub variants: Vec<Variant>,
+    /// Domain the link is served on.
+    #[serde(default)]
+    pub domain: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    /// Lowercase, without duplicates.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Fetched in the background after the link is created.
+    #[serde(default)]
+    pub metadata: Option<Metadata>,
+    #[serde(default)]
+    pub preview: Option<Preview>,
+    #[serde(default)]
+    pub health: Option<LinkHealth>,
+    /// User that created the link, `None` for links created before owners were stored.
+    #[serde(default, skip_serializing)]
+    pub owner: Option<String>,
+    /// Unix timestamp in seconds, the link stops redirecting from then on.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+impl Url {
+    /// Card shown to link preview crawlers, overrides first, then the destination
+    /// metadata, then the title of the link.
+    pub fn preview_card(&self) -> Preview {
+        let preview = self.preview.clone().unwrap_or_default();
+        let metadata = self.metadata.clone().unwrap_or_default();
+        Preview {
+            title: preview
+                .title
+                .or(metadata.title)
+                .or_else(|| self.title.clone()),
+            description: preview.description.or(metadata.description),
+            image: preview.image.or(metadata.image),
+        }
+    }
+
+    /// Host of the destination without port, `None` for malformed urls.
+    pub fn destination_host(&self) -> Option<&str> {
+        let rest = self.url.split_once("://")?.1;
+        let authority = rest.split(['/', '?', '#']).next()?;
+        let host = authority.rsplit('@').next()?;
+        let host = match host.rfind(':') {
+            Some(index) if !host.ends_with(']') => &host[..index],
+            _ => host,
+        };
+        Some(host).filter(|host| !host.is_empty())
+    }
+
+    /// Picks a variant for a new visitor proportionally to the weights.
+    pub fn pick_variant(&self) -> Option<usize> {
+        let total: u32 = self.variants.iter().map(|variant| variant.weight).sum();
+        if total == 0 {
+            return None;
+        }
+        self.variant_at(rand::thread_rng().gen_range(0..total))
+    }
+
+    fn variant_at(&self, mut roll: u32) -> Option<usize> {
+        for (index, variant) in self.variants.iter().enumerate() {
+            if roll < variant.weight {
+                return Some(index);
+            }
+            roll -= variant.weight;
+        }
+        None
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn destination(&self, variant: Option<usize>) -> &str {
+        variant
+            .and_then(|index| self.variants.get(index))
+            .map(|variant| &*variant.url)
+            .unwrap_or(&self.url)
+    }
+}
+
+/// Destination chosen for a single visit of a short link.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Visit {
+    pub url: Url,
+    pub variant: Option<usize>,
+}
+
+impl Visit {
+    pub fn location(&self) -> &str {
+        self.url.destination(self.variant)
+    }
+}
+
+/// Everything needed to store a new short link.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct NewUrl {
+    pub url: String,
+    pub variants: Vec<Variant>,
+    pub domain: String,
+    /// Custom slug instead of a generated id.
+    pub alias: Option<String>,
+    pub title: Option<String>,
+    pub notes: Option<String>,
+    pub tags: Vec<String>,
+    pub preview: Option<Preview>,
+    pub expires_at: Option<u64>,
 }
 
 #[derive(Serialize)]
@@ -18,6 +209,21 @@ pub struct ResponseUrl {
     pub short_url: String,
     pub long_url: String,
     pub count: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<Variant>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview: Option<Preview>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health: Option<LinkHealth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
 }
 
 impl From<Url> for ResponseUrl {
@@ -26,15 +232,221 @@ impl From<Url> for ResponseUrl {
             id: url.id.clone(),
             short_url: url.build_url(),
             long_url: url.url.clone(),
-            count: url.count.clone(),
+            count: url.count,
+            variants: url.variants,
+            title: url.title,
+            notes: url.notes,
+            tags: url.tags,
+            metadata: url.metadata,
+            preview: url.preview,
+            health: url.health,
+            expires_at: url.expires_at,
+        }
+    }
+}
+
+/// Public statistics of a link, without the owner's title, notes and tags.
+#[derive(Serialize)]
+pub struct UrlStats {
+    pub id: String,
+    pub short_url: String,
+    pub long_url: String,
+    pub count: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<Variant>,
+}
+
+impl From<Url> for UrlStats {
+    fn from(url: Url) -> Self {
+        UrlStats {
+            id: url.id.clone(),
+            short_url: url.build_url(),
+            long_url: url.url.clone(),
+            count: url.count,
+            variants: url.variants,
         }
     }
 }
 
 #[derive(Debug, Validate, Deserialize, Serialize)]
+pub struct CreateVariant {
+    #[validate(url(message = "Enter valid url"))]
+    pub url: String,
+    #[validate(range(min = 1, max = 1000, message = "Weight must be between 1 and 1000"))]
+    pub weight: u32,
+}
+
+#[derive(Debug, Validate, Deserialize, Serialize, Default)]
 pub struct CreateUrl {
     #[validate(url(message = "Enter valid url"))]
     pub url: String,
+    /// Weight of `url` when the link is split between several destinations.
+    #[validate(range(min = 1, max = 1000, message = "Weight must be between 1 and 1000"))]
+    pub weight: Option<u32>,
+    /// Additional destinations competing with `url`.
+    #[serde(default)]
+    #[validate]
+    pub variants: Vec<CreateVariant>,
+    /// One of the configured domains, the default domain when omitted.
+    pub domain: Option<String>,
+    #[validate(custom = "validate_alias")]
+    pub alias: Option<String>,
+    #[validate(length(max = 200, message = "Title must be at most 200 characters long"))]
+    pub title: Option<String>,
+    #[validate(length(max = 2000, message = "Notes must be at most 2000 characters long"))]
+    pub notes: Option<String>,
+    #[serde(default)]
+    #[validate(custom = "validate_tags")]
+    pub tags: Vec<String>,
+    #[validate]
+    pub preview: Option<Preview>,
+    /// Unix timestamp in seconds after which the link stops redirecting.
+    #[validate(custom = "validate_expires_at")]
+    pub expires_at: Option<u64>,
+}
+
+/// Changes to the description of an existing link, omitted fields are kept
+/// and empty strings clear them.
+#[derive(Debug, Validate, Deserialize, Serialize, Default, PartialEq, Eq, Clone)]
+pub struct UpdateUrl {
+    #[validate(length(max = 200, message = "Title must be at most 200 characters long"))]
+    pub title: Option<String>,
+    #[validate(length(max = 2000, message = "Notes must be at most 2000 characters long"))]
+    pub notes: Option<String>,
+    #[validate(custom = "validate_tags")]
+    pub tags: Option<Vec<String>>,
+    /// Replaces the whole preview card.
+    #[validate]
+    pub preview: Option<Preview>,
+}
+
+impl UpdateUrl {
+    pub fn apply(&self, url: &mut Url) {
+        if let Some(title) = &self.title {
+            url.title = non_empty(title);
+        }
+        if let Some(notes) = &self.notes {
+            url.notes = non_empty(notes);
+        }
+        if let Some(tags) = &self.tags {
+            url.tags = normalize_tags(tags);
+        }
+        if let Some(preview) = &self.preview {
+            url.preview = preview.normalize();
+        }
+    }
+}
+
+const MAX_TAGS: usize = 10;
+const TAG_MAX_LENGTH: usize = 32;
+
+fn validate_tags(tags: &[String]) -> Result<(), ValidationError> {
+    let valid = tags.len() <= MAX_TAGS
+        && tags.iter().all(|tag| {
+            let tag = tag.trim();
+            !tag.is_empty()
+                && tag.len() <= TAG_MAX_LENGTH
+                && tag
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+    if valid {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("tags");
+        error.message =
+            Some("Up to 10 tags of 1-32 letters, digits, '-' and '_' are allowed".into());
+        Err(error)
+    }
+}
+
+const MAX_IMAGE_URL_LENGTH: usize = 2048;
+
+fn validate_image(image: &str) -> Result<(), ValidationError> {
+    let image = image.trim();
+    let is_http = image.starts_with("http://") || image.starts_with("https://");
+    if image.is_empty() || (is_http && image.len() <= MAX_IMAGE_URL_LENGTH) {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("image");
+        error.message = Some("Image must be an http(s) url".into());
+        Err(error)
+    }
+}
+
+fn normalize_tags(tags: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = vec![];
+    for tag in tags.iter().map(|tag| tag.trim().to_lowercase()) {
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    normalized
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    Some(value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+fn validate_expires_at(expires_at: u64) -> Result<(), ValidationError> {
+    if expires_at > unix_time() {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("expires_at");
+        error.message = Some("Expiry must be in the future".into());
+        Err(error)
+    }
+}
+
+const ALIAS_MIN_LENGTH: usize = 3;
+const ALIAS_MAX_LENGTH: usize = 64;
+
+fn validate_alias(alias: &str) -> Result<(), ValidationError> {
+    let valid_chars = alias
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid_chars && (ALIAS_MIN_LENGTH..=ALIAS_MAX_LENGTH).contains(&alias.len()) {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("alias");
+        error.message = Some(
+            "Alias must be 3-64 characters long and contain only letters, digits, '-' and '_'"
+                .into(),
+        );
+        Err(error)
+    }
+}
+
+impl From<CreateUrl> for NewUrl {
+    fn from(create: CreateUrl) -> Self {
+        let variants = if create.variants.is_empty() {
+            vec![]
+        } else {
+            let control = CreateVariant {
+                url: create.url.clone(),
+                weight: create.weight.unwrap_or(1),
+            };
+            std::iter::once(control)
+                .chain(create.variants)
+                .map(|variant| Variant {
+                    url: variant.url,
+                    weight: variant.weight,
+                    count: 0,
+                })
+                .collect()
+        };
+        NewUrl {
+            url: create.url,
+            variants,
+            domain: create.domain.unwrap_or_default(),
+            alias: create.alias,
+            title: create.title.as_deref().and_then(non_empty),
+            notes: create.notes.as_deref().and_then(non_empty),
+            tags: normalize_tags(&create.tags),
+            preview: create.preview.as_ref().and_then(Preview::normalize),
+            expires_at: create.expires_at,
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -42,12 +454,105 @@ pub struct RedirectParams {
     pub id: String,
 }
 
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Sort {
+    #[default]
+    Newest,
+    MostClicked,
+}
+
+/// Filters and order of a link listing.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Default)]
+pub struct UrlQuery {
+    pub tag: Option<String>,
+    /// Destination host, subdomains match too.
+    pub destination: Option<String>,
+    /// Case-insensitive substring of the url, id, title, notes or tags.
+    pub q: Option<String>,
+    /// Only links whose latest check failed, or only the others.
+    pub broken: Option<bool>,
+    #[serde(default)]
+    pub sort: Sort,
+}
+
+impl UrlQuery {
+    /// Listing in storage order, which can be paginated without loading every link.
+    pub fn is_default(&self) -> bool {
+        self.tag.is_none()
+            && self.destination.is_none()
+            && self.q.is_none()
+            && self.broken.is_none()
+            && self.sort == Sort::Newest
+    }
+
+    pub fn matches(&self, url: &Url) -> bool {
+        let tag = match &self.tag {
+            Some(tag) => url
+                .tags
+                .iter()
+                .any(|url_tag| url_tag.eq_ignore_ascii_case(tag)),
+            None => true,
+        };
+        let destination = match &self.destination {
+            Some(destination) => url.destination_host().is_some_and(|host| {
+                let host = host.to_lowercase();
+                let destination = destination.to_lowercase();
+                host == destination || host.ends_with(&format!(".{}", destination))
+            }),
+            None => true,
+        };
+        let text = match &self.q {
+            Some(q) => {
+                let q = q.to_lowercase();
+                std::iter::once(&url.url)
+                    .chain(std::iter::once(&url.id))
+                    .chain(url.title.iter())
+                    .chain(url.notes.iter())
+                    .chain(url.tags.iter())
+                    .any(|value| value.to_lowercase().contains(&q))
+            }
+            None => true,
+        };
+        let broken = match self.broken {
+            Some(broken) => url.health.as_ref().is_some_and(|health| health.broken) == broken,
+            None => true,
+        };
+        tag && destination && text && broken
+    }
+}
+
+/// Query of link listings, blank filters are ignored.
 #[derive(Deserialize)]
 pub struct PageParams {
     pub page: Option<isize>,
+    pub tag: Option<String>,
+    pub destination: Option<String>,
+    pub q: Option<String>,
+    pub broken: Option<bool>,
+    pub sort: Option<Sort>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl PageParams {
+    pub fn query(&self) -> UrlQuery {
+        let filter = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        UrlQuery {
+            tag: filter(&self.tag),
+            destination: filter(&self.destination),
+            q: filter(&self.q),
+            broken: self.broken,
+            sort: self.sort.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Paginated<T> {
     pub total: isize,
     pub page_count: isize,
@@ -56,6 +561,18 @@ pub struct Paginated<T> {
     pub results: Vec<T>,
 }
 
+impl<T> Paginated<T> {
+    pub fn empty() -> Paginated<T> {
+        Paginated {
+            total: 0,
+            page_count: 0,
+            next: None,
+            prev: None,
+            results: vec![],
+        }
+    }
+}
+
 impl From<Paginated<Url>> for Paginated<ResponseUrl> {
     fn from(paginated: Paginated<Url>) -> Self {
         Paginated {
@@ -72,23 +589,392 @@ impl From<Paginated<Url>> for Paginated<ResponseUrl> {
     }
 }
 
+/// Member roles, ordered by the permissions they grant.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Lists the workspace links.
+    Viewer,
+    /// Moves links in and out of the workspace.
+    Editor,
+    /// Invites members.
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "viewer" => Some(Role::Viewer),
+            "editor" => Some(Role::Editor),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct Workspace {
+    pub id: String,
+    pub name: String,
+}
+
+/// Workspace as seen by one of its members.
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+pub struct Membership {
+    #[serde(flatten)]
+    pub workspace: Workspace,
+    pub role: Role,
+}
+
+/// Set of links a link belongs to, personal links or those of a workspace.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Scope {
+    User(String),
+    Workspace(String),
+}
+
+#[derive(Debug, Validate, Deserialize, Serialize)]
+pub struct CreateWorkspace {
+    #[validate(length(min = 1, max = 100, message = "Name must be 1-100 characters long"))]
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreateInvite {
+    pub role: Role,
+}
+
+/// Single use token granting a role in a workspace.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct Invite {
+    pub token: String,
+    pub workspace: String,
+    pub role: Role,
+}
+
+/// Target of a link move, `None` stands for the personal links of the caller.
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct MoveUrl {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// Link events webhooks can subscribe to.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum EventKind {
+    #[serde(rename = "link.created")]
+    LinkCreated,
+    #[serde(rename = "link.clicked")]
+    LinkClicked,
+    /// A link check found the destination broken while it was fine before.
+    #[serde(rename = "link.broken")]
+    LinkBroken,
+    #[serde(rename = "link.deleted")]
+    LinkDeleted,
+    /// The expiry of the link passed, it no longer redirects.
+    #[serde(rename = "link.expired")]
+    LinkExpired,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::LinkCreated => "link.created",
+            EventKind::LinkClicked => "link.clicked",
+            EventKind::LinkBroken => "link.broken",
+            EventKind::LinkDeleted => "link.deleted",
+            EventKind::LinkExpired => "link.expired",
+        }
+    }
+
+    pub fn parse(event: &str) -> Option<EventKind> {
+        match event {
+            "link.created" => Some(EventKind::LinkCreated),
+            "link.clicked" => Some(EventKind::LinkClicked),
+            "link.broken" => Some(EventKind::LinkBroken),
+            "link.deleted" => Some(EventKind::LinkDeleted),
+            "link.expired" => Some(EventKind::LinkExpired),
+            _ => None,
+        }
+    }
+}
+
+/// Link of an event as sent to webhooks.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct EventLink {
+    pub domain: String,
+    pub id: String,
+    pub url: String,
+    pub short_url: String,
+}
+
+/// Something that happened to a link.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct Event {
+    pub event: EventKind,
+    /// User that created the link, receives the event on their webhooks.
+    /// Not part of webhook payloads.
+    #[serde(skip)]
+    pub owner: Option<String>,
+    /// Unix timestamp in seconds.
+    pub created_at: u64,
+    pub link: EventLink,
+    /// Index of the destination a click was sent to, for split links.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<usize>,
+}
+
+impl Event {
+    pub fn new(event: EventKind, url: &Url, variant: Option<usize>) -> Event {
+        Event {
+            event,
+            owner: url.owner.clone(),
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            link: EventLink {
+                domain: url.domain.clone(),
+                id: url.id.clone(),
+                url: url.url.clone(),
+                short_url: url.build_url(),
+            },
+            variant,
+        }
+    }
+}
+
+/// Url of a user that receives events of their links.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// Subscribed events, all of them when empty.
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// Key of the HMAC signature sent with every delivery.
+    pub secret: String,
+}
+
+impl Webhook {
+    pub fn subscribes(&self, event: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+#[derive(Debug, Validate, Deserialize, Serialize)]
+pub struct CreateWebhook {
+    #[validate(url(message = "Enter valid url"))]
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+}
+
+/// One attempt to deliver an event to a webhook.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct Delivery {
+    /// Same for all attempts of an event, sent as `X-Webhook-Id`.
+    pub id: String,
+    pub webhook: String,
+    pub event: EventKind,
+    pub payload: String,
+    /// Starts at 1.
+    pub attempt: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub delivered: bool,
+    /// Unix timestamp in seconds.
+    pub attempted_at: u64,
+}
+
+#[derive(Deserialize)]
+pub struct WebhookParams {
+    pub webhook: String,
+}
+
+#[derive(Deserialize)]
+pub struct WorkspaceParams {
+    pub workspace: String,
+}
+
+#[derive(Deserialize)]
+pub struct InviteParams {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ComponentStatus {
+    Ok,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub status: ComponentStatus,
+    pub components: BTreeMap<&'static str, ComponentStatus>,
+}
+
+impl Health {
+    /// Overall status is `ok` only when every component is.
+    pub fn new(components: BTreeMap<&'static str, ComponentStatus>) -> Self {
+        let status = if components
+            .values()
+            .all(|status| *status == ComponentStatus::Ok)
+        {
+            ComponentStatus::Ok
+        } else {
+            ComponentStatus::Error
+        };
+        Health { status, components }
+    }
+}
+
 #[cfg_attr(test, mockall::automock)]
 #[async_trait]
 pub trait UrlService {
-    async fn shorten(&self, url: &str, user: &str) -> Result<Url, UrlError>;
-    async fn get(&self, id: &str) -> Result<Url, UrlError>;
+    async fn shorten(&self, url: &NewUrl, user: &str) -> Result<Url, UrlError>;
+    async fn get(&self, domain: &str, id: &str, variant: Option<usize>) -> Result<Visit, UrlError>;
+    async fn lookup(&self, domain: &str, id: &str) -> Result<Url, UrlError>;
     async fn new_user(&self) -> Result<String, UrlError>;
-    async fn get_urls_for_user(&self, user: &str, page: isize) -> Paginated<Url>;
+    async fn get_urls_for_user(&self, user: &str, page: isize, query: &UrlQuery) -> Paginated<Url>;
+    /// Changes title, notes or tags of a personal link of `user`.
+    async fn update_url(
+        &self,
+        user: &str,
+        domain: &str,
+        id: &str,
+        update: &UpdateUrl,
+    ) -> Result<Url, UrlError>;
+    /// Deletes a personal link of `user`.
+    async fn delete_url(&self, user: &str, domain: &str, id: &str) -> Result<(), UrlError>;
+    /// Sends `link.expired` for up to `limit` links whose expiry passed and
+    /// returns them.
+    async fn expire_links(&self, limit: usize) -> Result<Vec<Url>, UrlError>;
+    /// Fetches and stores metadata of the destination, failed fetches are only logged.
+    async fn refresh_metadata(&self, domain: &str, id: &str) -> Result<(), UrlError>;
+    /// Checks up to `limit` destinations that are due, checking each again after
+    /// `interval` seconds. Returns the links that broke since their previous check.
+    async fn check_links(
+        &self,
+        limit: usize,
+        concurrency: usize,
+        interval: u64,
+    ) -> Result<Vec<Url>, UrlError>;
+    async fn ping(&self) -> Result<(), UrlError>;
+    async fn create_workspace(&self, user: &str, name: &str) -> Result<Workspace, UrlError>;
+    async fn get_workspaces_for_user(&self, user: &str) -> Result<Vec<Membership>, UrlError>;
+    async fn create_invite(
+        &self,
+        user: &str,
+        workspace: &str,
+        role: Role,
+    ) -> Result<Invite, UrlError>;
+    async fn accept_invite(&self, user: &str, token: &str) -> Result<Membership, UrlError>;
+    async fn get_urls_for_workspace(
+        &self,
+        user: &str,
+        workspace: &str,
+        page: isize,
+    ) -> Result<Paginated<Url>, UrlError>;
+    /// Moves a link between the personal links of `user` and workspaces.
+    async fn move_url(
+        &self,
+        user: &str,
+        domain: &str,
+        id: &str,
+        from: &Scope,
+        to: &Scope,
+    ) -> Result<(), UrlError>;
+    async fn create_webhook(&self, user: &str, data: &CreateWebhook) -> Result<Webhook, UrlError>;
+    async fn get_webhooks(&self, user: &str) -> Result<Vec<Webhook>, UrlError>;
+    async fn delete_webhook(&self, user: &str, id: &str) -> Result<(), UrlError>;
+    /// Latest delivery attempts to the webhooks of `user`, newest first.
+    async fn get_deliveries(&self, user: &str) -> Result<Vec<Delivery>, UrlError>;
+    /// Deliveries that failed their last attempt, newest first.
+    async fn get_dead_letters(&self, user: &str) -> Result<Vec<Delivery>, UrlError>;
 }
 
 #[cfg_attr(test, mockall::automock)]
 #[async_trait]
 pub trait UrlRepo {
-    async fn generate(&self, url: &str) -> Result<Url, UrlError>;
-    async fn get(&self, id: &str) -> Result<Url, UrlError>;
-    async fn increment_counter(&self, id: &str) -> Result<bool, UrlError>;
+    async fn generate(&self, url: &NewUrl) -> Result<Url, UrlError>;
+    async fn get(&self, domain: &str, id: &str) -> Result<Url, UrlError>;
+    async fn increment_counter(
+        &self,
+        domain: &str,
+        id: &str,
+        variant: Option<usize>,
+    ) -> Result<bool, UrlError>;
     async fn new_user(&self) -> Result<String, UrlError>;
-    async fn generate_for_user(&self, url: &str, user: &str) -> Result<Url, UrlError>;
+    async fn generate_for_user(&self, url: &NewUrl, user: &str) -> Result<Url, UrlError>;
     async fn get_urls_for_user(&self, user: &str, start: isize, stop: isize) -> Vec<Url>;
     async fn count_urls_for_user(&self, user: &str) -> Result<isize, UrlError>;
+    async fn is_in_scope(&self, domain: &str, id: &str, scope: &Scope) -> Result<bool, UrlError>;
+    /// Removes the link with its pending check, expiry and click history,
+    /// returns `false` when it is not in `scope`.
+    async fn delete_url(&self, domain: &str, id: &str, scope: &Scope) -> Result<bool, UrlError>;
+    /// Up to `limit` links whose expiry passed as `(domain, id)`, each one is
+    /// returned only once.
+    async fn claim_expired(&self, limit: usize) -> Result<Vec<(String, String)>, UrlError>;
+    /// Stores title, notes and tags of an existing link.
+    async fn save_details(&self, url: &Url) -> Result<(), UrlError>;
+    async fn save_metadata(
+        &self,
+        domain: &str,
+        id: &str,
+        metadata: &Metadata,
+    ) -> Result<(), UrlError>;
+    /// Up to `limit` links due for a check as `(domain, id)`, postponed by
+    /// `lease` seconds so other instances don't check them too.
+    async fn claim_due_checks(
+        &self,
+        limit: usize,
+        lease: u64,
+    ) -> Result<Vec<(String, String)>, UrlError>;
+    async fn save_health(
+        &self,
+        domain: &str,
+        id: &str,
+        health: &LinkHealth,
+    ) -> Result<(), UrlError>;
+    async fn ping(&self) -> Result<(), UrlError>;
+    async fn create_workspace(&self, name: &str, owner: &str) -> Result<Workspace, UrlError>;
+    async fn get_workspace(&self, workspace: &str) -> Result<Workspace, UrlError>;
+    async fn get_role(&self, workspace: &str, user: &str) -> Result<Option<Role>, UrlError>;
+    async fn add_member(&self, workspace: &str, user: &str, role: Role) -> Result<(), UrlError>;
+    async fn get_workspaces_for_user(&self, user: &str) -> Result<Vec<Membership>, UrlError>;
+    async fn create_invite(&self, workspace: &str, role: Role) -> Result<Invite, UrlError>;
+    /// Returns the invite and deletes it, `None` for unknown or expired tokens.
+    async fn take_invite(&self, token: &str) -> Result<Option<Invite>, UrlError>;
+    async fn get_urls_for_workspace(&self, workspace: &str, start: isize, stop: isize) -> Vec<Url>;
+    async fn count_urls_for_workspace(&self, workspace: &str) -> Result<isize, UrlError>;
+    /// Returns `false` when the link is not in `from`.
+    async fn move_url(
+        &self,
+        domain: &str,
+        id: &str,
+        from: &Scope,
+        to: &Scope,
+    ) -> Result<bool, UrlError>;
+    async fn create_webhook(&self, user: &str, data: &CreateWebhook) -> Result<Webhook, UrlError>;
+    async fn get_webhooks(&self, user: &str) -> Result<Vec<Webhook>, UrlError>;
+    /// Returns `false` for unknown webhooks.
+    async fn delete_webhook(&self, user: &str, id: &str) -> Result<bool, UrlError>;
+    async fn log_delivery(&self, user: &str, delivery: &Delivery) -> Result<(), UrlError>;
+    async fn dead_letter(&self, user: &str, delivery: &Delivery) -> Result<(), UrlError>;
+    async fn get_deliveries(&self, user: &str) -> Result<Vec<Delivery>, UrlError>;
+    async fn get_dead_letters(&self, user: &str) -> Result<Vec<Delivery>, UrlError>;
+    /// Counts the event in the totals of its day, and clicks also per link.
+    async fn add_to_daily_stats(&self, event: &Event) -> Result<(), UrlError>;
 }
@@ -0,0 +1,266 @@
+use super::types::Url;
+use crate::metrics::{self, observe_redis};
+use crate::redis::{Connection, RedisClient};
+use crate::settings::Settings;
+use actix::prelude::*;
+use futures::StreamExt;
+use lru::LruCache;
+use redis::{AsyncCommands, RedisError, RedisResult};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Channel carrying the `{domain}/{id}` keys of changed links between instances,
+/// within the namespace.
+pub const INVALIDATIONS_CHANNEL: &str = "url_invalidations";
+/// Wait before subscribing again after the connection is lost.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+struct Entry {
+    /// `None` for ids that don't exist.
+    url: Option<Url>,
+    expires_at: Instant,
+}
+
+/// Bounded LRU cache of links for redirects, unknown ids are cached too.
+/// Click counts, metadata and health of cached links may be stale, only
+/// changes to the link itself invalidate it.
+pub struct UrlCache {
+    entries: Mutex<LruCache<String, Entry>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    /// Other instances evict invalidated links too when set.
+    peers: Option<Addr<InvalidationPublisher>>,
+}
+
+fn cache_key(domain: &str, id: &str) -> String {
+    format!("{}/{}", domain, id)
+}
+
+impl UrlCache {
+    pub fn new(capacity: NonZeroUsize, ttl: Duration, negative_ttl: Duration) -> UrlCache {
+        UrlCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+            negative_ttl,
+            peers: None,
+        }
+    }
+
+    /// A panic while holding the lock leaves the cache consistent, entries are
+    /// replaced whole.
+    fn entries(&self) -> std::sync::MutexGuard<'_, LruCache<String, Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// `Some(None)` when the id is known not to exist, `None` when it is not cached.
+    pub fn get(&self, domain: &str, id: &str) -> Option<Option<Url>> {
+        self.get_at(domain, id, Instant::now())
+    }
+
+    fn get_at(&self, domain: &str, id: &str, now: Instant) -> Option<Option<Url>> {
+        let key = cache_key(domain, id);
+        let mut entries = self.entries();
+        let cached = match entries.get(&key) {
+            Some(entry) if entry.expires_at > now => Some(entry.url.clone()),
+            Some(_) => {
+                entries.pop(&key);
+                None
+            }
+            None => None,
+        };
+        let result = match &cached {
+            Some(Some(_)) => "hit",
+            Some(None) => "negative_hit",
+            None => "miss",
+        };
+        metrics::URL_CACHE_LOOKUPS
+            .with_label_values(&[result])
+            .inc();
+        cached
+    }
+
+    /// Caches a link, or the absence of one for `url: None`.
+    pub fn insert(&self, domain: &str, id: &str, url: Option<Url>) {
+        let ttl = if url.is_some() {
+            self.ttl
+        } else {
+            self.negative_ttl
+        };
+        let entry = Entry {
+            url,
+            expires_at: Instant::now() + ttl,
+        };
+        self.entries().put(cache_key(domain, id), entry);
+    }
+
+    /// Evicts a link on this instance and, when configured, on all the others.
+    pub fn invalidate(&self, domain: &str, id: &str) {
+        let key = cache_key(domain, id);
+        self.evict(&key);
+        if let Some(peers) = &self.peers {
+            peers.do_send(Invalidate(key));
+        }
+    }
+
+    fn evict(&self, key: &str) {
+        self.entries().pop(key);
+    }
+
+    fn clear(&self) {
+        self.entries().clear();
+    }
+}
+
+struct Invalidate(String);
+
+impl Message for Invalidate {
+    type Result = ();
+}
+
+/// Publishes invalidated keys one at a time, in the order they were invalidated.
+pub struct InvalidationPublisher {
+    redis_client: Arc<RedisClient>,
+    channel: String,
+    /// Opened on the first invalidation and again after a failure.
+    connection: Option<Connection>,
+}
+
+impl Actor for InvalidationPublisher {
+    type Context = Context<Self>;
+}
+
+impl Handler<Invalidate> for InvalidationPublisher {
+    type Result = ();
+
+    fn handle(&mut self, Invalidate(key): Invalidate, ctx: &mut Context<Self>) {
+        let redis_client = self.redis_client.clone();
+        let channel = self.channel.clone();
+        let connection = self.connection.take();
+        let publish = async move {
+            let mut connection = match connection {
+                Some(connection) => connection,
+                None => observe_redis("connect", redis_client.get_async_connection()).await?,
+            };
+            let _: usize = observe_redis("publish", connection.publish(channel, key)).await?;
+            Ok::<_, RedisError>(connection)
+        };
+        ctx.wait(publish.into_actor(self).map(|result, act, _| {
+            if let Ok(connection) = result {
+                act.connection = Some(connection);
+            }
+        }));
+    }
+}
+
+/// Evicts the keys published by any instance until the connection is lost.
+async fn listen(redis_client: &RedisClient, channel: &str, cache: &UrlCache) -> RedisResult<()> {
+    let mut pubsub = redis_client.get_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    // invalidations published while unsubscribed were missed
+    cache.clear();
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        if let Ok(key) = message.get_payload::<String>() {
+            cache.evict(&key);
+        }
+    }
+    Ok(())
+}
+
+async fn subscribe(redis_client: Arc<RedisClient>, channel: String, cache: Arc<UrlCache>) {
+    loop {
+        if let Err(e) = listen(&redis_client, &channel, &cache).await {
+            tracing::warn!(error = %e, "cache invalidations were not received");
+        }
+        actix_rt::time::delay_for(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+/// Starts the cache and its invalidation subscriber on the current arbiter,
+/// `None` when caching is disabled.
+pub fn start(settings: &Settings, redis_client: Arc<RedisClient>) -> Option<Arc<UrlCache>> {
+    let capacity = NonZeroUsize::new(settings.url_cache_capacity)?;
+    let channel = crate::redis::namespace(settings).key(INVALIDATIONS_CHANNEL);
+    let peers = InvalidationPublisher {
+        redis_client: redis_client.clone(),
+        channel: channel.clone(),
+        connection: None,
+    }
+    .start();
+    let cache = Arc::new(UrlCache {
+        peers: Some(peers),
+        ..UrlCache::new(
+            capacity,
+            Duration::from_secs(settings.url_cache_ttl),
+            Duration::from_secs(settings.url_cache_negative_ttl),
+        )
+    });
+    actix_rt::spawn(subscribe(redis_client, channel, cache.clone()));
+    Some(cache)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(id: &str) -> Url {
+        Url {
+            id: id.to_string(),
+            url: "https://example.com".to_string(),
+            domain: "localhost".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn cache(capacity: usize) -> UrlCache {
+        UrlCache::new(
+            NonZeroUsize::new(capacity).unwrap(),
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+        )
+    }
+
+    #[test]
+    fn test_get_and_expire() {
+        let sut = cache(10);
+        assert_eq!(sut.get("localhost", "a"), None);
+        sut.insert("localhost", "a", Some(url("a")));
+        sut.insert("localhost", "b", None);
+        assert_eq!(sut.get("localhost", "a"), Some(Some(url("a"))));
+        assert_eq!(sut.get("localhost", "b"), Some(None));
+        assert_eq!(sut.get("other.localhost", "a"), None);
+
+        // unknown ids expire sooner
+        let later = Instant::now() + Duration::from_secs(30);
+        assert_eq!(sut.get_at("localhost", "a", later), Some(Some(url("a"))));
+        assert_eq!(sut.get_at("localhost", "b", later), None);
+        let later = Instant::now() + Duration::from_secs(61);
+        assert_eq!(sut.get_at("localhost", "a", later), None);
+        assert_eq!(sut.get("localhost", "a"), None);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let sut = cache(2);
+        sut.insert("localhost", "a", Some(url("a")));
+        sut.insert("localhost", "b", Some(url("b")));
+        sut.get("localhost", "a");
+        sut.insert("localhost", "c", Some(url("c")));
+        // the least recently used link goes first
+        assert_eq!(sut.get("localhost", "b"), None);
+        assert!(sut.get("localhost", "a").is_some());
+        assert!(sut.get("localhost", "c").is_some());
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let sut = cache(10);
+        sut.insert("localhost", "a", Some(url("a")));
+        sut.insert("localhost", "b", None);
+        sut.invalidate("localhost", "a");
+        sut.invalidate("localhost", "b");
+        assert_eq!(sut.get("localhost", "a"), None);
+        assert_eq!(sut.get("localhost", "b"), None);
+    }
+}
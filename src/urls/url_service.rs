@@ -1,9 +1,120 @@
+use super::link_checker::LinkChecker;
+use super::metadata::MetadataFetcher;
 use super::types::*;
+use super::url_cache::UrlCache;
+use super::webhooks::EventSink;
+use crate::metrics;
 use crate::urls::error::UrlError;
+use crate::urls::utils::unix_time;
 use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use std::sync::Arc;
+
+const PAGE_SIZE: isize = 25;
+/// Newest links searched by filtered or sorted listings, which load them all.
+const FILTERED_LINKS_LIMIT: isize = 1000;
+const MAX_WEBHOOKS: usize = 10;
 
 pub struct UrlServiceImpl<A: UrlRepo> {
     pub url_repo: A,
+    /// Metadata of destinations is not fetched when unset.
+    pub metadata_fetcher: Option<Arc<dyn MetadataFetcher + Send + Sync>>,
+    /// Destinations are not checked when unset.
+    pub link_checker: Option<Arc<dyn LinkChecker + Send + Sync>>,
+    /// Every sink receives all link events.
+    pub events: Vec<Arc<dyn EventSink + Send + Sync>>,
+    /// Clicks are counted by the stream worker when unset.
+    pub count_clicks: bool,
+    /// Redirects always read Redis when unset.
+    pub cache: Option<Arc<UrlCache>>,
+}
+
+/// Index range of a page as used by the repository, `stop` is exclusive.
+fn page_range(page: isize) -> (isize, isize) {
+    let start = PAGE_SIZE * page;
+    (start, start + PAGE_SIZE)
+}
+
+fn paginate(page: isize, total: isize, results: Vec<Url>) -> Paginated<Url> {
+    let page_count: isize = total / PAGE_SIZE;
+    let next: Option<isize> = if page < (page_count - 1) {
+        Some(page + 1)
+    } else {
+        None
+    };
+    let prev: Option<isize> = if page > 0 { Some(page - 1) } else { None };
+    Paginated {
+        total,
+        page_count,
+        next,
+        prev,
+        results,
+    }
+}
+
+impl<A> UrlServiceImpl<A>
+where
+    A: UrlRepo + Sync + Send,
+{
+    /// Role of a member, at least `required`. Non-members don't learn the workspace exists.
+    async fn require_role(
+        &self,
+        workspace: &str,
+        user: &str,
+        required: Role,
+    ) -> Result<Role, UrlError> {
+        match self.url_repo.get_role(workspace, user).await? {
+            Some(role) if role >= required => Ok(role),
+            Some(_) => Err(UrlError::Forbidden),
+            None => Err(UrlError::NotFound),
+        }
+    }
+
+    fn publish(&self, event: EventKind, url: &Url, variant: Option<usize>) {
+        if self.events.is_empty() {
+            return;
+        }
+        let event = Event::new(event, url, variant);
+        for events in self.events.iter() {
+            events.publish(event.clone());
+        }
+    }
+
+    /// Link for a redirect, unknown ids are cached as well.
+    async fn get_cached(&self, domain: &str, id: &str) -> Result<Url, UrlError> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.url_repo.get(domain, id).await,
+        };
+        if let Some(cached) = cache.get(domain, id) {
+            return cached.ok_or(UrlError::NotFound);
+        }
+        let url = self.url_repo.get(domain, id).await;
+        match &url {
+            Ok(url) => cache.insert(domain, id, Some(url.clone())),
+            Err(UrlError::NotFound) => cache.insert(domain, id, None),
+            Err(_) => {}
+        }
+        url
+    }
+
+    fn invalidate(&self, domain: &str, id: &str) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(domain, id);
+        }
+    }
+
+    /// Personal links may only be moved by their owner, workspace links by editors.
+    async fn require_move(&self, scope: &Scope, user: &str) -> Result<(), UrlError> {
+        match scope {
+            Scope::User(owner) if owner == user => Ok(()),
+            Scope::User(_) => Err(UrlError::Forbidden),
+            Scope::Workspace(workspace) => self
+                .require_role(workspace, user, Role::Editor)
+                .await
+                .map(|_| ()),
+        }
+    }
 }
 
 #[async_trait]
@@ -11,53 +122,348 @@ impl<A> UrlService for UrlServiceImpl<A>
 where
     A: UrlRepo + Sync + Send,
 {
-    async fn shorten(&self, url: &str, user: &str) -> Result<Url, UrlError> {
-        self.url_repo.generate_for_user(url, user).await
+    #[tracing::instrument(skip(self, url), fields(domain = %url.domain))]
+    async fn shorten(&self, url: &NewUrl, user: &str) -> Result<Url, UrlError> {
+        let url = self.url_repo.generate_for_user(url, user).await;
+        if let Ok(url) = &url {
+            // an alias may have been looked up before it existed
+            self.invalidate(&url.domain, &url.id);
+            metrics::LINKS_CREATED.inc();
+            self.publish(EventKind::LinkCreated, url, None);
+        }
+        url
     }
 
-    async fn get(&self, id: &str) -> Result<Url, UrlError> {
-        let url = self.url_repo.get(id).await;
+    #[tracing::instrument(skip(self))]
+    async fn get(&self, domain: &str, id: &str, variant: Option<usize>) -> Result<Visit, UrlError> {
+        // expired links redirect like unknown ids, whether or not the sweep saw them
+        let url = self.get_cached(domain, id).await.and_then(|url| {
+            if url.is_expired(unix_time()) {
+                Err(UrlError::NotFound)
+            } else {
+                Ok(url)
+            }
+        });
         match url {
             Ok(url) => {
-                self.url_repo.increment_counter(id).await.ok();
-                Ok(url)
+                metrics::REDIRECTS.with_label_values(&["hit"]).inc();
+                let variant = match variant {
+                    Some(index) if index < url.variants.len() => Some(index),
+                    _ => url.pick_variant(),
+                };
+                if self.count_clicks {
+                    metrics::CLICK_QUEUE_DEPTH.inc();
+                    if let Err(e) = self.url_repo.increment_counter(domain, id, variant).await {
+                        tracing::warn!(domain, id, error = %e, "click was not counted");
+                    }
+                    metrics::CLICK_QUEUE_DEPTH.dec();
+                }
+                self.publish(EventKind::LinkClicked, &url, variant);
+                Ok(Visit { url, variant })
+            }
+            Err(e) => {
+                metrics::REDIRECTS.with_label_values(&["miss"]).inc();
+                Err(e)
             }
-            Err(e) => Err(e),
         }
     }
 
+    #[tracing::instrument(skip(self))]
+    async fn lookup(&self, domain: &str, id: &str) -> Result<Url, UrlError> {
+        self.url_repo.get(domain, id).await
+    }
+
+    #[tracing::instrument(skip(self))]
     async fn new_user(&self) -> Result<String, UrlError> {
-        self.url_repo.new_user().await
+        let user = self.url_repo.new_user().await;
+        if user.is_ok() {
+            metrics::USERS_CREATED.inc();
+        }
+        user
     }
 
-    async fn get_urls_for_user(&self, user: &str, page: isize) -> Paginated<Url> {
-        let page_size: isize = 25;
-        let start: isize = page_size * page;
-        let stop: isize = start + page_size;
-        let total: isize = self.url_repo.count_urls_for_user(user).await.unwrap_or(0);
-        let page_count: isize = total / page_size;
-        let next: Option<isize> = if page < (page_count - 1) {
-            Some(page + 1)
-        } else {
-            None
+    #[tracing::instrument(skip(self))]
+    async fn get_urls_for_user(&self, user: &str, page: isize, query: &UrlQuery) -> Paginated<Url> {
+        let (start, stop) = page_range(page);
+        let total: isize = match self.url_repo.count_urls_for_user(user).await {
+            Ok(total) => total,
+            Err(e) => {
+                tracing::warn!(user, error = %e, "unable to count user links");
+                0
+            }
+        };
+        if query.is_default() {
+            let results = self.url_repo.get_urls_for_user(user, start, stop).await;
+            return paginate(page, total, results);
+        }
+        // links are stored by creation time only, other queries load the newest ones
+        let mut results: Vec<Url> = self
+            .url_repo
+            .get_urls_for_user(user, 0, total.min(FILTERED_LINKS_LIMIT))
+            .await
+            .into_iter()
+            .filter(|url| query.matches(url))
+            .collect();
+        if query.sort == Sort::MostClicked {
+            results.sort_by_key(|url| std::cmp::Reverse(url.count));
+        }
+        let total = results.len() as isize;
+        let results = results
+            .into_iter()
+            .skip(start.max(0) as usize)
+            .take((stop - start) as usize)
+            .collect();
+        paginate(page, total, results)
+    }
+
+    #[tracing::instrument(skip(self, update))]
+    async fn update_url(
+        &self,
+        user: &str,
+        domain: &str,
+        id: &str,
+        update: &UpdateUrl,
+    ) -> Result<Url, UrlError> {
+        let scope = Scope::User(user.to_string());
+        if !self.url_repo.is_in_scope(domain, id, &scope).await? {
+            return Err(UrlError::NotFound);
+        }
+        let mut url = self.url_repo.get(domain, id).await?;
+        update.apply(&mut url);
+        self.url_repo.save_details(&url).await?;
+        self.invalidate(domain, id);
+        Ok(url)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_url(&self, user: &str, domain: &str, id: &str) -> Result<(), UrlError> {
+        // read before deleting, the event describes the link
+        let url = self.url_repo.get(domain, id).await?;
+        let scope = Scope::User(user.to_string());
+        if !self.url_repo.delete_url(domain, id, &scope).await? {
+            return Err(UrlError::NotFound);
+        }
+        self.invalidate(domain, id);
+        self.publish(EventKind::LinkDeleted, &url, None);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn expire_links(&self, limit: usize) -> Result<Vec<Url>, UrlError> {
+        let mut expired = vec![];
+        for (domain, id) in self.url_repo.claim_expired(limit).await? {
+            match self.url_repo.get(&domain, &id).await {
+                Ok(url) => expired.push(url),
+                Err(e) => tracing::debug!(%domain, %id, error = %e, "expired link is gone"),
+            }
+        }
+        for url in expired.iter() {
+            self.publish(EventKind::LinkExpired, url, None);
+        }
+        Ok(expired)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn refresh_metadata(&self, domain: &str, id: &str) -> Result<(), UrlError> {
+        let fetcher = match &self.metadata_fetcher {
+            Some(fetcher) => fetcher,
+            None => return Ok(()),
         };
-        let prev: Option<isize> = if page > 0 { Some(page - 1) } else { None };
-        let results = self.url_repo.get_urls_for_user(user, start, stop).await;
+        let url = self.url_repo.get(domain, id).await?;
+        match fetcher.fetch(&url.url).await {
+            Ok(metadata) => {
+                metrics::METADATA_FETCHES.with_label_values(&["ok"]).inc();
+                self.url_repo.save_metadata(domain, id, &metadata).await
+            }
+            Err(e) => {
+                metrics::METADATA_FETCHES
+                    .with_label_values(&["error"])
+                    .inc();
+                tracing::info!(domain, id, error = %e, "metadata was not fetched");
+                Ok(())
+            }
+        }
+    }
 
-        Paginated {
-            total,
-            page_count,
-            next,
-            prev,
-            results,
+    #[tracing::instrument(skip(self))]
+    async fn check_links(
+        &self,
+        limit: usize,
+        concurrency: usize,
+        interval: u64,
+    ) -> Result<Vec<Url>, UrlError> {
+        let checker = match &self.link_checker {
+            Some(checker) => checker,
+            None => return Ok(vec![]),
+        };
+        let due = self.url_repo.claim_due_checks(limit, interval).await?;
+        let broken: Vec<Option<Url>> = stream::iter(due)
+            .map(|(domain, id)| async move {
+                let mut url = match self.url_repo.get(&domain, &id).await {
+                    Ok(url) => url,
+                    Err(e) => {
+                        tracing::debug!(%domain, %id, error = %e, "checked link is gone");
+                        return None;
+                    }
+                };
+                let health = checker.check(&url.url).await;
+                let result = if health.broken { "broken" } else { "ok" };
+                metrics::LINK_CHECKS.with_label_values(&[result]).inc();
+                if let Err(e) = self.url_repo.save_health(&domain, &id, &health).await {
+                    tracing::warn!(%domain, %id, error = %e, "link health was not saved");
+                    return None;
+                }
+                let was_broken = url.health.as_ref().is_some_and(|health| health.broken);
+                let is_broken = health.broken;
+                url.health = Some(health);
+                Some(url).filter(|_| is_broken && !was_broken)
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+        let broken: Vec<Url> = broken.into_iter().flatten().collect();
+        for url in broken.iter() {
+            self.publish(EventKind::LinkBroken, url, None);
         }
+        Ok(broken)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn ping(&self) -> Result<(), UrlError> {
+        self.url_repo.ping().await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn create_workspace(&self, user: &str, name: &str) -> Result<Workspace, UrlError> {
+        self.url_repo.create_workspace(name, user).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_workspaces_for_user(&self, user: &str) -> Result<Vec<Membership>, UrlError> {
+        self.url_repo.get_workspaces_for_user(user).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn create_invite(
+        &self,
+        user: &str,
+        workspace: &str,
+        role: Role,
+    ) -> Result<Invite, UrlError> {
+        self.require_role(workspace, user, Role::Owner).await?;
+        self.url_repo.create_invite(workspace, role).await
+    }
+
+    #[tracing::instrument(skip(self, token))]
+    async fn accept_invite(&self, user: &str, token: &str) -> Result<Membership, UrlError> {
+        let invite = self
+            .url_repo
+            .take_invite(token)
+            .await?
+            .ok_or(UrlError::NotFound)?;
+        let workspace = self.url_repo.get_workspace(&invite.workspace).await?;
+        // an invite never downgrades an existing member
+        let role = match self.url_repo.get_role(&workspace.id, user).await? {
+            Some(role) if role >= invite.role => role,
+            _ => {
+                self.url_repo
+                    .add_member(&workspace.id, user, invite.role)
+                    .await?;
+                invite.role
+            }
+        };
+        Ok(Membership { workspace, role })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_urls_for_workspace(
+        &self,
+        user: &str,
+        workspace: &str,
+        page: isize,
+    ) -> Result<Paginated<Url>, UrlError> {
+        self.require_role(workspace, user, Role::Viewer).await?;
+        let (start, stop) = page_range(page);
+        let total = self.url_repo.count_urls_for_workspace(workspace).await?;
+        let results = self
+            .url_repo
+            .get_urls_for_workspace(workspace, start, stop)
+            .await;
+        Ok(paginate(page, total, results))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn move_url(
+        &self,
+        user: &str,
+        domain: &str,
+        id: &str,
+        from: &Scope,
+        to: &Scope,
+    ) -> Result<(), UrlError> {
+        self.require_move(from, user).await?;
+        self.require_move(to, user).await?;
+        if from == to {
+            return Ok(());
+        }
+        match self.url_repo.move_url(domain, id, from, to).await? {
+            true => Ok(()),
+            false => Err(UrlError::NotFound),
+        }
+    }
+
+    #[tracing::instrument(skip(self, data))]
+    async fn create_webhook(&self, user: &str, data: &CreateWebhook) -> Result<Webhook, UrlError> {
+        if self.url_repo.get_webhooks(user).await?.len() >= MAX_WEBHOOKS {
+            return Err(UrlError::Forbidden);
+        }
+        self.url_repo.create_webhook(user, data).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_webhooks(&self, user: &str) -> Result<Vec<Webhook>, UrlError> {
+        self.url_repo.get_webhooks(user).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn delete_webhook(&self, user: &str, id: &str) -> Result<(), UrlError> {
+        match self.url_repo.delete_webhook(user, id).await? {
+            true => Ok(()),
+            false => Err(UrlError::NotFound),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_deliveries(&self, user: &str) -> Result<Vec<Delivery>, UrlError> {
+        self.url_repo.get_deliveries(user).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_dead_letters(&self, user: &str) -> Result<Vec<Delivery>, UrlError> {
+        self.url_repo.get_dead_letters(user).await
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::urls::link_checker::MockLinkChecker;
+    use crate::urls::metadata::{MetadataError, MockMetadataFetcher};
+    use crate::urls::webhooks::MockEventSink;
     use mockall::predicate::*;
+    use std::num::NonZeroUsize;
+    use std::time::Duration;
+
+    fn service(url_repo: MockUrlRepo) -> UrlServiceImpl<MockUrlRepo> {
+        UrlServiceImpl {
+            url_repo,
+            metadata_fetcher: None,
+            link_checker: None,
+            events: vec![],
+            count_clicks: true,
+            cache: None,
+        }
+    }
 
     #[actix_web::main]
     #[test]
@@ -70,6 +476,7 @@ mod tests {
             id: id.to_string(),
             url: long_url.to_string(),
             count: 0,
+            ..Default::default()
         };
 
         let mut url_repo = MockUrlRepo::new();
@@ -78,21 +485,587 @@ mod tests {
             .return_const(Ok(url.clone()));
         url_repo
             .expect_get()
-            .with(eq(id.clone()))
+            .with(eq("localhost"), eq(id))
             .return_const(Ok(url.clone()));
         url_repo
             .expect_increment_counter()
             .times(1)
             .return_const(Ok(true));
 
-        let sut = UrlServiceImpl { url_repo };
+        let sut = service(url_repo);
 
-        let result = sut.shorten(&long_url, &user).await.ok();
+        let new_url = NewUrl {
+            url: long_url.to_string(),
+            ..Default::default()
+        };
+        let result = sut.shorten(&new_url, user).await.ok();
         let expected = Some(url.clone());
         assert_eq!(expected, result);
 
-        let result = sut.get(&id).await.ok();
-        let expected = Some(url.clone());
+        let result = sut.get("localhost", id, None).await.ok();
+        let expected = Some(Visit {
+            url: url.clone(),
+            variant: None,
+        });
         assert_eq!(expected, result);
     }
+
+    fn split_url() -> Url {
+        Url {
+            id: "split".to_string(),
+            url: "http://a.com".to_string(),
+            count: 0,
+            variants: vec![
+                Variant {
+                    url: "http://a.com".to_string(),
+                    weight: 1,
+                    count: 0,
+                },
+                Variant {
+                    url: "http://b.com".to_string(),
+                    weight: 3,
+                    count: 0,
+                },
+            ],
+            domain: "localhost".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_get_sticky_variant() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo.expect_get().return_const(Ok(split_url()));
+        url_repo
+            .expect_increment_counter()
+            .with(eq("localhost"), eq("split"), eq(Some(1)))
+            .times(1)
+            .return_const(Ok(true));
+
+        let sut = service(url_repo);
+
+        let visit = sut.get("localhost", "split", Some(1)).await.unwrap();
+        assert_eq!(visit.variant, Some(1));
+        assert_eq!(visit.location(), "http://b.com");
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_get_repicks_unknown_variant() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo.expect_get().return_const(Ok(split_url()));
+        url_repo
+            .expect_increment_counter()
+            .times(1)
+            .return_const(Ok(true));
+
+        let sut = service(url_repo);
+
+        let visit = sut.get("localhost", "split", Some(7)).await.unwrap();
+        assert!(matches!(visit.variant, Some(0) | Some(1)));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_workspace_permissions() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_get_role()
+            .with(eq("team"), eq("viewer"))
+            .return_const(Ok(Some(Role::Viewer)));
+        url_repo
+            .expect_get_role()
+            .with(eq("team"), eq("stranger"))
+            .return_const(Ok(None));
+        url_repo.expect_create_invite().times(0);
+        url_repo.expect_move_url().times(0);
+        url_repo
+            .expect_count_urls_for_workspace()
+            .return_const(Ok(1));
+        url_repo
+            .expect_get_urls_for_workspace()
+            .return_const(vec![Url::default()]);
+
+        let sut = service(url_repo);
+
+        let urls = sut
+            .get_urls_for_workspace("viewer", "team", 0)
+            .await
+            .unwrap();
+        assert_eq!(urls.total, 1);
+        assert_eq!(
+            sut.get_urls_for_workspace("stranger", "team", 0)
+                .await
+                .unwrap_err(),
+            UrlError::NotFound
+        );
+        assert_eq!(
+            sut.create_invite("viewer", "team", Role::Editor)
+                .await
+                .unwrap_err(),
+            UrlError::Forbidden
+        );
+        let personal = Scope::User("viewer".to_string());
+        let team = Scope::Workspace("team".to_string());
+        assert_eq!(
+            sut.move_url("viewer", "localhost", "id", &personal, &team)
+                .await
+                .unwrap_err(),
+            UrlError::Forbidden
+        );
+        let other = Scope::User("owner".to_string());
+        assert_eq!(
+            sut.move_url("viewer", "localhost", "id", &other, &personal)
+                .await
+                .unwrap_err(),
+            UrlError::Forbidden
+        );
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_move_url() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_get_role()
+            .return_const(Ok(Some(Role::Editor)));
+        url_repo
+            .expect_move_url()
+            .with(
+                eq("localhost"),
+                eq("id"),
+                eq(Scope::User("user".to_string())),
+                eq(Scope::Workspace("team".to_string())),
+            )
+            .times(1)
+            .return_const(Ok(true));
+
+        let sut = service(url_repo);
+
+        let personal = Scope::User("user".to_string());
+        let team = Scope::Workspace("team".to_string());
+        assert_eq!(
+            sut.move_url("user", "localhost", "id", &personal, &team)
+                .await,
+            Ok(())
+        );
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_accept_invite_keeps_higher_role() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo.expect_take_invite().return_const(Ok(Some(Invite {
+            token: "token".to_string(),
+            workspace: "team".to_string(),
+            role: Role::Viewer,
+        })));
+        url_repo.expect_get_workspace().return_const(Ok(Workspace {
+            id: "team".to_string(),
+            name: "Team".to_string(),
+        }));
+        url_repo
+            .expect_get_role()
+            .return_const(Ok(Some(Role::Editor)));
+        url_repo.expect_add_member().times(0);
+
+        let sut = service(url_repo);
+
+        let membership = sut.accept_invite("user", "token").await.unwrap();
+        assert_eq!(membership.role, Role::Editor);
+    }
+
+    fn tagged_url(id: &str, count: u64, tags: &[&str]) -> Url {
+        Url {
+            id: id.to_string(),
+            url: format!("https://docs.example.com/{}", id),
+            count,
+            domain: "localhost".to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_filter_limit() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo.expect_count_urls_for_user().return_const(Ok(5000));
+        url_repo
+            .expect_get_urls_for_user()
+            .with(eq("user"), eq(0), eq(FILTERED_LINKS_LIMIT))
+            .times(1)
+            .return_const(vec![tagged_url("a", 1, &["work"])]);
+
+        let sut = service(url_repo);
+
+        let query = UrlQuery {
+            tag: Some("work".to_string()),
+            ..Default::default()
+        };
+        let urls = sut.get_urls_for_user("user", 0, &query).await;
+        assert_eq!(urls.total, 1);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_filter_and_sort_urls() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo.expect_count_urls_for_user().return_const(Ok(3));
+        url_repo
+            .expect_get_urls_for_user()
+            .with(eq("user"), eq(0), eq(3))
+            .return_const(vec![
+                tagged_url("a", 1, &["work"]),
+                tagged_url("b", 5, &[]),
+                tagged_url("c", 9, &["work", "rust"]),
+            ]);
+
+        let sut = service(url_repo);
+
+        let query = UrlQuery {
+            tag: Some("WORK".to_string()),
+            sort: Sort::MostClicked,
+            ..Default::default()
+        };
+        let urls = sut.get_urls_for_user("user", 0, &query).await;
+        assert_eq!(urls.total, 2);
+        let ids: Vec<&str> = urls.results.iter().map(|url| url.id.as_str()).collect();
+        assert_eq!(ids, vec!["c", "a"]);
+
+        let query = UrlQuery {
+            destination: Some("example.com".to_string()),
+            q: Some("B".to_string()),
+            ..Default::default()
+        };
+        let urls = sut.get_urls_for_user("user", 0, &query).await;
+        assert_eq!(urls.results, vec![tagged_url("b", 5, &[])]);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_update_url() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_is_in_scope()
+            .with(
+                eq("localhost"),
+                eq("a"),
+                eq(Scope::User("user".to_string())),
+            )
+            .return_const(Ok(true));
+        url_repo.expect_is_in_scope().return_const(Ok(false));
+        url_repo
+            .expect_get()
+            .return_const(Ok(tagged_url("a", 1, &["work"])));
+        url_repo.expect_save_details().times(1).return_const(Ok(()));
+
+        let sut = service(url_repo);
+
+        let update = UpdateUrl {
+            title: Some("Docs".to_string()),
+            tags: Some(vec![" Rust ".to_string(), "rust".to_string()]),
+            ..Default::default()
+        };
+        let url = sut
+            .update_url("user", "localhost", "a", &update)
+            .await
+            .unwrap();
+        assert_eq!(url.title, Some("Docs".to_string()));
+        assert_eq!(url.tags, vec!["rust".to_string()]);
+        assert_eq!(
+            sut.update_url("other", "localhost", "a", &update).await,
+            Err(UrlError::NotFound)
+        );
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_delete_url() {
+        let mut url = tagged_url("a", 1, &[]);
+        url.owner = Some("user".to_string());
+        let mut url_repo = MockUrlRepo::new();
+        url_repo.expect_get().return_const(Ok(url));
+        url_repo
+            .expect_delete_url()
+            .with(
+                eq("localhost"),
+                eq("a"),
+                eq(Scope::User("user".to_string())),
+            )
+            .times(1)
+            .return_const(Ok(true));
+        url_repo.expect_delete_url().return_const(Ok(false));
+        let mut events = MockEventSink::new();
+        events
+            .expect_publish()
+            .withf(|event| {
+                event.event == EventKind::LinkDeleted && event.owner.as_deref() == Some("user")
+            })
+            .times(1)
+            .return_const(());
+
+        let sut = UrlServiceImpl {
+            events: vec![Arc::new(events)],
+            ..service(url_repo)
+        };
+
+        assert_eq!(
+            sut.delete_url("other", "localhost", "a").await,
+            Err(UrlError::NotFound)
+        );
+        assert_eq!(sut.delete_url("user", "localhost", "a").await, Ok(()));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_expired_links() {
+        let mut url = tagged_url("a", 1, &[]);
+        url.expires_at = Some(1);
+        let mut url_repo = MockUrlRepo::new();
+        url_repo.expect_get().return_const(Ok(url));
+        url_repo.expect_increment_counter().times(0);
+        url_repo
+            .expect_claim_expired()
+            .return_const(Ok(vec![("localhost".to_string(), "a".to_string())]));
+        let mut events = MockEventSink::new();
+        events
+            .expect_publish()
+            .withf(|event| event.event == EventKind::LinkExpired && event.link.id == "a")
+            .times(1)
+            .return_const(());
+
+        let sut = UrlServiceImpl {
+            events: vec![Arc::new(events)],
+            ..service(url_repo)
+        };
+
+        assert_eq!(
+            sut.get("localhost", "a", None).await.map(|_| ()),
+            Err(UrlError::NotFound)
+        );
+        let expired = sut.expire_links(10).await.unwrap();
+        assert_eq!(expired.len(), 1);
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_cached_get() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_get()
+            .with(eq("localhost"), eq("a"))
+            // the first redirect, the update and the redirect after it
+            .times(3)
+            .return_const(Ok(tagged_url("a", 1, &[])));
+        url_repo
+            .expect_get()
+            .with(eq("localhost"), eq("b"))
+            .times(1)
+            .return_const(Err(UrlError::NotFound));
+        url_repo
+            .expect_increment_counter()
+            .times(3)
+            .return_const(Ok(true));
+        url_repo.expect_is_in_scope().return_const(Ok(true));
+        url_repo.expect_save_details().return_const(Ok(()));
+        url_repo
+            .expect_generate_for_user()
+            .return_const(Ok(tagged_url("b", 0, &[])));
+
+        let sut = UrlServiceImpl {
+            cache: Some(Arc::new(UrlCache::new(
+                NonZeroUsize::new(10).unwrap(),
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+            ))),
+            ..service(url_repo)
+        };
+
+        sut.get("localhost", "a", None).await.unwrap();
+        sut.get("localhost", "a", None).await.unwrap();
+        // edits are read again
+        sut.update_url("user", "localhost", "a", &UpdateUrl::default())
+            .await
+            .unwrap();
+        sut.get("localhost", "a", None).await.unwrap();
+
+        assert_eq!(
+            sut.get("localhost", "b", None).await.map(|_| ()),
+            Err(UrlError::NotFound)
+        );
+        assert_eq!(
+            sut.get("localhost", "b", None).await.map(|_| ()),
+            Err(UrlError::NotFound)
+        );
+        // the id exists now, the unknown id is forgotten
+        sut.shorten(&NewUrl::default(), "user").await.unwrap();
+        assert!(sut.cache.as_ref().unwrap().get("localhost", "b").is_none());
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_refresh_metadata() {
+        let metadata = Metadata {
+            title: Some("Docs".to_string()),
+            ..Default::default()
+        };
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_get()
+            .return_const(Ok(tagged_url("a", 0, &[])));
+        url_repo
+            .expect_save_metadata()
+            .with(eq("localhost"), eq("a"), eq(metadata.clone()))
+            .times(1)
+            .return_const(Ok(()));
+        let mut fetcher = MockMetadataFetcher::new();
+        fetcher
+            .expect_fetch()
+            .with(eq("https://docs.example.com/a"))
+            .times(1)
+            .return_const(Ok(metadata));
+        fetcher
+            .expect_fetch()
+            .return_const(Err(MetadataError::Timeout));
+
+        let sut = UrlServiceImpl {
+            metadata_fetcher: Some(Arc::new(fetcher)),
+            ..service(url_repo)
+        };
+
+        assert_eq!(sut.refresh_metadata("localhost", "a").await, Ok(()));
+        // failed fetches leave the link as it is
+        assert_eq!(sut.refresh_metadata("localhost", "a").await, Ok(()));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_check_links() {
+        let broken = LinkHealth {
+            status: Some(404),
+            checked_at: 1,
+            broken: true,
+            ..Default::default()
+        };
+        let mut already_broken = tagged_url("b", 0, &[]);
+        already_broken.health = Some(broken.clone());
+
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_claim_due_checks()
+            .with(eq(10), eq(60))
+            .times(1)
+            .return_const(Ok(vec![
+                ("localhost".to_string(), "a".to_string()),
+                ("localhost".to_string(), "b".to_string()),
+                ("localhost".to_string(), "gone".to_string()),
+            ]));
+        url_repo
+            .expect_get()
+            .with(eq("localhost"), eq("a"))
+            .return_const(Ok(tagged_url("a", 0, &[])));
+        url_repo
+            .expect_get()
+            .with(eq("localhost"), eq("b"))
+            .return_const(Ok(already_broken));
+        url_repo
+            .expect_get()
+            .with(eq("localhost"), eq("gone"))
+            .return_const(Err(UrlError::NotFound));
+        url_repo
+            .expect_save_health()
+            .with(always(), always(), eq(broken.clone()))
+            .times(2)
+            .return_const(Ok(()));
+        let mut checker = MockLinkChecker::new();
+        checker.expect_check().times(2).return_const(broken.clone());
+        let mut events = MockEventSink::new();
+        events
+            .expect_publish()
+            .withf(|event| event.event == EventKind::LinkBroken && event.link.id == "a")
+            .times(1)
+            .return_const(());
+
+        let sut = UrlServiceImpl {
+            link_checker: Some(Arc::new(checker)),
+            events: vec![Arc::new(events)],
+            ..service(url_repo)
+        };
+
+        // only links that were fine before are reported
+        let result = sut.check_links(10, 2, 60).await.unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].id, "a");
+        assert_eq!(result[0].health, Some(broken));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_events() {
+        let mut url = tagged_url("a", 0, &[]);
+        url.owner = Some("user".to_string());
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_generate_for_user()
+            .return_const(Ok(url.clone()));
+        url_repo.expect_get().return_const(Ok(url));
+        url_repo.expect_increment_counter().return_const(Ok(true));
+        url_repo.expect_get_webhooks().return_const(Ok(vec![]));
+        url_repo
+            .expect_create_webhook()
+            .return_const(Err(UrlError::Internal));
+        let mut events = MockEventSink::new();
+        events
+            .expect_publish()
+            .withf(|event| {
+                event.owner.as_deref() == Some("user") && event.event == EventKind::LinkCreated
+            })
+            .times(1)
+            .return_const(());
+        events
+            .expect_publish()
+            .withf(|event| {
+                event.event == EventKind::LinkClicked
+                    && event.link.short_url == "http://localhost/a"
+            })
+            .times(1)
+            .return_const(());
+
+        let sut = UrlServiceImpl {
+            events: vec![Arc::new(events)],
+            ..service(url_repo)
+        };
+
+        sut.shorten(&NewUrl::default(), "user").await.unwrap();
+        sut.get("localhost", "a", None).await.unwrap();
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_webhook_limit() {
+        let webhook = Webhook {
+            id: "hook".to_string(),
+            url: "https://hooks.example.com".to_string(),
+            events: vec![],
+            secret: "secret".to_string(),
+        };
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_get_webhooks()
+            .return_const(Ok(vec![webhook; MAX_WEBHOOKS]));
+        url_repo.expect_create_webhook().times(0);
+
+        let sut = service(url_repo);
+
+        let data = CreateWebhook {
+            url: "https://hooks.example.com".to_string(),
+            events: vec![],
+        };
+        assert_eq!(
+            sut.create_webhook("user", &data).await,
+            Err(UrlError::Forbidden)
+        );
+    }
 }
@@ -1,4 +1,17 @@
 use crate::urls::types::Url;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Seconds since the epoch, 0 for a clock set before it.
+pub fn unix_time() -> u64 {
+    unix_time_millis() / 1000
+}
+
+pub fn unix_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_millis() as u64
+}
 
 pub trait BuildUrl {
     fn build_url(&self) -> String;
@@ -6,12 +19,11 @@ pub trait BuildUrl {
 
 impl BuildUrl for Url {
     fn build_url(&self) -> String {
-        let base_url = std::env::var("DOMAIN").expect("DOMAIN");
-        let schema: &str = if base_url.starts_with("localhost") {
+        let schema: &str = if self.domain.starts_with("localhost") {
             "http://"
         } else {
             "https://"
         };
-        format!("{}{}/{}", schema, base_url, &self.id)
+        format!("{}{}/{}", schema, self.domain, &self.id)
     }
 }
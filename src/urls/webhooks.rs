@@ -0,0 +1,439 @@
+use super::metadata::{public_client, MetadataError};
+use super::types::{Delivery, Event, EventKind, UrlRepo, Webhook};
+use super::utils::unix_time;
+use crate::metrics;
+use crate::settings::Settings;
+use actix::prelude::*;
+use actix_web::error::BlockingError;
+use actix_web::web;
+use async_trait::async_trait;
+use futures::channel::oneshot;
+use futures::future::{self, LocalBoxFuture};
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use reqwest::header::CONTENT_TYPE;
+use sha2::Sha256;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Attempts per delivery before it goes to the dead letters.
+pub const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for every following one.
+const BACKOFF: Duration = Duration::from_secs(2);
+const DELIVERY_ID_LENGTH: usize = 16;
+
+/// Receives link events, must return without waiting for deliveries.
+#[cfg_attr(test, mockall::automock)]
+pub trait EventSink {
+    fn publish(&self, event: Event);
+
+    /// Resolves once the events published so far are handled, for shutdowns.
+    fn flush(&self) -> LocalBoxFuture<'static, ()>;
+}
+
+/// Asks an event actor to answer once its pending events are handled.
+pub struct Flush;
+
+impl Message for Flush {
+    type Result = ();
+}
+
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait WebhookClient {
+    /// Status of the response to a signed delivery of `payload`.
+    async fn send(
+        &self,
+        webhook: &Webhook,
+        delivery_id: &str,
+        payload: &str,
+    ) -> Result<u16, MetadataError>;
+}
+
+/// Hex encoded HMAC-SHA256 of `{timestamp}.{payload}` keyed with the webhook secret.
+pub fn signature(secret: &str, timestamp: u64, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC takes any key");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+/// Posts deliveries over HTTP, to public addresses only unless configured otherwise.
+#[derive(Debug, Clone)]
+pub struct HttpWebhookClient {
+    timeout: Duration,
+    allow_private: bool,
+}
+
+impl HttpWebhookClient {
+    pub fn new(timeout: Duration, allow_private: bool) -> HttpWebhookClient {
+        HttpWebhookClient {
+            timeout,
+            allow_private,
+        }
+    }
+
+    fn send_blocking(
+        &self,
+        webhook: &Webhook,
+        delivery_id: &str,
+        payload: String,
+    ) -> Result<u16, MetadataError> {
+        let url = reqwest::Url::parse(&webhook.url).map_err(|_| MetadataError::InvalidUrl)?;
+        let timestamp = unix_time();
+        let response = public_client(&url, self.timeout, self.allow_private)?
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Id", delivery_id)
+            .header("X-Webhook-Timestamp", timestamp.to_string())
+            .header(
+                "X-Webhook-Signature",
+                format!("sha256={}", signature(&webhook.secret, timestamp, &payload)),
+            )
+            .body(payload)
+            .send()?;
+        Ok(response.status().as_u16())
+    }
+}
+
+#[async_trait]
+impl WebhookClient for HttpWebhookClient {
+    async fn send(
+        &self,
+        webhook: &Webhook,
+        delivery_id: &str,
+        payload: &str,
+    ) -> Result<u16, MetadataError> {
+        let client = self.clone();
+        let (webhook, delivery_id, payload) = (
+            webhook.clone(),
+            delivery_id.to_string(),
+            payload.to_string(),
+        );
+        web::block(move || client.send_blocking(&webhook, &delivery_id, payload))
+            .await
+            .map_err(|e| match e {
+                BlockingError::Error(e) => e,
+                BlockingError::Canceled => MetadataError::Request("canceled".to_string()),
+            })
+    }
+}
+
+/// Delivers an event to every subscribed webhook of its owner.
+pub async fn dispatch(
+    url_repo: &(dyn UrlRepo + Send + Sync),
+    client: &(dyn WebhookClient + Send + Sync),
+    backoff: Duration,
+    event: Event,
+) {
+    let owner = match &event.owner {
+        Some(owner) => owner,
+        None => return,
+    };
+    let webhooks = match url_repo.get_webhooks(owner).await {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            tracing::warn!(user = %owner, error = %e, "webhooks were not loaded");
+            return;
+        }
+    };
+    let payload = match serde_json::to_string(&event) {
+        Ok(payload) => payload,
+        Err(_) => return,
+    };
+    let deliveries = webhooks
+        .iter()
+        .filter(|webhook| webhook.subscribes(event.event))
+        .map(|webhook| {
+            deliver(
+                url_repo,
+                client,
+                backoff,
+                owner,
+                event.event,
+                webhook,
+                &payload,
+            )
+        });
+    future::join_all(deliveries).await;
+}
+
+/// Retries with exponential backoff, every attempt is logged and the last
+/// failed one is kept in the dead letters.
+async fn deliver(
+    url_repo: &(dyn UrlRepo + Send + Sync),
+    client: &(dyn WebhookClient + Send + Sync),
+    backoff: Duration,
+    owner: &str,
+    event: EventKind,
+    webhook: &Webhook,
+    payload: &str,
+) {
+    let id: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(DELIVERY_ID_LENGTH)
+        .map(char::from)
+        .collect();
+    for attempt in 1..=MAX_ATTEMPTS {
+        let result = client.send(webhook, &id, payload).await;
+        let delivery = Delivery {
+            id: id.clone(),
+            webhook: webhook.id.clone(),
+            event,
+            payload: payload.to_string(),
+            attempt,
+            status: result.as_ref().ok().copied(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            delivered: matches!(result, Ok(200..=299)),
+            attempted_at: unix_time(),
+        };
+        if let Err(e) = url_repo.log_delivery(owner, &delivery).await {
+            tracing::warn!(id = %id, error = %e, "delivery was not logged");
+        }
+        if delivery.delivered {
+            metrics::WEBHOOK_DELIVERIES
+                .with_label_values(&["delivered"])
+                .inc();
+            return;
+        }
+        if attempt == MAX_ATTEMPTS {
+            metrics::WEBHOOK_DELIVERIES
+                .with_label_values(&["dead"])
+                .inc();
+            if let Err(e) = url_repo.dead_letter(owner, &delivery).await {
+                tracing::warn!(id = %id, error = %e, "failed delivery was not kept");
+            }
+            return;
+        }
+        metrics::WEBHOOK_DELIVERIES
+            .with_label_values(&["retry"])
+            .inc();
+        actix_rt::time::delay_for(backoff * 2u32.pow(attempt - 1)).await;
+    }
+}
+
+/// Delivers events in the background, each one independently of the others.
+pub struct WebhookWorker {
+    url_repo: Arc<dyn UrlRepo + Send + Sync>,
+    client: Arc<dyn WebhookClient + Send + Sync>,
+    /// Events whose deliveries, retries included, are not finished.
+    pending: usize,
+    flushes: Vec<oneshot::Sender<()>>,
+}
+
+impl Actor for WebhookWorker {
+    type Context = Context<Self>;
+}
+
+impl Message for Event {
+    type Result = ();
+}
+
+impl Handler<Event> for WebhookWorker {
+    type Result = ();
+
+    fn handle(&mut self, event: Event, ctx: &mut Context<Self>) {
+        let url_repo = self.url_repo.clone();
+        let client = self.client.clone();
+        let delivery = async move { dispatch(&*url_repo, &*client, BACKOFF, event).await };
+        self.pending += 1;
+        ctx.spawn(delivery.into_actor(self).map(|_, act, _| {
+            act.pending -= 1;
+            if act.pending == 0 {
+                for flush in act.flushes.drain(..) {
+                    let _ = flush.send(());
+                }
+            }
+        }));
+    }
+}
+
+impl Handler<Flush> for WebhookWorker {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _: Flush, _: &mut Context<Self>) -> Self::Result {
+        if self.pending == 0 {
+            return Box::pin(future::ready(()));
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.flushes.push(sender);
+        Box::pin(async move {
+            let _ = receiver.await;
+        })
+    }
+}
+
+impl EventSink for Addr<WebhookWorker> {
+    fn publish(&self, event: Event) {
+        self.do_send(event);
+    }
+
+    fn flush(&self) -> LocalBoxFuture<'static, ()> {
+        let flushed = self.send(Flush);
+        Box::pin(async move {
+            let _ = flushed.await;
+        })
+    }
+}
+
+/// Starts the worker on the current arbiter.
+pub fn start(
+    settings: &Settings,
+    url_repo: Arc<dyn UrlRepo + Send + Sync>,
+) -> Arc<dyn EventSink + Send + Sync> {
+    let client = HttpWebhookClient::new(
+        Duration::from_secs(settings.webhook_timeout),
+        settings.webhook_allow_private,
+    );
+    Arc::new(
+        WebhookWorker {
+            url_repo,
+            client: Arc::new(client),
+            pending: 0,
+            flushes: vec![],
+        }
+        .start(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::urls::types::{MockUrlRepo, Url};
+    use mockall::predicate::*;
+
+    fn webhook(id: &str, events: Vec<EventKind>) -> Webhook {
+        Webhook {
+            id: id.to_string(),
+            url: format!("https://hooks.example.com/{}", id),
+            events,
+            secret: "secret".to_string(),
+        }
+    }
+
+    fn clicked() -> Event {
+        let url = Url {
+            id: "test".to_string(),
+            url: "https://example.com".to_string(),
+            domain: "localhost".to_string(),
+            owner: Some("user".to_string()),
+            ..Default::default()
+        };
+        Event::new(EventKind::LinkClicked, &url, None)
+    }
+
+    #[test]
+    fn test_signature() {
+        // HMAC-SHA256 of "1.The quick brown fox jumps over the lazy dog"
+        assert_eq!(
+            signature("key", 1, "The quick brown fox jumps over the lazy dog"),
+            "3ff4d3cc115b639a16dc5b217aa5c89be41d1e4b54efc356d63d0cd2a65b30f1"
+        );
+        assert_ne!(signature("key", 1, "{}"), signature("key", 2, "{}"));
+        assert_ne!(signature("key", 1, "{}"), signature("other", 1, "{}"));
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_dispatch_retries() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_get_webhooks()
+            .with(eq("user"))
+            .return_const(Ok(vec![
+                webhook("clicks", vec![EventKind::LinkClicked]),
+                webhook("created", vec![EventKind::LinkCreated]),
+            ]));
+        url_repo
+            .expect_log_delivery()
+            .withf(|user, delivery| user == "user" && delivery.webhook == "clicks")
+            .times(2)
+            .return_const(Ok(()));
+        url_repo.expect_dead_letter().times(0);
+        let mut client = MockWebhookClient::new();
+        let mut attempts = 0;
+        client
+            .expect_send()
+            .withf(|webhook, _, payload| {
+                webhook.id == "clicks" && payload.contains(r#""event":"link.clicked""#)
+            })
+            .times(2)
+            .returning(move |_, _, _| {
+                attempts += 1;
+                if attempts == 1 {
+                    Err(MetadataError::Timeout)
+                } else {
+                    Ok(204)
+                }
+            });
+
+        dispatch(&url_repo, &client, Duration::from_millis(1), clicked()).await;
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_dispatch_dead_letter() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_get_webhooks()
+            .return_const(Ok(vec![webhook("all", vec![])]));
+        url_repo
+            .expect_log_delivery()
+            .times(MAX_ATTEMPTS as usize)
+            .return_const(Ok(()));
+        url_repo
+            .expect_dead_letter()
+            .withf(|_, delivery| {
+                delivery.attempt == MAX_ATTEMPTS
+                    && delivery.status == Some(500)
+                    && !delivery.delivered
+            })
+            .times(1)
+            .return_const(Ok(()));
+        let mut client = MockWebhookClient::new();
+        client
+            .expect_send()
+            .times(MAX_ATTEMPTS as usize)
+            .return_const(Ok(500));
+
+        dispatch(&url_repo, &client, Duration::from_millis(1), clicked()).await;
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_flush_waits_for_deliveries() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_get_webhooks()
+            .return_const(Ok(vec![webhook("all", vec![])]));
+        url_repo.expect_log_delivery().return_const(Ok(()));
+        let sent = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = sent.clone();
+        let mut client = MockWebhookClient::new();
+        client.expect_send().returning(move |_, _, _| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(204)
+        });
+        let sut = WebhookWorker {
+            url_repo: Arc::new(url_repo),
+            client: Arc::new(client),
+            pending: 0,
+            flushes: vec![],
+        }
+        .start();
+
+        sut.flush().await;
+        sut.publish(clicked());
+        sut.publish(clicked());
+        sut.flush().await;
+        assert_eq!(sent.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::settings::Settings;
+use crate::urls::error::UrlError;
+use crate::urls::event_stream::{parse_entry, STREAM_KEY};
+use crate::urls::redis_url_repo;
+use crate::urls::types::{Event, EventKind, UrlRepo};
+use redis::streams::{StreamReadOptions, StreamReadReply};
+use redis::{AsyncCommands, RedisResult};
+
+/// Consumer group shared by all aggregating workers, each entry goes to one of them.
+pub const GROUP: &str = "aggregator";
+const BATCH_SIZE: usize = 100;
+/// Milliseconds a read waits for new entries.
+const BLOCK_MS: usize = 5000;
+
+/// Updates the counters of one event.
+pub async fn aggregate(
+    url_repo: &(dyn UrlRepo + Send + Sync),
+    event: &Event,
+    count_clicks: bool,
+) -> Result<(), UrlError> {
+    if count_clicks && event.event == EventKind::LinkClicked {
+        url_repo
+            .increment_counter(&event.link.domain, &event.link.id, event.variant)
+            .await?;
+    }
+    url_repo.add_to_daily_stats(event).await
+}
+
+/// Aggregates the event stream until Redis fails, returns the process exit code.
+/// Entries are acknowledged after they are counted, so an entry may be counted
+/// twice when the worker stops in between.
+pub async fn run(settings: &Settings) -> i32 {
+    let url_repo = redis_url_repo::configure(settings).await;
+    let stream_key = url_repo.namespace.key(STREAM_KEY);
+    let mut conn = match url_repo.redis_client.get_async_connection().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            tracing::error!(error = %e, "unable to connect to Redis");
+            return 1;
+        }
+    };
+    let created: RedisResult<()> = conn.xgroup_create_mkstream(&stream_key, GROUP, "$").await;
+    if let Err(e) = created {
+        if e.code() != Some("BUSYGROUP") {
+            tracing::error!(error = %e, "unable to create the consumer group");
+            return 1;
+        }
+    }
+    tracing::info!(consumer = %settings.worker_name, "aggregating events");
+
+    // entries read before a restart but not acknowledged come first
+    let mut start = "0";
+    loop {
+        let options = StreamReadOptions::default()
+            .group(GROUP, &settings.worker_name)
+            .count(BATCH_SIZE)
+            .block(BLOCK_MS);
+        let reply: StreamReadReply =
+            match conn.xread_options(&[&stream_key], &[start], options).await {
+                Ok(reply) => reply,
+                Err(e) => {
+                    tracing::error!(error = %e, "unable to read the event stream");
+                    return 1;
+                }
+            };
+        let entries: Vec<_> = reply.keys.into_iter().flat_map(|key| key.ids).collect();
+        if entries.is_empty() {
+            start = ">";
+            continue;
+        }
+        for entry in entries.iter() {
+            match parse_entry(&entry.map) {
+                Some(event) => {
+                    if let Err(e) =
+                        aggregate(&url_repo, &event, settings.count_clicks_in_worker).await
+                    {
+                        tracing::error!(id = %entry.id, error = %e, "event was not aggregated");
+                        return 1;
+                    }
+                }
+                None => tracing::warn!(id = %entry.id, "skipping malformed event"),
+            }
+        }
+        let ids: Vec<&str> = entries.iter().map(|entry| entry.id.as_str()).collect();
+        let acked: RedisResult<usize> = conn.xack(&stream_key, GROUP, &ids).await;
+        if let Err(e) = acked {
+            tracing::error!(error = %e, "unable to acknowledge events");
+            return 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::urls::types::{MockUrlRepo, Url};
+    use mockall::predicate::*;
+
+    fn event(kind: EventKind) -> Event {
+        let url = Url {
+            id: "test".to_string(),
+            domain: "localhost".to_string(),
+            ..Default::default()
+        };
+        Event::new(kind, &url, Some(1))
+    }
+
+    #[actix_web::main]
+    #[test]
+    async fn test_aggregate() {
+        let mut url_repo = MockUrlRepo::new();
+        url_repo
+            .expect_increment_counter()
+            .with(eq("localhost"), eq("test"), eq(Some(1)))
+            .times(1)
+            .return_const(Ok(true));
+        url_repo
+            .expect_add_to_daily_stats()
+            .times(3)
+            .return_const(Ok(()));
+
+        let clicked = event(EventKind::LinkClicked);
+        aggregate(&url_repo, &clicked, true).await.unwrap();
+        // counted on redirect already
+        aggregate(&url_repo, &clicked, false).await.unwrap();
+        aggregate(&url_repo, &event(EventKind::LinkCreated), true)
+            .await
+            .unwrap();
+    }
+}